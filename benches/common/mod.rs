@@ -0,0 +1,19 @@
+// Builds an image of noise from a fixed seed so every run times the same pixels
+// Samples hold depth significant bits (0 to 2^depth - 1), the way div16 leaves 4 bits of an 8 bit image
+pub fn noise(width: u32, height: u32, depth: u32) -> image::RgbImage
+{
+    let mut state = 0x2545f491u32;
+    image::ImageBuffer::from_fn(width, height,
+        | _, _ |
+        {
+            let mut sample = ||
+            {
+                state ^= state << 13;
+                state ^= state >> 17;
+                state ^= state << 5;
+                (state >> (32 - depth)) as u8
+            };
+            image::Rgb([sample(), sample(), sample()])
+        }
+    )
+}
@@ -0,0 +1,124 @@
+use criterion::{criterion_group, criterion_main, BatchSize, BenchmarkId, Criterion, Throughput};
+use image_affinity::affinity::affinity;
+use image_affinity::average::average;
+use image_affinity::center_diff::center_diff;
+use image_affinity::div16::div16;
+use image_affinity::export::Export;
+use image_affinity::max_diff::{analyze_max_diff, max_diff};
+use image_affinity::progress;
+use image_affinity::saturate::saturate;
+use std::fs;
+use std::hint::black_box;
+
+mod common;
+use common::noise;
+
+// Image sizes the filters are timed at, with affinity analysis kept to smaller ones since it takes seconds at 1024x1024
+const SIZES: [u32; 3] = [64, 256, 1024];
+const AFFINITY_SIZES: [u32; 3] = [64, 128, 256];
+
+// Significant bits of the samples, since affinity analysis slows down with the number of distinct values
+const DEPTHS: [u32; 3] = [1, 4, 8];
+
+// Directory the end to end benchmark saves to, as analysis saves the raw output to it and the saturated one to saturated_ it
+const OUTPUT_DIR: &str = "output/";
+
+// Times a filter on every size and depth, leaving out everything but computing its output
+fn bench<F>(c: &mut Criterion, name: &str, sizes: &[u32], filter: F)
+    where F: Fn(&image::RgbImage) -> image::RgbImage
+{
+    let mut group = c.benchmark_group(name);
+    group.sample_size(10);
+    for depth in DEPTHS
+    {
+        for size in sizes
+        {
+            let img = noise(*size, *size, depth);
+            group.throughput(Throughput::Elements(*size as u64 * *size as u64));
+            group.bench_with_input(BenchmarkId::new(format!("{}-bit", depth), size), &img, | b, img | b.iter(|| filter(black_box(img))));
+        }
+    }
+    group.finish();
+}
+
+fn affinity_filter(c: &mut Criterion)
+{
+    bench(c, "affinity", &AFFINITY_SIZES, affinity);
+}
+
+fn max_diff_filter(c: &mut Criterion)
+{
+    bench(c, "max_diff", &SIZES, max_diff);
+}
+
+fn center_diff_filter(c: &mut Criterion)
+{
+    bench(c, "center_diff", &SIZES, center_diff);
+}
+
+// Averages the saturated image with a saturated max diff output standing in for the affinity output the pipeline reads back,
+// which only changes the values averaged and keeps large sizes quick to set up
+fn average_filter(c: &mut Criterion)
+{
+    let mut group = c.benchmark_group("average");
+    for depth in DEPTHS
+    {
+        for size in SIZES
+        {
+            let mut original = noise(size, size, depth);
+            let mut analyzed = max_diff(&original);
+            saturate(&mut original);
+            saturate(&mut analyzed);
+            group.throughput(Throughput::Elements(size as u64 * size as u64));
+            group.bench_with_input(BenchmarkId::new(format!("{}-bit", depth), size), &(original, analyzed),
+                | b, (original, analyzed) | b.iter(|| average(black_box(original), black_box(analyzed))));
+        }
+    }
+    group.finish();
+}
+
+// Times the in place operations on a fresh copy of the image each iteration, leaving the copy out of the time
+fn in_place(c: &mut Criterion)
+{
+    for (name, operation) in [("saturate", saturate as fn(&mut image::RgbImage)), ("div16", div16)]
+    {
+        let mut group = c.benchmark_group(name);
+        for depth in DEPTHS
+        {
+            for size in SIZES
+            {
+                let img = noise(size, size, depth);
+                group.throughput(Throughput::Elements(size as u64 * size as u64));
+                group.bench_with_input(BenchmarkId::new(format!("{}-bit", depth), size), &img,
+                    | b, img | b.iter_batched_ref(|| img.clone(), | img | operation(black_box(img)), BatchSize::LargeInput));
+            }
+        }
+        group.finish();
+    }
+}
+
+// Times max diff analysis end to end, including saturating and saving both outputs as BMP files, to show what writing
+// images adds to the filter on its own
+fn max_diff_analysis(c: &mut Criterion)
+{
+    // Outputs go to a scratch directory, and the timing lines analysis logs are silenced
+    let dir = std::env::temp_dir().join("image_affinity_bench");
+    fs::create_dir_all(dir.join(OUTPUT_DIR)).unwrap();
+    fs::create_dir_all(dir.join("saturated_".to_owned() + OUTPUT_DIR)).unwrap();
+    std::env::set_current_dir(&dir).unwrap();
+    progress::quiet(true);
+
+    let mut group = c.benchmark_group("analyze_max_diff");
+    group.sample_size(10);
+    for size in SIZES
+    {
+        let img = noise(size, size, 8);
+        group.throughput(Throughput::Elements(size as u64 * size as u64));
+        group.bench_with_input(BenchmarkId::new("8-bit", size), &img,
+            | b, img | b.iter(|| analyze_max_diff(black_box(img), 0, "bench.bmp", OUTPUT_DIR, None, &mut Export::none())));
+    }
+    group.finish();
+}
+
+criterion_group!(benches, affinity_filter, max_diff_filter, center_diff_filter, average_filter, in_place, max_diff_analysis);
+criterion_main!(benches);
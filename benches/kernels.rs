@@ -0,0 +1,120 @@
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use image_affinity::center_diff::{center_diff, center_diff_radius};
+use image_affinity::kernels::{apply, apply_scalar, window_min_max, Op};
+use image_affinity::max_diff::{max_diff, max_diff_radius};
+use std::hint::black_box;
+
+mod common;
+use common::noise;
+
+// Image sizes the filters are timed at
+const SIZES: [u32; 3] = [128, 512, 1024];
+
+// Max diff as it was written before the row kernels, reading every neighbour with get_pixel
+fn reference_max_diff(img: &image::RgbImage) -> image::RgbImage
+{
+    let (width, height) = img.dimensions();
+    image::ImageBuffer::from_fn(width, height,
+        | x, y |
+        {
+            let rl = if x > 0 { x - 1 } else { x };
+            let rr = if x < width - 1 { x + 1 } else { x };
+            let cl = if y > 0 { y - 1 } else { y };
+            let cr = if y < height - 1 { y + 1 } else { y };
+            let mut out = [0; 3];
+            for (i, out) in out.iter_mut().enumerate()
+            {
+                let mut min = 255;
+                let mut max = 0;
+                for r in rl .. rr
+                {
+                    for c in cl .. cr
+                    {
+                        let num = img.get_pixel(r, c) [i];
+                        min = min.min(num);
+                        max = max.max(num);
+                    }
+                }
+                *out = max - min;
+            }
+            image::Rgb(out)
+        }
+    )
+}
+
+// Center diff as it was written before the row kernels
+fn reference_center_diff(img: &image::RgbImage) -> image::RgbImage
+{
+    let (width, height) = img.dimensions();
+    image::ImageBuffer::from_fn(width, height,
+        | x, y |
+        {
+            let rl = if x > 0 { x - 1 } else { x };
+            let rr = if x < width - 1 { x + 1 } else { x };
+            let cl = if y > 0 { y - 1 } else { y };
+            let cr = if y < height - 1 { y + 1 } else { y };
+            let mut out = [0; 3];
+            for (i, out) in out.iter_mut().enumerate()
+            {
+                let center = img.get_pixel(x, y) [i];
+                for r in rl .. rr
+                {
+                    for c in cl .. cr
+                    {
+                        let num = img.get_pixel(r, c) [i];
+                        *out = (*out).max(num.abs_diff(center));
+                    }
+                }
+            }
+            image::Rgb(out)
+        }
+    )
+}
+
+// Compares the row kernel filters with the per pixel versions they replaced
+fn filters(c: &mut Criterion)
+{
+    let mut group = c.benchmark_group("diff filters");
+    for size in SIZES
+    {
+        let img = noise(size, size, 8);
+        group.throughput(Throughput::Elements(size as u64 * size as u64));
+        group.bench_with_input(BenchmarkId::new("max_diff reference", size), &img, | b, img | b.iter(|| reference_max_diff(black_box(img))));
+        group.bench_with_input(BenchmarkId::new("max_diff", size), &img, | b, img | b.iter(|| max_diff(black_box(img))));
+        group.bench_with_input(BenchmarkId::new("center_diff reference", size), &img, | b, img | b.iter(|| reference_center_diff(black_box(img))));
+        group.bench_with_input(BenchmarkId::new("center_diff", size), &img, | b, img | b.iter(|| center_diff(black_box(img))));
+    }
+    group.finish();
+}
+
+// Compares the vector instructions with the portable loop on one row of samples
+fn rows(c: &mut Criterion)
+{
+    let mut group = c.benchmark_group("row kernels");
+    for width in [64usize, 1024, 4096]
+    {
+        let other: Vec<u8> = noise(width as u32, 1, 8).into_raw();
+        let mut acc = vec![128; other.len()];
+        group.throughput(Throughput::Bytes(other.len() as u64));
+        group.bench_function(BenchmarkId::new("simd", width), | b | b.iter(|| apply(Op::Max, black_box(&mut acc), black_box(&other))));
+        group.bench_function(BenchmarkId::new("scalar", width), | b | b.iter(|| apply_scalar(Op::Max, black_box(&mut acc), black_box(&other))));
+    }
+    group.finish();
+}
+
+// Shows how the window search scales with the radius, comparing every offset for small radii and separably from LARGE_RADIUS
+fn radii(c: &mut Criterion)
+{
+    let img = noise(512, 512, 8);
+    let mut group = c.benchmark_group("window radius");
+    for radius in [1u32, 2, 4, 8, 16, 32, 64, 128]
+    {
+        group.bench_with_input(BenchmarkId::new("window_min_max", radius), &radius, | b, radius | b.iter(|| window_min_max(black_box(&img), *radius)));
+        group.bench_with_input(BenchmarkId::new("max_diff", radius), &radius, | b, radius | b.iter(|| max_diff_radius(black_box(&img), *radius)));
+        group.bench_with_input(BenchmarkId::new("center_diff", radius), &radius, | b, radius | b.iter(|| center_diff_radius(black_box(&img), *radius)));
+    }
+    group.finish();
+}
+
+criterion_group!(benches, filters, rows, radii);
+criterion_main!(benches);
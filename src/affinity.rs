@@ -1,12 +1,17 @@
-use crate::saturate::saturate;
+use crate::export::Export;
+use crate::mask::{clear, contains, saturate_within};
+use crate::progress::log;
 
 use std::collections::HashMap;
 use std::collections::HashSet;
 use std::time::Instant;
 use crate::image::GenericImageView;
 
-// Gets the single and joint frequencies of pixels in each subimage
-fn get_frequencies(subimage: &image::SubImage<&image::ImageBuffer<image::Rgb<u8>, std::vec::Vec<u8>>>) -> (Vec<HashMap<u8, usize>>, Vec<HashMap<(u8, u8), usize>>)
+// Single and joint frequencies of each color channel
+pub type Frequencies = (Vec<HashMap<u8, usize>>, Vec<HashMap<(u8, u8), usize>>);
+
+// Gets the single and joint frequencies of pixels in each subimage, only counting pixels inside the mask when one is given
+pub fn get_frequencies(subimage: &image::SubImage<&image::ImageBuffer<image::Rgb<u8>, std::vec::Vec<u8>>>, mask: Option<&image::GrayImage>) -> Frequencies
 {
     // HashMaps for each color channel to represent co-occurrences and single occurrences
     let mut single_frequencies: Vec<HashMap<u8, usize>> = Vec::with_capacity(3);
@@ -19,8 +24,8 @@ fn get_frequencies(subimage: &image::SubImage<&image::ImageBuffer<image::Rgb<u8>
         joint_frequencies.push(HashMap::new());
     }
 
-    // Iterate through the subimage
-    let (width, height) = subimage.dimensions();
+    // Iterate through the subimage, whose offset places its pixels in the mask
+    let (x0, y0, width, height) = subimage.bounds();
     for i in 0 .. width - 1
     {
         for j in 0 .. height - 1
@@ -41,6 +46,11 @@ fn get_frequencies(subimage: &image::SubImage<&image::ImageBuffer<image::Rgb<u8>
             {
                 for c in j .. j + 2
                 {
+                    if mask.is_some_and(| mask | !contains(mask, x0 + r, y0 + c))
+                    {
+                        continue;
+                    }
+
                     // Iterate through each color channel
                     for p in 0 .. 3
                     {
@@ -84,27 +94,58 @@ fn get_frequencies(subimage: &image::SubImage<&image::ImageBuffer<image::Rgb<u8>
     (single_frequencies, joint_frequencies)
 }
 
+// Gets the bounds (x, y, width, height) of the square around a pixel that affinity analysis looks at, clamped to the image
+pub fn window(x: u32, y: u32, width: u32, height: u32) -> (u32, u32, u32, u32)
+{
+    let xb = if x > 0 { x - 1 } else { x };
+    let yb = if y > 0 { y - 1 } else { y };
+    let wb = if x > 0 && x < width - 1 { 3 } else { 2 };
+    let hb = if y > 0 && y < height - 1 { 3 } else { 2 };
+    (xb, yb, wb, hb)
+}
+
+// Finds the most frequent pair, preferring the largest pixel difference between equally frequent pairs
+pub fn strongest_pair(joint: &HashMap<(u8, u8), usize>) -> Option<((u8, u8), usize)>
+{
+    let mut best: Option<((u8, u8), usize)> = None;
+    for (&(l, r), &cooccurance) in joint
+    {
+        let stronger = match best
+        {
+            None => true,
+            Some(((bl, br), count)) => cooccurance > count || (cooccurance == count && (l - r, l) > (bl - br, bl)),
+        };
+        if stronger
+        {
+            best = Some(((l, r), cooccurance));
+        }
+    }
+    best
+}
+
 // Uses affinity analysis to set each pixel to the highest affinity in a 3x3 square around it
-pub fn analyze_affinity(img: &image::RgbImage, entry: &str, output_dir: &str)
+pub fn affinity(img: &image::RgbImage) -> image::RgbImage
 {
-    // Setup image to be copied to and start counting time
+    affinity_within(img, None)
+}
+
+// Uses affinity analysis on only the pixels of each square that lie inside the mask
+pub fn affinity_within(img: &image::RgbImage, mask: Option<&image::GrayImage>) -> image::RgbImage
+{
+    // Setup image to be copied to
     let (width, height) = img.dimensions();
     let mut image: image::RgbImage = image::ImageBuffer::new(width, height);
-    let now = Instant::now();
     let mut subimage = img.view(0, 0, 2, 2);
 
     image.enumerate_pixels_mut().for_each(
         | (x, y, pixel) |
         {
             // Acquire subimage based on bounds
-            let xb = if x > 0 { x - 1 } else { x };
-            let yb = if y > 0 { y - 1 } else { y };
-            let wb = if x > 0 && x < width - 1 { 3 } else { 2 };
-            let hb = if y > 0 && y < height - 1 { 3 } else { 2 };
+            let (xb, yb, wb, hb) = window(x, y, width, height);
             subimage.change_bounds(xb, yb, wb, hb);
 
             // Find the strongest affinity with the largest pixel difference
-            let (_, joint) = get_frequencies(&subimage);
+            let (_, joint) = get_frequencies(&subimage, mask);
             
             let mut out = [0; 3];
             for i in 0 .. 3
@@ -155,14 +196,180 @@ pub fn analyze_affinity(img: &image::RgbImage, entry: &str, output_dir: &str)
         }
     );
 
+    image
+}
+
+// Runs affinity analysis on the image and saves both the raw and saturated outputs
+pub fn analyze_affinity(img: &image::RgbImage, entry: &str, output_dir: &str, mask: Option<&image::GrayImage>, export: &mut Export)
+{
+    // Start counting time and run the analysis
+    let now = Instant::now();
+    let mut image = affinity_within(img, mask);
+    clear(&mut image, mask);
+
     let elapsed = now.elapsed();
-    let sec = (elapsed.as_secs() as f64) + (elapsed.subsec_nanos() as f64 / 1000_000_000.0);
-    println!("Affinity Analysis Completed in: {}", sec);
-    image.save(output_dir.to_owned() + entry).unwrap();
+    let sec = (elapsed.as_secs() as f64) + (elapsed.subsec_nanos() as f64 / 1_000_000_000.0);
+    log(&format!("Affinity Analysis Completed in: {}", sec));
+    export.save(&image, &(output_dir.to_owned() + entry));
     let now = Instant::now();
-    saturate(&mut image);
+    saturate_within(&mut image, mask);
     let elapsed = now.elapsed();
-    let sec = (elapsed.as_secs() as f64) + (elapsed.subsec_nanos() as f64 / 1000_000_000.0);
-    println!("Output Saturated in: {}", sec);
-    image.save("saturated_".to_owned() + output_dir + entry).unwrap();
+    let sec = (elapsed.as_secs() as f64) + (elapsed.subsec_nanos() as f64 / 1_000_000_000.0);
+    log(&format!("Output Saturated in: {}", sec));
+    export.save(&image, &("saturated_".to_owned() + output_dir + entry));
+}
+
+// Offset directions used by directional affinity as (name, column step, row step)
+pub const DIRECTIONS: [(&str, i64, i64); 4] = [("horizontal", 1, 0), ("vertical", 0, 1), ("diagonal", 1, 1), ("antidiagonal", 1, -1)];
+
+// Gets the joint frequencies of pixel pairs separated by the given offset inside a window of the image
+// With a mask only pairs with both pixels inside it are counted
+fn get_directional_frequencies(img: &image::RgbImage, window: (u32, u32, u32, u32), offset: (i64, i64), mask: Option<&image::GrayImage>) -> Vec<HashMap<(u8, u8), usize>>
+{
+    let (x0, y0, x1, y1) = window;
+    let mut joint_frequencies: Vec<HashMap<(u8, u8), usize>> = vec![HashMap::new(); 3];
+
+    for r in x0 ..= x1
+    {
+        for c in y0 ..= y1
+        {
+            // Only count pairs whose partner pixel also lies inside the window
+            let pr = r as i64 + offset.0;
+            let pc = c as i64 + offset.1;
+            if pr < x0 as i64 || pr > x1 as i64 || pc < y0 as i64 || pc > y1 as i64
+            {
+                continue;
+            }
+            if mask.is_some_and(| mask | !contains(mask, r, c) || !contains(mask, pr as u32, pc as u32))
+            {
+                continue;
+            }
+
+            for (p, joint) in joint_frequencies.iter_mut().enumerate()
+            {
+                let pixel = img.get_pixel(r, c) [p];
+                let partner = img.get_pixel(pr as u32, pc as u32) [p];
+
+                // Handle pair if affinity is not with self
+                if pixel != partner
+                {
+                    // Counts start at 1 like get_frequencies, which shifts every count alike and keeps the same strongest pair
+                    let tuple = if pixel < partner { (partner, pixel) } else { (pixel, partner) };
+                    *joint.entry(tuple).or_insert(1) += 1;
+                }
+            }
+        }
+    }
+
+    joint_frequencies
+}
+
+// Uses affinity analysis separately for each offset direction, giving one output per direction in DIRECTIONS order
+// Each pixel is set to the difference of the most frequent pair at that offset within a window of radius distance,
+// counting only pairs inside the mask when one is given
+pub fn directional_affinity(img: &image::RgbImage, distance: u32, mask: Option<&image::GrayImage>) -> Vec<image::RgbImage>
+{
+    let (width, height) = img.dimensions();
+    DIRECTIONS.iter().map(
+        | (_, dx, dy) |
+        {
+            let offset = (dx * distance as i64, dy * distance as i64);
+            image::ImageBuffer::from_fn(width, height,
+                | x, y |
+                {
+                    // Clamp the window to the image bounds
+                    let window = (x.saturating_sub(distance), y.saturating_sub(distance),
+                                  (x + distance).min(width - 1), (y + distance).min(height - 1));
+                    let joint = get_directional_frequencies(img, window, offset, mask);
+
+                    // Find the strongest affinity with the largest pixel difference
+                    let mut out = [0; 3];
+                    for (channel, joint) in out.iter_mut().zip(&joint)
+                    {
+                        if let Some(((l, r), _)) = strongest_pair(joint)
+                        {
+                            *channel = l - r;
+                        }
+                    }
+                    image::Rgb(out)
+                }
+            )
+        }
+    ).collect()
+}
+
+// Runs directional affinity analysis on the image and saves the raw and saturated outputs of each direction
+// The output directory function maps a direction name to the directory the image belongs in
+pub fn analyze_directional<F>(img: &image::RgbImage, distance: u32, entry: &str, output_dir: F, mask: Option<&image::GrayImage>, export: &mut Export)
+    where F: Fn(&str) -> String
+{
+    let now = Instant::now();
+    let images = directional_affinity(img, distance, mask);
+    let elapsed = now.elapsed();
+    let sec = (elapsed.as_secs() as f64) + (elapsed.subsec_nanos() as f64 / 1_000_000_000.0);
+    log(&format!("Directional Affinity Analysis Completed in: {}", sec));
+
+    for ((name, _, _), mut image) in DIRECTIONS.iter().zip(images)
+    {
+        let dir = output_dir(name);
+        clear(&mut image, mask);
+        export.save(&image, &(dir.clone() + entry));
+        saturate_within(&mut image, mask);
+        export.save(&image, &("saturated_".to_owned() + &dir + entry));
+    }
+}
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+
+    // Columns alternating between two values, so only pairs reaching across columns differ
+    fn stripes(width: u32, height: u32) -> image::RgbImage
+    {
+        image::ImageBuffer::from_fn(width, height, | x, _ | image::Rgb([if x % 2 == 0 { 20 } else { 120 }; 3]))
+    }
+
+    #[test]
+    fn directions_follow_the_stripes()
+    {
+        let images = directional_affinity(&stripes(6, 5), 1, None);
+        for ((name, _, _), image) in DIRECTIONS.iter().zip(&images)
+        {
+            let expected = if *name == "vertical" { 0 } else { 100 };
+            assert!(image.pixels().all(| pixel | pixel.0 == [expected; 3]), "{} should be {} everywhere", name, expected);
+        }
+
+        // At distance 2 horizontal pairs land on the same column value too
+        let images = directional_affinity(&stripes(6, 5), 2, None);
+        assert!(images [0].pixels().all(| pixel | pixel.0 == [0; 3]));
+    }
+
+    #[test]
+    fn directional_pairs_stay_inside_the_mask()
+    {
+        // Only the even columns are inside, so no horizontal pair at distance 1 has both pixels inside
+        let mask = image::ImageBuffer::from_fn(6, 5, | x, _ | image::Luma([if x % 2 == 0 { 255 } else { 0 }]));
+        let images = directional_affinity(&stripes(6, 5), 1, Some(&mask));
+        assert!(images.iter().all(| image | image.pixels().all(| pixel | pixel.0 == [0; 3])));
+    }
+
+    #[test]
+    fn strongest_pair_prefers_frequency_then_difference()
+    {
+        let joint: HashMap<(u8, u8), usize> = [((50, 40), 3), ((90, 10), 2), ((30, 0), 3)].iter().cloned().collect();
+        assert_eq!(strongest_pair(&joint), Some(((30, 0), 3)));
+        assert_eq!(strongest_pair(&HashMap::new()), None);
+    }
+
+    #[test]
+    fn affinity_uses_the_most_frequent_pair()
+    {
+        let img = stripes(4, 4);
+        assert!(affinity(&img).pixels().all(| pixel | pixel.0 == [100; 3]));
+
+        // Inside a mask of one column there are no pairs left to count
+        let mask = image::ImageBuffer::from_fn(4, 4, | x, _ | image::Luma([if x == 1 { 255 } else { 0 }]));
+        assert!(affinity_within(&img, Some(&mask)).pixels().all(| pixel | pixel.0 == [0; 3]));
+    }
 }
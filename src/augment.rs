@@ -0,0 +1,237 @@
+use rand::Rng;
+use rand::SeedableRng;
+use rand::rngs::StdRng;
+
+// Operations every augmented copy can apply, in the order they are listed
+pub const OPERATIONS: [&str; 5] = ["rotate", "flip", "scale", "elastic", "intensity"];
+
+const MAX_ROTATION: f64 = 15.0;     // Largest rotation either way in degrees
+const MAX_SCALE: f64 = 0.1;         // Largest relative zoom in or out
+const ELASTIC_ALPHA: f64 = 8.0;     // Largest elastic displacement in pixels before smoothing
+const ELASTIC_SIGMA: f64 = 4.0;     // Standard deviation of the gaussian smoothing the displacement field
+const MAX_GAIN: f64 = 0.1;          // Largest relative change in contrast
+const MAX_BIAS: f64 = 10.0;         // Largest change in brightness in gray levels
+
+// Parses a comma separated list of operation names into indices of OPERATIONS
+pub fn parse_operations(list: &str) -> Vec<usize>
+{
+    list.split(',').map(| name | name.trim()).filter(| name | !name.is_empty()).map(
+        | name |
+        {
+            match OPERATIONS.iter().position(| operation | *operation == name)
+            {
+                Some(index) => index,
+                None => panic!("Unknown augmentation {} (expected one of {})", name, OPERATIONS.join(", ")),
+            }
+        }
+    ).collect()
+}
+
+// Names the nth augmented copy of an image by adding a suffix to its stem
+pub fn name(name: &str, copy: usize) -> String
+{
+    match name.rfind('.')
+    {
+        Some(index) => format!("{}_aug{}{}", &name [.. index], copy, &name [index ..]),
+        None => format!("{}_aug{}", name, copy),
+    }
+}
+
+// Settings for the augmented copies made of every training image
+pub struct Augmentation
+{
+    pub count: usize,
+    pub operations: Vec<usize>,
+    pub seed: u64,
+}
+
+impl Augmentation
+{
+    fn enabled(&self, operation: &str) -> bool
+    {
+        self.operations.iter().any(| index | OPERATIONS [*index] == operation)
+    }
+
+    // Makes the nth augmented copy of an image along with its mask
+    // The generator is seeded from the seed, image name and copy number so copies do not depend on processing order
+    pub fn apply(&self, img: &image::RgbImage, mask: Option<&image::GrayImage>, name: &str, copy: usize) -> (image::RgbImage, Option<image::GrayImage>)
+    {
+        let hash = name.bytes().fold(0xcbf29ce484222325u64, | hash, byte | (hash ^ byte as u64).wrapping_mul(0x100000001b3));
+        let mut rng = StdRng::seed_from_u64(self.seed ^ hash ^ (copy as u64).wrapping_mul(0x9e3779b97f4a7c15));
+        let (width, height) = img.dimensions();
+
+        // Rotation, flipping and scaling combine into one affine map about the center
+        let angle = if self.enabled("rotate") { rng.gen_range(-MAX_ROTATION, MAX_ROTATION).to_radians() } else { 0.0 };
+        let flip = self.enabled("flip") && rng.gen_bool(0.5);
+        let scale = if self.enabled("scale") { rng.gen_range(1.0 - MAX_SCALE, 1.0 + MAX_SCALE) } else { 1.0 };
+        let displacement = if self.enabled("elastic") { Some((field(&mut rng, width, height), field(&mut rng, width, height))) } else { None };
+        let (gain, bias) = if self.enabled("intensity")
+        {
+            (rng.gen_range(1.0 - MAX_GAIN, 1.0 + MAX_GAIN), rng.gen_range(-MAX_BIAS, MAX_BIAS))
+        }
+        else
+        {
+            (1.0, 0.0)
+        };
+
+        // Maps every output pixel back to the position it samples in the input
+        let (cx, cy) = ((width as f64 - 1.0) / 2.0, (height as f64 - 1.0) / 2.0);
+        let (sin, cos) = angle.sin_cos();
+        let source = | x: u32, y: u32 |
+        {
+            let (mut dx, mut dy) = (x as f64, y as f64);
+            if let Some((fx, fy)) = &displacement
+            {
+                let index = (y * width + x) as usize;
+                dx += fx [index];
+                dy += fy [index];
+            }
+            let (dx, dy) = ((dx - cx) / scale, (dy - cy) / scale);
+            let sx = cos * dx + sin * dy;
+            let sy = -sin * dx + cos * dy;
+            (if flip { cx - sx } else { cx + sx }, cy + sy)
+        };
+
+        let mut augmented = warp(img, &source, false);
+        if gain != 1.0 || bias != 0.0
+        {
+            augmented.iter_mut().for_each(| value | *value = (*value as f64 * gain + bias).round().clamp(0.0, 255.0) as u8);
+        }
+        (augmented, mask.map(| mask | warp(mask, &source, true)))
+    }
+}
+
+// Makes a random displacement field smoothed with a gaussian so neighbouring pixels move together (Simard et al.)
+fn field(rng: &mut StdRng, width: u32, height: u32) -> Vec<f64>
+{
+    let (width, height) = (width as usize, height as usize);
+    let noise: Vec<f64> = (0 .. width * height).map(| _ | rng.gen_range(-1.0, 1.0)).collect();
+
+    let radius = (3.0 * ELASTIC_SIGMA).ceil() as i64;
+    let kernel: Vec<f64> = (-radius ..= radius).map(| i | (-(i * i) as f64 / (2.0 * ELASTIC_SIGMA * ELASTIC_SIGMA)).exp()).collect();
+    let total: f64 = kernel.iter().sum();
+    let kernel: Vec<f64> = kernel.iter().map(| weight | weight / total).collect();
+
+    // The smoothing is separable, clamping at the borders
+    let blur = | values: &[f64], step: usize, length: usize, count: usize, stride: usize |
+    {
+        let mut out = vec![0.0; values.len()];
+        for line in 0 .. count
+        {
+            for i in 0 .. length
+            {
+                out [line * stride + i * step] = kernel.iter().enumerate().map(
+                    | (k, weight) | weight * values [line * stride + (i as i64 + k as i64 - radius).clamp(0, length as i64 - 1) as usize * step]
+                ).sum();
+            }
+        }
+        out
+    };
+    let rows = blur(&noise, 1, width, height, width);
+    let smoothed = blur(&rows, width, height, width, 1);
+    smoothed.iter().map(| value | value * ELASTIC_ALPHA).collect()
+}
+
+// Resamples an image at the positions given for every output pixel, with black outside the input
+// Masks use nearest neighbour sampling so they stay binary while images are interpolated bilinearly
+fn warp<P, F>(img: &image::ImageBuffer<P, Vec<u8>>, source: &F, nearest: bool) -> image::ImageBuffer<P, Vec<u8>>
+    where P: image::Pixel<Subpixel = u8> + 'static, F: Fn(u32, u32) -> (f64, f64)
+{
+    let (width, height) = img.dimensions();
+    let channels = P::CHANNEL_COUNT as usize;
+    let sample = | x: i64, y: i64, channel: usize | -> f64
+    {
+        if x < 0 || y < 0 || x >= width as i64 || y >= height as i64 { 0.0 } else { img.get_pixel(x as u32, y as u32).channels() [channel] as f64 }
+    };
+    let mut out = img.clone();
+    out.enumerate_pixels_mut().for_each(
+        | (x, y, pixel) |
+        {
+            let (sx, sy) = source(x, y);
+            let values = pixel.channels_mut();
+            for (channel, value) in values.iter_mut().enumerate().take(channels)
+            {
+                *value = if nearest
+                {
+                    sample(sx.round() as i64, sy.round() as i64, channel) as u8
+                }
+                else
+                {
+                    let (x0, y0) = (sx.floor(), sy.floor());
+                    let (fx, fy) = (sx - x0, sy - y0);
+                    let (x0, y0) = (x0 as i64, y0 as i64);
+                    let top = sample(x0, y0, channel) * (1.0 - fx) + sample(x0 + 1, y0, channel) * fx;
+                    let bottom = sample(x0, y0 + 1, channel) * (1.0 - fx) + sample(x0 + 1, y0 + 1, channel) * fx;
+                    (top * (1.0 - fy) + bottom * fy).round().clamp(0.0, 255.0) as u8
+                };
+            }
+        }
+    );
+    out
+}
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+
+    fn scan() -> (image::RgbImage, image::GrayImage)
+    {
+        let img = image::ImageBuffer::from_fn(24, 18, | x, y | image::Rgb([(x * 10) as u8, (y * 12) as u8, ((x + y) * 5) as u8]));
+        let mask = image::ImageBuffer::from_fn(24, 18, | x, y | image::Luma([if (6 .. 18).contains(&x) && (4 .. 14).contains(&y) { 255 } else { 0 }]));
+        (img, mask)
+    }
+
+    fn augmentation(operations: &str, seed: u64) -> Augmentation
+    {
+        Augmentation { count: 2, operations: parse_operations(operations), seed }
+    }
+
+    #[test]
+    fn copies_depend_only_on_seed_name_and_number()
+    {
+        let (img, mask) = scan();
+        let all = augmentation(&OPERATIONS.join(","), 7);
+        let (first, first_mask) = all.apply(&img, Some(&mask), "a.bmp", 1);
+        let (again, again_mask) = all.apply(&img, Some(&mask), "a.bmp", 1);
+        assert!(first.clone().into_raw() == again.into_raw());
+        assert!(first_mask.clone().unwrap().into_raw() == again_mask.unwrap().into_raw());
+        assert!(first_mask.unwrap().pixels().all(| pixel | pixel [0] == 0 || pixel [0] == 255), "mask is not binary");
+
+        let (other_copy, _) = all.apply(&img, None, "a.bmp", 2);
+        let (other_name, _) = all.apply(&img, None, "b.bmp", 1);
+        let (other_seed, _) = augmentation(&OPERATIONS.join(","), 8).apply(&img, None, "a.bmp", 1);
+        for other in [other_copy, other_name, other_seed]
+        {
+            assert!(other.into_raw() != first.clone().into_raw());
+        }
+    }
+
+    #[test]
+    fn no_operations_give_the_input_back()
+    {
+        let (img, mask) = scan();
+        let (augmented, augmented_mask) = augmentation("", 3).apply(&img, Some(&mask), "a.bmp", 1);
+        assert!(augmented.into_raw() == img.into_raw());
+        assert!(augmented_mask.unwrap().into_raw() == mask.into_raw());
+    }
+
+    #[test]
+    fn flipping_mirrors_exactly()
+    {
+        let (img, _) = scan();
+        let mirrored = image::imageops::flip_horizontal(&img);
+        let flip = augmentation("flip", 1);
+        let results: Vec<Vec<u8>> = (1 ..= 8).map(| copy | flip.apply(&img, None, "a.bmp", copy).0.into_raw()).collect();
+        assert!(results.iter().all(| result | *result == img.clone().into_raw() || *result == mirrored.clone().into_raw()));
+        assert!(results.iter().any(| result | *result == mirrored.clone().into_raw()), "no copy was flipped");
+    }
+
+    #[test]
+    fn names_copies_by_number()
+    {
+        assert_eq!(name("dir/a.bmp", 3), "dir/a_aug3.bmp");
+        assert_eq!(name("a", 1), "a_aug1");
+        assert_eq!(parse_operations("flip, elastic"), [1, 3]);
+    }
+}
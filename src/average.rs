@@ -1,34 +1,32 @@
-use crate::saturate::saturate;
+use crate::export::Export;
+use crate::blend::{blend, BlendOp};
+use crate::mask::{clear, saturate_within};
+use crate::progress::log;
 
 use std::time::Instant;
 
-pub fn analyze_average(original: &image::RgbImage, analyzed: &image::RgbImage, entry: &str, output_dir: &str)
+// Sets every pixel to the mean of the original and analyzed pixels
+pub fn average(original: &image::RgbImage, analyzed: &image::RgbImage) -> image::RgbImage
 {
-    // Setup image to be copied to and start counting time
-    let (width, height) = analyzed.dimensions();
-    let mut image: image::RgbImage = image::ImageBuffer::new(width, height);
-    let now = Instant::now();
+    blend(&[original, analyzed], &[1.0, 1.0], BlendOp::Mean)
+}
 
-    image.enumerate_pixels_mut().for_each(
-        | (x, y, pixel) |
-        {
-            let mut out = [0; 3];
-            for i in 0 .. 3
-            {
-                out [i] = ((original.get_pixel(x, y) [i] as u16 + analyzed.get_pixel(x, y) [i] as u16) / 2) as u8;
-            }
-            *pixel = image::Rgb(out);
-        }
-    );
+// Averages the original and analyzed images and saves both the raw and saturated outputs
+pub fn analyze_average(original: &image::RgbImage, analyzed: &image::RgbImage, entry: &str, output_dir: &str, mask: Option<&image::GrayImage>, export: &mut Export)
+{
+    // Start counting time and run the analysis
+    let now = Instant::now();
+    let mut image = average(original, analyzed);
+    clear(&mut image, mask);
 
     let elapsed = now.elapsed();
-    let sec = (elapsed.as_secs() as f64) + (elapsed.subsec_nanos() as f64 / 1000_000_000.0);
-    println!("Average Analysis Completed in: {}", sec);
-    image.save(output_dir.to_owned() + entry).unwrap();
+    let sec = (elapsed.as_secs() as f64) + (elapsed.subsec_nanos() as f64 / 1_000_000_000.0);
+    log(&format!("Average Analysis Completed in: {}", sec));
+    export.save(&image, &(output_dir.to_owned() + entry));
     let now = Instant::now();
-    saturate(&mut image);
+    saturate_within(&mut image, mask);
     let elapsed = now.elapsed();
-    let sec = (elapsed.as_secs() as f64) + (elapsed.subsec_nanos() as f64 / 1000_000_000.0);
-    println!("Output Saturated in: {}", sec);
-    image.save("saturated_".to_owned() + output_dir + entry).unwrap();
+    let sec = (elapsed.as_secs() as f64) + (elapsed.subsec_nanos() as f64 / 1_000_000_000.0);
+    log(&format!("Output Saturated in: {}", sec));
+    export.save(&image, &("saturated_".to_owned() + output_dir + entry));
 }
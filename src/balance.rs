@@ -0,0 +1,197 @@
+use crate::augment;
+use crate::inputs::Input;
+
+use rand::Rng;
+use rand::SeedableRng;
+use rand::rngs::StdRng;
+use rand::seq::SliceRandom;
+use std::collections::{BTreeMap, BTreeSet};
+
+// Ways of evening out the number of training images in each category
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Strategy
+{
+    None,
+    Undersample,
+    Oversample,
+    Augment,
+}
+
+impl Strategy
+{
+    pub fn from_name(name: &str) -> Strategy
+    {
+        match name
+        {
+            "none" => Strategy::None,
+            "undersample" => Strategy::Undersample,
+            "oversample" => Strategy::Oversample,
+            "augment" => Strategy::Augment,
+            _ => panic!("Unknown balancing strategy {} (expected none, undersample, oversample or augment)", name),
+        }
+    }
+}
+
+// What an output image is made from
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Origin
+{
+    Original,
+    Duplicate(usize),
+    Augmented(usize),
+}
+
+impl Origin
+{
+    // Names the output made from an input image
+    pub fn name(self, name: &str) -> String
+    {
+        match self
+        {
+            Origin::Original => name.to_owned(),
+            Origin::Duplicate(copy) => match name.rfind('.')
+            {
+                Some(index) => format!("{}_dup{}{}", &name [.. index], copy, &name [index ..]),
+                None => format!("{}_dup{}", name, copy),
+            },
+            Origin::Augmented(copy) => augment::name(name, copy),
+        }
+    }
+
+    // Describes the origin for the manifest
+    pub fn kind(self) -> &'static str
+    {
+        match self
+        {
+            Origin::Original => "original",
+            Origin::Duplicate(_) => "duplicate",
+            Origin::Augmented(_) => "augmented",
+        }
+    }
+}
+
+// An image to process, made from the input with the given file name
+pub struct Job
+{
+    pub input: Input,
+    pub name: String,
+    pub origin: Origin,
+}
+
+// Counts the jobs in each training category
+pub fn counts<F>(jobs: &[Job], category: &F) -> BTreeMap<String, usize>
+    where F: Fn(&str) -> Option<String>
+{
+    let mut counts = BTreeMap::new();
+    jobs.iter().filter_map(| job | category(&job.name)).for_each(| label | *counts.entry(label).or_insert(0) += 1);
+    counts
+}
+
+// Evens out the training categories by dropping jobs from the larger ones or adding copies of originals to the smaller ones
+// Undersampling drops whole source images along with all their copies, keeping as many sources as the smallest category has
+// The category function gives the training category of an input and None for images outside the training split
+pub fn balance<F>(jobs: Vec<Job>, category: F, strategy: Strategy, augmented: usize, seed: u64) -> Vec<Job>
+    where F: Fn(&str) -> Option<String>
+{
+    if strategy == Strategy::None
+    {
+        return jobs;
+    }
+    let mut rng = StdRng::seed_from_u64(seed);
+    let mut groups: BTreeMap<String, Vec<Job>> = BTreeMap::new();
+    let mut balanced = Vec::new();
+    for job in jobs
+    {
+        match category(&job.name)
+        {
+            Some(label) => groups.entry(label).or_default().push(job),
+            None => balanced.push(job),
+        }
+    }
+
+    let target = if strategy == Strategy::Undersample
+    {
+        groups.values().map(| group | group.iter().map(| job | &job.name).collect::<BTreeSet<_>>().len()).min()
+    }
+    else
+    {
+        groups.values().map(| group | group.len()).max()
+    }.unwrap_or(0);
+    for (_, group) in groups
+    {
+        if strategy == Strategy::Undersample
+        {
+            let mut sources: Vec<String> = group.iter().map(| job | job.name.clone()).collect::<BTreeSet<_>>().into_iter().collect();
+            sources.shuffle(&mut rng);
+            sources.truncate(target);
+            let kept: BTreeSet<String> = sources.into_iter().collect();
+            balanced.extend(group.into_iter().filter(| job | kept.contains(&job.name)));
+            continue;
+        }
+
+        // New copies are numbered after the ones each original already has
+        let originals: Vec<(Input, String)> = group.iter().filter(| job | job.origin == Origin::Original).map(| job | (job.input.clone(), job.name.clone())).collect();
+        let mut next: BTreeMap<String, usize> = originals.iter().map(| (_, name) | (name.clone(), if strategy == Strategy::Augment { augmented + 1 } else { 1 })).collect();
+        let missing = target - group.len();
+        balanced.extend(group);
+        for _ in 0 .. missing
+        {
+            let (input, name) = &originals [rng.gen_range(0, originals.len())];
+            let copy = next.get_mut(name).unwrap();
+            balanced.push(Job { input: input.clone(), name: name.clone(), origin: if strategy == Strategy::Augment { Origin::Augmented(*copy) } else { Origin::Duplicate(*copy) } });
+            *copy += 1;
+        }
+    }
+    balanced
+}
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+    use crate::inputs::Source;
+    use std::path::PathBuf;
+
+    // Jobs for the given originals, each followed by the given number of augmented copies
+    fn jobs(names: &[&str], augmented: usize) -> Vec<Job>
+    {
+        let mut jobs = Vec::new();
+        for name in names
+        {
+            let input = Input { path: name.to_string(), source: Source::File(PathBuf::from(name)) };
+            jobs.push(Job { input: input.clone(), name: name.to_string(), origin: Origin::Original });
+            jobs.extend((1 ..= augmented).map(| copy | Job { input: input.clone(), name: name.to_string(), origin: Origin::Augmented(copy) }));
+        }
+        jobs
+    }
+
+    fn category(name: &str) -> Option<String>
+    {
+        Some(if name.starts_with('a') { "Abnormal" } else { "Normal" }.to_owned())
+    }
+
+    #[test]
+    fn undersampling_keeps_copies_with_their_source()
+    {
+        for seed in 0 .. 20
+        {
+            let balanced = balance(jobs(&["a1", "a2", "n1", "n2", "n3", "n4", "n5"], 2), category, Strategy::Undersample, 2, seed);
+            assert_eq!(balanced.len(), 12);
+            assert_eq!(counts(&balanced, &category).values().cloned().collect::<Vec<usize>>(), vec![6, 6]);
+            for job in &balanced
+            {
+                let copies = balanced.iter().filter(| other | other.name == job.name).count();
+                assert_eq!(copies, 3, "{} lost an original or copy", job.name);
+            }
+        }
+    }
+
+    #[test]
+    fn oversampling_duplicates_originals()
+    {
+        let balanced = balance(jobs(&["a1", "n1", "n2"], 1), category, Strategy::Oversample, 1, 0);
+        assert_eq!(counts(&balanced, &category).values().cloned().collect::<Vec<usize>>(), vec![4, 4]);
+        let duplicates: Vec<String> = balanced.iter().filter(| job | job.origin != Origin::Original && job.name == "a1").map(| job | job.origin.name(&job.name)).collect();
+        assert_eq!(duplicates, ["a1_aug1", "a1_dup1", "a1_dup2"]);
+    }
+}
@@ -0,0 +1,227 @@
+use crate::export::Export;
+use crate::filters;
+use crate::mask::{clear, saturate_within};
+use crate::progress::log;
+
+use std::time::Instant;
+
+// Operators used to combine the weighted inputs of a blend
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum BlendOp
+{
+    Mean,
+    Max,
+    Min,
+    Product,
+    Difference,
+    AlphaMask,
+}
+
+impl BlendOp
+{
+    pub fn from_name(name: &str) -> BlendOp
+    {
+        match name
+        {
+            "mean" => BlendOp::Mean,
+            "max" => BlendOp::Max,
+            "min" => BlendOp::Min,
+            "product" => BlendOp::Product,
+            "difference" => BlendOp::Difference,
+            "alpha" => BlendOp::AlphaMask,
+            _ => panic!("Unknown blend operator {} (expected mean, max, min, product, difference or alpha)", name),
+        }
+    }
+}
+
+// Parses a comma separated list of filter:weight pairs (the weight defaults to 1)
+pub fn parse_inputs(list: &str) -> Vec<(String, f64)>
+{
+    list.split(',').map(| item | item.trim()).filter(| item | !item.is_empty()).map(
+        | item |
+        {
+            let mut parts = item.splitn(2, ':');
+            let name = parts.next().unwrap().trim().to_owned();
+            let weight = match parts.next()
+            {
+                Some(weight) => weight.trim().parse::<f64>().unwrap_or_else(| _ | panic!("Invalid blend weight in {}", item)),
+                None => 1.0,
+            };
+            filters::check(&name);
+            (name, weight)
+        }
+    ).collect()
+}
+
+// Checks that the inputs can be blended with the operator, so bad settings are reported before any image is processed
+pub fn check(inputs: &[(String, f64)], op: BlendOp)
+{
+    if inputs.len() == 1
+    {
+        panic!("Blending needs at least two filters");
+    }
+    if op == BlendOp::AlphaMask && inputs.len() > 3
+    {
+        panic!("Alpha mask blending takes a mask and at most two layers, but {} filters were given", inputs.len());
+    }
+    if op == BlendOp::Mean && !inputs.is_empty() && inputs.iter().map(| (_, weight) | weight).sum::<f64>() == 0.0
+    {
+        panic!("Blend weights sum to zero, so their weighted mean is undefined");
+    }
+}
+
+// Combines one channel value (0-255) from each input
+fn combine(values: &[f64], weights: &[f64], op: BlendOp) -> f64
+{
+    let weighted = values.iter().zip(weights).map(| (value, weight) | value * weight);
+    match op
+    {
+        // Weighted mean normalised by the total weight
+        BlendOp::Mean => weighted.sum::<f64>() / weights.iter().sum::<f64>(),
+        BlendOp::Max => weighted.fold(f64::MIN, f64::max),
+        BlendOp::Min => weighted.fold(f64::MAX, f64::min),
+        BlendOp::Product => 255.0 * weighted.map(| value | value / 255.0).product::<f64>(),
+
+        // Absolute difference between the first input and the sum of the rest
+        BlendOp::Difference =>
+        {
+            let weighted: Vec<f64> = weighted.collect();
+            (weighted [0] - weighted [1 ..].iter().sum::<f64>()).abs()
+        },
+
+        // The first input is the alpha mask laid over the second input (and under the third, if given)
+        BlendOp::AlphaMask =>
+        {
+            let weighted: Vec<f64> = weighted.collect();
+            let alpha = (weighted [0] / 255.0).clamp(0.0, 1.0);
+            let under = if weighted.len() > 2 { weighted [2] } else { 0.0 };
+            alpha * weighted [1] + (1.0 - alpha) * under
+        },
+    }
+}
+
+// Blends two or more images pixel by pixel with the given weights and operator (results are truncated like the other filters)
+pub fn blend(images: &[&image::RgbImage], weights: &[f64], op: BlendOp) -> image::RgbImage
+{
+    assert!(images.len() >= 2, "Blending needs at least two inputs");
+    assert_eq!(images.len(), weights.len());
+    if op == BlendOp::AlphaMask
+    {
+        assert!(images.len() <= 3, "Alpha mask blending takes a mask and at most two layers");
+    }
+
+    let (width, height) = images [0].dimensions();
+    let mut values = vec![0.0; images.len()];
+    image::ImageBuffer::from_fn(width, height,
+        | x, y |
+        {
+            let mut out = [0; 3];
+            for (i, channel) in out.iter_mut().enumerate()
+            {
+                for (value, image) in values.iter_mut().zip(images)
+                {
+                    *value = image.get_pixel(x, y) [i] as f64;
+                }
+                *channel = combine(&values, weights, op).clamp(0.0, 255.0) as u8;
+            }
+            image::Rgb(out)
+        }
+    )
+}
+
+// Blends the saturated outputs of the given filters and saves both the raw and saturated results
+// Max diff and center diff inputs look the given radius from each pixel
+#[allow(clippy::too_many_arguments)]
+pub fn analyze_blend(img: &image::RgbImage, diff_radius: u32, inputs: &[(String, f64)], op: BlendOp, entry: &str, output_dir: &str, mask: Option<&image::GrayImage>, export: &mut Export)
+{
+    let now = Instant::now();
+
+    // Inputs are saturated first so filters with small output ranges still contribute, like the average output
+    let outputs: Vec<image::RgbImage> = inputs.iter().map(
+        | (name, _) |
+        {
+            let mut output = filters::run(name, img, diff_radius, mask);
+            saturate_within(&mut output, mask);
+            output
+        }
+    ).collect();
+    let images: Vec<&image::RgbImage> = outputs.iter().collect();
+    let weights: Vec<f64> = inputs.iter().map(| (_, weight) | *weight).collect();
+    let mut image = blend(&images, &weights, op);
+    clear(&mut image, mask);
+
+    let elapsed = now.elapsed();
+    let sec = (elapsed.as_secs() as f64) + (elapsed.subsec_nanos() as f64 / 1_000_000_000.0);
+    log(&format!("Blend Analysis Completed in: {}", sec));
+    export.save(&image, &(output_dir.to_owned() + entry));
+    saturate_within(&mut image, mask);
+    export.save(&image, &("saturated_".to_owned() + output_dir + entry));
+}
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+
+    fn pixel(value: u8) -> image::RgbImage
+    {
+        image::RgbImage::from_pixel(1, 1, image::Rgb([value; 3]))
+    }
+
+    fn blended(values: &[u8], weights: &[f64], op: BlendOp) -> u8
+    {
+        let images: Vec<image::RgbImage> = values.iter().map(| value | pixel(*value)).collect();
+        blend(&images.iter().collect::<Vec<&image::RgbImage>>(), weights, op).get_pixel(0, 0) [0]
+    }
+
+    #[test]
+    fn operators_give_known_pixels()
+    {
+        assert_eq!(blended(&[200, 100], &[1.0, 3.0], BlendOp::Mean), 125);
+        assert_eq!(blended(&[200, 100], &[1.0, 1.0], BlendOp::Max), 200);
+        assert_eq!(blended(&[200, 100], &[0.25, 1.0], BlendOp::Max), 100);
+        assert_eq!(blended(&[200, 100], &[1.0, 1.0], BlendOp::Min), 100);
+        assert_eq!(blended(&[255, 102], &[1.0, 1.0], BlendOp::Product), 102);
+        assert_eq!(blended(&[200, 30, 50], &[1.0, 1.0, 1.0], BlendOp::Difference), 120);
+        assert_eq!(blended(&[50, 200], &[1.0, 1.0], BlendOp::Difference), 150);
+        // Weighted results beyond the range are clamped
+        assert_eq!(blended(&[200, 100], &[2.0, 1.0], BlendOp::Max), 255);
+    }
+
+    #[test]
+    fn alpha_mask_chooses_between_layers()
+    {
+        assert_eq!(blended(&[255, 200, 100], &[1.0, 1.0, 1.0], BlendOp::AlphaMask), 200);
+        assert_eq!(blended(&[0, 200, 100], &[1.0, 1.0, 1.0], BlendOp::AlphaMask), 100);
+        assert_eq!(blended(&[0, 200], &[1.0, 1.0], BlendOp::AlphaMask), 0);
+        assert_eq!(blended(&[255, 200], &[1.0, 1.0], BlendOp::AlphaMask), 200);
+    }
+
+    #[test]
+    fn inputs_default_to_unit_weights()
+    {
+        assert_eq!(parse_inputs("affinity, max_diff:0.5,"), [("affinity".to_owned(), 1.0), ("max_diff".to_owned(), 0.5)]);
+        check(&parse_inputs("affinity:1,max_diff:-1"), BlendOp::Difference);
+    }
+
+    #[test]
+    #[should_panic(expected = "Blend weights sum to zero, so their weighted mean is undefined")]
+    fn rejects_weights_summing_to_zero()
+    {
+        check(&parse_inputs("affinity:1,max_diff:-1"), BlendOp::Mean);
+    }
+
+    #[test]
+    #[should_panic(expected = "Blending needs at least two filters")]
+    fn rejects_a_single_input()
+    {
+        check(&parse_inputs("affinity"), BlendOp::Max);
+    }
+
+    #[test]
+    #[should_panic(expected = "Alpha mask blending takes a mask and at most two layers, but 4 filters were given")]
+    fn rejects_too_many_alpha_layers()
+    {
+        check(&parse_inputs("original,affinity,max_diff,center_diff"), BlendOp::AlphaMask);
+    }
+}
@@ -1,70 +1,50 @@
-use crate::saturate::saturate;
+use crate::export::Export;
+use crate::kernels::{apply, window_min_max_within, Op};
+use crate::mask::{clear, saturate_within};
+use crate::progress::log;
 
 use std::time::Instant;
 
-// Sets every pixel to the largest difference between it and its neighbors
-pub fn analyze_center_diff(img: &image::RgbImage, entry: &str, output_dir: &str)
+// Sets every pixel to the largest difference between it and its neighbors in the 2x2 square of it and the pixels before it
+pub fn center_diff(img: &image::RgbImage) -> image::RgbImage
 {
-    // Setup image to be copied to and start counting time
-    let (width, height) = img.dimensions();
-    let mut image: image::RgbImage = image::ImageBuffer::new(width, height);
-    let now = Instant::now();
-
-    image.enumerate_pixels_mut().for_each(
-        | (x, y, pixel) |
-        {
-            // Handle edge cases to allow keeping the image 1024x1024
-            let rl = if x > 0 { x - 1 } else { x };
-            let rr = if x < width - 1 { x + 1 } else { x };
-            let cl = if y > 0 { y - 1 } else { y };
-            let cr = if y < height - 1 { y + 1 } else { y };
+    center_diff_radius(img, 0)
+}
 
-            let mut out = [0; 3];
-            for i in 0 .. 3
-            {
-                let mut max = 0;
-                let center = img.get_pixel(x, y) [i];
-                
-                for r in rl .. rr
-                {
-                    for c in cl .. cr
-                    {
-                        let num = img.get_pixel(r, c) [i];
+// Sets every pixel to the largest difference between it and its neighbors in the window of the given radius (see window_min_max)
+pub fn center_diff_radius(img: &image::RgbImage, radius: u32) -> image::RgbImage
+{
+    center_diff_within(img, radius, None)
+}
 
-                        // Keeps the largest difference with respect to the center pixel
-                        if num > center
-                        {
-                            let diff = num - center;
-                            if diff > max
-                            {
-                                max = diff;
-                            }
-                        }
-                        else
-                        {
-                            let diff = center - num;
-                            if diff > max
-                            {
-                                max = diff;
-                            }
-                        }
-                    }
-                }
-                out [i] = max;
-            }
+// Sets every pixel to the largest difference between it and the neighbors in its window that lie inside the mask
+pub fn center_diff_within(img: &image::RgbImage, radius: u32, mask: Option<&image::GrayImage>) -> image::RgbImage
+{
+    // The largest difference from the center is to either the largest or the smallest value in the window
+    let (min, mut image) = window_min_max_within(img, radius, mask);
+    apply(Op::Sub, &mut image, img);
+    let mut below = img.clone();
+    apply(Op::Sub, &mut below, &min);
+    apply(Op::Max, &mut image, &below);
+    image
+}
 
-            *pixel = image::Rgb(out);
-        }
-    );
+// Runs center diff analysis on the image and saves both the raw and saturated outputs
+pub fn analyze_center_diff(img: &image::RgbImage, radius: u32, entry: &str, output_dir: &str, mask: Option<&image::GrayImage>, export: &mut Export)
+{
+    // Start counting time and run the analysis
+    let now = Instant::now();
+    let mut image = center_diff_within(img, radius, mask);
+    clear(&mut image, mask);
 
     let elapsed = now.elapsed();
-    let sec = (elapsed.as_secs() as f64) + (elapsed.subsec_nanos() as f64 / 1000_000_000.0);
-    println!("Center Diff Analysis Completed in: {}", sec);
-    image.save(output_dir.to_owned() + entry).unwrap();
+    let sec = (elapsed.as_secs() as f64) + (elapsed.subsec_nanos() as f64 / 1_000_000_000.0);
+    log(&format!("Center Diff Analysis Completed in: {}", sec));
+    export.save(&image, &(output_dir.to_owned() + entry));
     let now = Instant::now();
-    saturate(&mut image);
+    saturate_within(&mut image, mask);
     let elapsed = now.elapsed();
-    let sec = (elapsed.as_secs() as f64) + (elapsed.subsec_nanos() as f64 / 1000_000_000.0);
-    println!("Output Saturated in: {}", sec);
-    image.save("saturated_".to_owned() + output_dir + entry).unwrap();
+    let sec = (elapsed.as_secs() as f64) + (elapsed.subsec_nanos() as f64 / 1_000_000_000.0);
+    log(&format!("Output Saturated in: {}", sec));
+    export.save(&image, &("saturated_".to_owned() + output_dir + entry));
 }
@@ -0,0 +1,334 @@
+use rand::Rng;
+use rand::SeedableRng;
+use rand::rngs::StdRng;
+use rand::seq::SliceRandom;
+
+// Binary classifiers trained on feature vectors, scoring how likely a sample is to be positive
+pub trait Classifier
+{
+    fn fit(&mut self, x: &[Vec<f64>], y: &[bool]);
+
+    // Returns a score between 0 and 1, where higher means more likely positive
+    fn score(&self, x: &[f64]) -> f64;
+}
+
+// Names of the available classifiers
+pub const CLASSIFIERS: [&str; 3] = ["knn", "logistic", "forest"];
+
+// Creates a classifier by name with its default settings
+pub fn by_name(name: &str, seed: u64) -> Box<dyn Classifier>
+{
+    match name
+    {
+        "knn" => Box::new(Knn::new(5)),
+        "logistic" => Box::new(LogisticRegression::new(1000, 0.1, 0.01)),
+        "forest" => Box::new(RandomForest::new(50, 8, 2, seed)),
+        _ => panic!("Unknown classifier {} (expected one of {})", name, CLASSIFIERS.join(", ")),
+    }
+}
+
+fn distance(a: &[f64], b: &[f64]) -> f64
+{
+    a.iter().zip(b).map(| (a, b) | (a - b) * (a - b)).sum()
+}
+
+// k-nearest neighbours with the fraction of positive neighbours as the score
+pub struct Knn
+{
+    k: usize,
+    x: Vec<Vec<f64>>,
+    y: Vec<bool>,
+}
+
+impl Knn
+{
+    pub fn new(k: usize) -> Knn
+    {
+        Knn { k, x: Vec::new(), y: Vec::new() }
+    }
+}
+
+impl Classifier for Knn
+{
+    fn fit(&mut self, x: &[Vec<f64>], y: &[bool])
+    {
+        self.x = x.to_vec();
+        self.y = y.to_vec();
+    }
+
+    fn score(&self, x: &[f64]) -> f64
+    {
+        let mut neighbours: Vec<(f64, bool)> = self.x.iter().zip(&self.y).map(| (train, label) | (distance(train, x), *label)).collect();
+        neighbours.sort_by(| a, b | a.0.partial_cmp(&b.0).unwrap());
+        let k = self.k.min(neighbours.len());
+        neighbours [.. k].iter().filter(| (_, label) | *label).count() as f64 / k as f64
+    }
+}
+
+// Logistic regression trained with full batch gradient descent and L2 regularisation
+pub struct LogisticRegression
+{
+    iterations: usize,
+    rate: f64,
+    l2: f64,
+    weights: Vec<f64>,
+    bias: f64,
+}
+
+impl LogisticRegression
+{
+    pub fn new(iterations: usize, rate: f64, l2: f64) -> LogisticRegression
+    {
+        LogisticRegression { iterations, rate, l2, weights: Vec::new(), bias: 0.0 }
+    }
+}
+
+fn sigmoid(z: f64) -> f64
+{
+    1.0 / (1.0 + (-z).exp())
+}
+
+impl Classifier for LogisticRegression
+{
+    fn fit(&mut self, x: &[Vec<f64>], y: &[bool])
+    {
+        let n = x.len() as f64;
+        self.weights = vec![0.0; x [0].len()];
+        self.bias = 0.0;
+        for _ in 0 .. self.iterations
+        {
+            let mut gradient = vec![0.0; self.weights.len()];
+            let mut bias_gradient = 0.0;
+            for (sample, label) in x.iter().zip(y)
+            {
+                let error = self.score(sample) - if *label { 1.0 } else { 0.0 };
+                for (g, value) in gradient.iter_mut().zip(sample)
+                {
+                    *g += error * value;
+                }
+                bias_gradient += error;
+            }
+            for (weight, g) in self.weights.iter_mut().zip(&gradient)
+            {
+                *weight -= self.rate * (g / n + self.l2 * *weight);
+            }
+            self.bias -= self.rate * bias_gradient / n;
+        }
+    }
+
+    fn score(&self, x: &[f64]) -> f64
+    {
+        sigmoid(self.bias + self.weights.iter().zip(x).map(| (w, v) | w * v).sum::<f64>())
+    }
+}
+
+// Decision tree node holding the fraction of positive samples at its leaves
+enum Node
+{
+    Leaf(f64),
+    Split
+    {
+        feature: usize,
+        threshold: f64,
+        left: Box<Node>,
+        right: Box<Node>,
+    },
+}
+
+impl Node
+{
+    fn score(&self, x: &[f64]) -> f64
+    {
+        match self
+        {
+            Node::Leaf(score) => *score,
+            Node::Split { feature, threshold, left, right } => if x [*feature] <= *threshold { left.score(x) } else { right.score(x) },
+        }
+    }
+}
+
+fn gini(positive: usize, total: usize) -> f64
+{
+    if total == 0
+    {
+        return 0.0;
+    }
+    let p = positive as f64 / total as f64;
+    2.0 * p * (1.0 - p)
+}
+
+// Random forest of Gini decision trees grown on bootstrap samples with random feature subsets at each split
+pub struct RandomForest
+{
+    trees: usize,
+    max_depth: usize,
+    min_leaf: usize,
+    seed: u64,
+    forest: Vec<Node>,
+}
+
+impl RandomForest
+{
+    pub fn new(trees: usize, max_depth: usize, min_leaf: usize, seed: u64) -> RandomForest
+    {
+        RandomForest { trees, max_depth, min_leaf, seed, forest: Vec::new() }
+    }
+
+    fn grow(&self, x: &[Vec<f64>], y: &[bool], samples: &[usize], depth: usize, rng: &mut StdRng) -> Node
+    {
+        let positive = samples.iter().filter(| i | y [**i]).count();
+        let leaf = Node::Leaf(positive as f64 / samples.len() as f64);
+        if depth >= self.max_depth || positive == 0 || positive == samples.len() || samples.len() < 2 * self.min_leaf
+        {
+            return leaf;
+        }
+
+        // Consider the square root of the number of features at each split
+        let features = x [0].len();
+        let mut candidates: Vec<usize> = (0 .. features).collect();
+        candidates.shuffle(rng);
+        candidates.truncate(((features as f64).sqrt().ceil() as usize).max(1));
+
+        let mut best: Option<(f64, usize, f64)> = None;
+        let parent = gini(positive, samples.len());
+        for feature in candidates
+        {
+            let mut sorted: Vec<(f64, bool)> = samples.iter().map(| i | (x [*i] [feature], y [*i])).collect();
+            sorted.sort_by(| a, b | a.0.partial_cmp(&b.0).unwrap());
+
+            // Sweep thresholds between consecutive distinct values, tracking positives on the left
+            let mut left_positive = 0;
+            for i in 1 .. sorted.len()
+            {
+                if sorted [i - 1].1
+                {
+                    left_positive += 1;
+                }
+                if sorted [i].0 == sorted [i - 1].0 || i < self.min_leaf || sorted.len() - i < self.min_leaf
+                {
+                    continue;
+                }
+                let right = sorted.len() - i;
+                let impurity = (i as f64 * gini(left_positive, i) + right as f64 * gini(positive - left_positive, right)) / sorted.len() as f64;
+                if impurity < parent && best.is_none_or(| (b, _, _) | impurity < b)
+                {
+                    best = Some((impurity, feature, (sorted [i - 1].0 + sorted [i].0) / 2.0));
+                }
+            }
+        }
+
+        match best
+        {
+            Some((_, feature, threshold)) =>
+            {
+                let (left, right): (Vec<usize>, Vec<usize>) = samples.iter().partition(| i | x [**i] [feature] <= threshold);
+                Node::Split
+                {
+                    feature,
+                    threshold,
+                    left: Box::new(self.grow(x, y, &left, depth + 1, rng)),
+                    right: Box::new(self.grow(x, y, &right, depth + 1, rng)),
+                }
+            },
+            None => leaf,
+        }
+    }
+}
+
+impl Classifier for RandomForest
+{
+    fn fit(&mut self, x: &[Vec<f64>], y: &[bool])
+    {
+        let mut rng = StdRng::seed_from_u64(self.seed);
+        self.forest = (0 .. self.trees).map(
+            | _ |
+            {
+                let samples: Vec<usize> = (0 .. x.len()).map(| _ | rng.gen_range(0, x.len())).collect();
+                self.grow(x, y, &samples, 0, &mut rng)
+            }
+        ).collect();
+    }
+
+    fn score(&self, x: &[f64]) -> f64
+    {
+        self.forest.iter().map(| tree | tree.score(x)).sum::<f64>() / self.forest.len() as f64
+    }
+}
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+
+    // Two clusters, negatives around (0, 0) and positives around (4, 4)
+    fn clusters() -> (Vec<Vec<f64>>, Vec<bool>)
+    {
+        let mut x = Vec::new();
+        let mut y = Vec::new();
+        for i in 0 .. 10
+        {
+            let offset = (i % 3) as f64 * 0.3;
+            x.push(vec![offset, 0.5 - offset]);
+            y.push(false);
+            x.push(vec![4.0 + offset, 4.5 - offset]);
+            y.push(true);
+        }
+        (x, y)
+    }
+
+    #[test]
+    fn classifiers_separate_clusters()
+    {
+        let (x, y) = clusters();
+        for name in CLASSIFIERS
+        {
+            let mut model = by_name(name, 1);
+            model.fit(&x, &y);
+            assert!(model.score(&[0.2, 0.2]) < 0.5, "{}", name);
+            assert!(model.score(&[4.2, 4.2]) > 0.5, "{}", name);
+            let score = model.score(&[2.0, 2.0]);
+            assert!((0.0 ..= 1.0).contains(&score), "{}", name);
+        }
+    }
+
+    #[test]
+    fn knn_scores_fraction_of_neighbours()
+    {
+        let x = vec![vec![0.0], vec![1.0], vec![2.0], vec![10.0]];
+        let y = vec![true, false, true, true];
+        let mut model = Knn::new(3);
+        model.fit(&x, &y);
+        assert_eq!(model.score(&[0.9]), 2.0 / 3.0);
+
+        // k larger than the training set uses every sample
+        let mut model = Knn::new(10);
+        model.fit(&x, &y);
+        assert_eq!(model.score(&[0.0]), 0.75);
+    }
+
+    #[test]
+    fn forest_leaf_scores_pure_sets()
+    {
+        let x = vec![vec![0.0], vec![1.0]];
+        let mut model = RandomForest::new(5, 4, 1, 0);
+        model.fit(&x, &[true, true]);
+        assert_eq!(model.score(&[0.5]), 1.0);
+    }
+
+    #[test]
+    fn logistic_regression_is_calibrated_on_balanced_noise()
+    {
+        // Identical samples with both labels leave the score at one half
+        let x = vec![vec![1.0], vec![1.0]];
+        let mut model = LogisticRegression::new(100, 0.1, 0.0);
+        model.fit(&x, &[true, false]);
+        assert!((model.score(&[1.0]) - 0.5).abs() < 1e-9);
+    }
+
+    #[test]
+    #[should_panic(expected = "Unknown classifier")]
+    fn unknown_classifier()
+    {
+        by_name("svm", 0);
+    }
+}
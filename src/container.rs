@@ -0,0 +1,423 @@
+use crate::npy::write_npy;
+
+use argparse::{ArgumentParser, Store};
+use flate2::{Compression, Crc};
+use flate2::read::DeflateDecoder;
+use flate2::write::DeflateEncoder;
+use std::collections::HashSet;
+use std::fs;
+use std::fs::File;
+use std::io;
+use std::io::{BufReader, BufWriter, Read, Seek, SeekFrom, Write};
+
+// Layout of a container file, with every number little endian:
+//   header   magic "AFFINITY", version (u32), entry count (u32), index offset (u64)
+//   payloads the deflate compressed samples of every entry, one after another
+//   index    for each entry: image, variant, split and label (each a u16 length and UTF-8 bytes),
+//            width (u32), height (u32), channels (u8), payload offset (u64), compressed length (u64),
+//            sample count (u64) and CRC-32 of the samples (u32)
+// The index sits at the end so entries can be streamed in, and is read whole on opening for random access
+const MAGIC: &[u8; 8] = b"AFFINITY";
+const VERSION: u32 = 1;
+const HEADER_SIZE: u64 = 24;
+
+// Where an image of one variant is stored and how to decode it
+#[derive(Clone, Debug)]
+pub struct Entry
+{
+    pub image: String,
+    pub variant: String,
+    pub split: String,
+    pub label: String,
+    pub width: u32,
+    pub height: u32,
+    pub channels: u8,
+    pub offset: u64,
+    pub compressed: u64,
+    pub length: u64,
+    pub crc: u32,
+}
+
+// CRC-32 of the samples of an entry, the checksum zip archives use
+fn crc32(data: &[u8]) -> u32
+{
+    let mut crc = Crc::new();
+    crc.update(data);
+    crc.sum()
+}
+
+fn write_string(out: &mut Vec<u8>, value: &str)
+{
+    out.extend_from_slice(&(value.len() as u16).to_le_bytes());
+    out.extend_from_slice(value.as_bytes());
+}
+
+fn read_bytes<R: Read>(input: &mut R, count: usize) -> Vec<u8>
+{
+    let mut bytes = vec![0; count];
+    input.read_exact(&mut bytes).expect("Container index is truncated");
+    bytes
+}
+
+fn read_u16<R: Read>(input: &mut R) -> u16
+{
+    let bytes = read_bytes(input, 2);
+    u16::from_le_bytes([bytes [0], bytes [1]])
+}
+
+fn read_u32<R: Read>(input: &mut R) -> u32
+{
+    let bytes = read_bytes(input, 4);
+    u32::from_le_bytes([bytes [0], bytes [1], bytes [2], bytes [3]])
+}
+
+fn read_u64<R: Read>(input: &mut R) -> u64
+{
+    let bytes = read_bytes(input, 8);
+    u64::from_le_bytes([bytes [0], bytes [1], bytes [2], bytes [3], bytes [4], bytes [5], bytes [6], bytes [7]])
+}
+
+fn read_string<R: Read>(input: &mut R) -> String
+{
+    let length = read_u16(input) as usize;
+    String::from_utf8(read_bytes(input, length)).expect("Container index holds invalid text")
+}
+
+// Writes images to a container, compressing each as it is added
+pub struct ContainerWriter
+{
+    file: BufWriter<File>,
+    offset: u64,
+    entries: Vec<Entry>,
+    keys: HashSet<(String, String)>,
+}
+
+impl ContainerWriter
+{
+    pub fn create(path: &str) -> ContainerWriter
+    {
+        let mut file = BufWriter::new(File::create(path).unwrap_or_else(| _ | panic!("Failed to create {}", path)));
+
+        // The entry count and index offset are filled in by finish
+        file.write_all(MAGIC).unwrap();
+        file.write_all(&VERSION.to_le_bytes()).unwrap();
+        file.write_all(&[0; 12]).unwrap();
+        ContainerWriter { file, offset: HEADER_SIZE, entries: Vec::new(), keys: HashSet::new() }
+    }
+
+    // Adds the interleaved samples of an image with the given width, height and channel count
+    // Panics if the same image and variant was already added
+    pub fn add(&mut self, image: &str, variant: &str, split: &str, label: &str, shape: (u32, u32, u8), data: &[u8])
+    {
+        let (width, height, channels) = shape;
+        if !self.keys.insert((image.to_owned(), variant.to_owned()))
+        {
+            panic!("Container already holds {} of {}", variant, image);
+        }
+        let mut encoder = DeflateEncoder::new(Vec::new(), Compression::default());
+        encoder.write_all(data).unwrap();
+        let payload = encoder.finish().unwrap();
+        self.file.write_all(&payload).unwrap();
+        self.entries.push(Entry
+        {
+            image: image.to_owned(),
+            variant: variant.to_owned(),
+            split: split.to_owned(),
+            label: label.to_owned(),
+            width,
+            height,
+            channels,
+            offset: self.offset,
+            compressed: payload.len() as u64,
+            length: data.len() as u64,
+            crc: crc32(data),
+        });
+        self.offset += payload.len() as u64;
+    }
+
+    // Writes the index and completes the header
+    pub fn finish(mut self)
+    {
+        let mut index = Vec::new();
+        for entry in &self.entries
+        {
+            write_string(&mut index, &entry.image);
+            write_string(&mut index, &entry.variant);
+            write_string(&mut index, &entry.split);
+            write_string(&mut index, &entry.label);
+            index.extend_from_slice(&entry.width.to_le_bytes());
+            index.extend_from_slice(&entry.height.to_le_bytes());
+            index.push(entry.channels);
+            index.extend_from_slice(&entry.offset.to_le_bytes());
+            index.extend_from_slice(&entry.compressed.to_le_bytes());
+            index.extend_from_slice(&entry.length.to_le_bytes());
+            index.extend_from_slice(&entry.crc.to_le_bytes());
+        }
+        self.file.write_all(&index).unwrap();
+        self.file.seek(SeekFrom::Start(MAGIC.len() as u64 + 4)).unwrap();
+        self.file.write_all(&(self.entries.len() as u32).to_le_bytes()).unwrap();
+        self.file.write_all(&self.offset.to_le_bytes()).unwrap();
+        self.file.flush().unwrap();
+    }
+}
+
+// Reads entries from a container by image and variant
+pub struct Container
+{
+    file: BufReader<File>,
+    entries: Vec<Entry>,
+}
+
+impl Container
+{
+    pub fn open(path: &str) -> Container
+    {
+        let mut file = BufReader::new(File::open(path).unwrap_or_else(| _ | panic!("Failed to open {}", path)));
+        if &read_bytes(&mut file, MAGIC.len()) [..] != MAGIC
+        {
+            panic!("{} is not a container", path);
+        }
+        let version = read_u32(&mut file);
+        if version != VERSION
+        {
+            panic!("Unsupported container version {}", version);
+        }
+        let count = read_u32(&mut file) as usize;
+        let index = read_u64(&mut file);
+        file.seek(SeekFrom::Start(index)).unwrap();
+        let entries = (0 .. count).map(
+            | _ | Entry
+            {
+                image: read_string(&mut file),
+                variant: read_string(&mut file),
+                split: read_string(&mut file),
+                label: read_string(&mut file),
+                width: read_u32(&mut file),
+                height: read_u32(&mut file),
+                channels: read_bytes(&mut file, 1) [0],
+                offset: read_u64(&mut file),
+                compressed: read_u64(&mut file),
+                length: read_u64(&mut file),
+                crc: read_u32(&mut file),
+            }
+        ).collect();
+        Container { file, entries }
+    }
+
+    pub fn entries(&self) -> &[Entry]
+    {
+        &self.entries
+    }
+
+    pub fn find(&self, image: &str, variant: &str) -> Option<&Entry>
+    {
+        self.entries.iter().find(| entry | entry.image == image && entry.variant == variant)
+    }
+
+    // Reads and checks the interleaved samples of an entry
+    pub fn read(&mut self, entry: &Entry) -> Vec<u8>
+    {
+        self.file.seek(SeekFrom::Start(entry.offset)).unwrap();
+        let mut data = Vec::with_capacity(entry.length as usize);
+        let decoded = DeflateDecoder::new((&mut self.file).take(entry.compressed)).read_to_end(&mut data);
+        if decoded.is_err() || data.len() as u64 != entry.length || crc32(&data) != entry.crc
+        {
+            panic!("Entry {} of {} is corrupt", entry.variant, entry.image);
+        }
+        data
+    }
+}
+
+// Lists the entries of a container or extracts them to image files
+pub fn run(args: Vec<String>)
+{
+    let mut action = "".to_owned();
+    let mut path = "".to_owned();
+    let mut output = "extracted/".to_owned();
+    let mut image = "".to_owned();
+    let mut variant = "".to_owned();
+    {
+        let mut ap = ArgumentParser::new();
+        ap.set_description("List or extract the images stored in a container written with --container");
+        ap.refer(&mut action)
+            .add_argument("action", Store,
+            "Either list or extract")
+            .required();
+        ap.refer(&mut path)
+            .add_argument("container", Store,
+            "Path of the container")
+            .required();
+        ap.refer(&mut output)
+            .add_option(&["-o", "--output"], Store,
+            "Set the directory entries are extracted to as variant/[split/][category/]image (extracted/ by default)");
+        ap.refer(&mut image)
+            .add_option(&["--image"], Store,
+            "Only use entries of this image");
+        ap.refer(&mut variant)
+            .add_option(&["--variant"], Store,
+            "Only use entries of this variant");
+        if let Err(code) = ap.parse(args, &mut io::stdout(), &mut io::stderr())
+        {
+            std::process::exit(code);
+        }
+    }
+    if !output.ends_with('/')
+    {
+        output.push('/');
+    }
+
+    let mut container = Container::open(&path);
+    let entries: Vec<Entry> = if !image.is_empty() && !variant.is_empty()
+    {
+        container.find(&image, &variant).into_iter().cloned().collect()
+    }
+    else
+    {
+        container.entries().iter().filter(| entry | (image.is_empty() || entry.image == image) && (variant.is_empty() || entry.variant == variant)).cloned().collect()
+    };
+    match action.as_str()
+    {
+        "list" =>
+        {
+            println!("| Image | Variant | Split | Category | Size | Compressed |");
+            println!("|---|---|---|---|---|---|");
+            for entry in &entries
+            {
+                println!("| {} | {} | {} | {} | {}x{}x{} | {:.1}% |", entry.image, entry.variant, entry.split, entry.label, entry.width, entry.height, entry.channels,
+                    entry.compressed as f64 / entry.length.max(1) as f64 * 100.0);
+            }
+            println!("\n{} of {} entries", entries.len(), container.entries().len());
+        }
+        "extract" =>
+        {
+            for entry in &entries
+            {
+                let dir = [output.trim_end_matches('/'), &entry.variant, &entry.split, &entry.label].iter().filter(| part | !part.is_empty()).cloned().collect::<Vec<&str>>().join("/") + "/";
+                fs::create_dir_all(&dir).unwrap();
+                let data = container.read(entry);
+
+                // Images with other than 1 or 3 channels, such as channel stacks, are written as NumPy arrays
+                let path = dir + &entry.image;
+                match entry.channels
+                {
+                    1 => image::GrayImage::from_raw(entry.width, entry.height, data).unwrap().save(&path).unwrap(),
+                    3 => image::RgbImage::from_raw(entry.width, entry.height, data).unwrap().save(&path).unwrap(),
+                    _ =>
+                    {
+                        let path = std::path::Path::new(&path).with_extension("npy");
+                        write_npy(path.to_str().unwrap(), &[entry.height as usize, entry.width as usize, entry.channels as usize], &data).unwrap();
+                    }
+                }
+            }
+            println!("Extracted {} entries to {}", entries.len(), output);
+        }
+        _ => panic!("Unknown action {} (expected list or extract)", action),
+    }
+}
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+
+    // Gets a scratch path that no other test writes to
+    fn scratch(name: &str) -> String
+    {
+        std::env::temp_dir().join(format!("image_affinity_container_{}_{}", std::process::id(), name)).to_string_lossy().into_owned()
+    }
+
+    // Samples that deflate cannot shrink, so payloads are stored as they are
+    fn noise(length: usize, seed: u32) -> Vec<u8>
+    {
+        let mut state = seed;
+        (0 .. length).map(| _ | { state ^= state << 13; state ^= state >> 17; state ^= state << 5; (state >> 24) as u8 }).collect()
+    }
+
+    // Writes a container of three images with two variants each, returning the samples of every entry
+    fn write(path: &str) -> Vec<(String, String, Vec<u8>)>
+    {
+        let mut entries = Vec::new();
+        let mut writer = ContainerWriter::create(path);
+        for (i, image) in ["a.bmp", "b.bmp", "c.bmp"].iter().enumerate()
+        {
+            for (j, variant) in ["base", "output"].iter().enumerate()
+            {
+                let data = noise(4 * 3 * 3, (i * 2 + j + 1) as u32);
+                writer.add(image, variant, "training", if i == 0 { "Normal" } else { "Abnormal" }, (4, 3, 3), &data);
+                entries.push((image.to_string(), variant.to_string(), data));
+            }
+        }
+        writer.finish();
+        entries
+    }
+
+    #[test]
+    fn round_trips_every_entry()
+    {
+        let path = scratch("round_trip");
+        let written = write(&path);
+        let mut container = Container::open(&path);
+        let entries = container.entries().to_vec();
+        assert_eq!(entries.len(), written.len());
+        for (entry, (image, variant, data)) in entries.iter().zip(&written)
+        {
+            assert_eq!((&entry.image, &entry.variant), (image, variant));
+            assert_eq!((entry.width, entry.height, entry.channels, entry.split.as_str()), (4, 3, 3, "training"));
+            assert_eq!(&container.read(entry), data);
+        }
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn finds_one_entry()
+    {
+        let path = scratch("find");
+        let written = write(&path);
+        let mut container = Container::open(&path);
+        let entry = container.find("b.bmp", "output").unwrap().clone();
+        assert_eq!(entry.label, "Abnormal");
+        assert_eq!(container.read(&entry), written [3].2);
+        assert!(container.find("b.bmp", "missing").is_none());
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    #[should_panic(expected = "Container index is truncated")]
+    fn rejects_truncated_files()
+    {
+        let path = scratch("truncated");
+        write(&path);
+        let bytes = fs::read(&path).unwrap();
+        fs::write(&path, &bytes [.. bytes.len() - 10]).unwrap();
+        Container::open(&path);
+    }
+
+    #[test]
+    #[should_panic(expected = "is not a container")]
+    fn rejects_other_files()
+    {
+        let path = scratch("magic");
+        write(&path);
+        let mut bytes = fs::read(&path).unwrap();
+        bytes [0] = b'X';
+        fs::write(&path, bytes).unwrap();
+        Container::open(&path);
+    }
+
+    #[test]
+    #[should_panic(expected = "Entry output of a.bmp is corrupt")]
+    fn rejects_corrupt_payloads()
+    {
+        let path = scratch("corrupt");
+        write(&path);
+        let mut container = Container::open(&path);
+        let entry = container.find("a.bmp", "output").unwrap().clone();
+
+        // Flips a sample in the middle of the stored block so the data still inflates but fails its CRC
+        let mut bytes = fs::read(&path).unwrap();
+        bytes [(entry.offset + entry.compressed / 2) as usize] ^= 0xff;
+        fs::write(&path, bytes).unwrap();
+        container = Container::open(&path);
+        container.read(&entry);
+    }
+}
@@ -0,0 +1,748 @@
+use flate2::read::DeflateDecoder;
+use std::collections::HashMap;
+use std::io::Read;
+
+// Transfer syntaxes the reader can decode
+const IMPLICIT_LITTLE: &str = "1.2.840.10008.1.2";
+const EXPLICIT_LITTLE: &str = "1.2.840.10008.1.2.1";
+const DEFLATED_LITTLE: &str = "1.2.840.10008.1.2.1.99";
+const EXPLICIT_BIG: &str = "1.2.840.10008.1.2.2";
+const RLE_LOSSLESS: &str = "1.2.840.10008.1.2.5";
+
+// Names of the supported transfer syntaxes, listed when a file uses another one
+const SUPPORTED: [&str; 5] = ["implicit VR little endian", "explicit VR little endian", "deflated explicit VR little endian", "explicit VR big endian", "RLE lossless"];
+
+// JPEG and JPEG 2000 compressed files are not decoded and have to be converted to one of the supported syntaxes first,
+// for example with dcmdjpeg or gdcmconv --raw, so they are named in the error rather than reported by UID alone
+const COMPRESSED: [(&str, &str); 7] = [
+    ("1.2.840.10008.1.2.4.50", "JPEG baseline"),
+    ("1.2.840.10008.1.2.4.51", "JPEG extended"),
+    ("1.2.840.10008.1.2.4.57", "JPEG lossless"),
+    ("1.2.840.10008.1.2.4.70", "JPEG lossless first order prediction"),
+    ("1.2.840.10008.1.2.4.80", "JPEG-LS lossless"),
+    ("1.2.840.10008.1.2.4.90", "JPEG 2000 lossless"),
+    ("1.2.840.10008.1.2.4.91", "JPEG 2000"),
+];
+
+// Tags of the elements the reader uses, as (group, element)
+const TRANSFER_SYNTAX: (u16, u16) = (0x0002, 0x0010);
+const SAMPLES_PER_PIXEL: (u16, u16) = (0x0028, 0x0002);
+const PHOTOMETRIC: (u16, u16) = (0x0028, 0x0004);
+const PLANAR_CONFIGURATION: (u16, u16) = (0x0028, 0x0006);
+const ROWS: (u16, u16) = (0x0028, 0x0010);
+const COLUMNS: (u16, u16) = (0x0028, 0x0011);
+const BITS_ALLOCATED: (u16, u16) = (0x0028, 0x0100);
+const BITS_STORED: (u16, u16) = (0x0028, 0x0101);
+const HIGH_BIT: (u16, u16) = (0x0028, 0x0102);
+const PIXEL_REPRESENTATION: (u16, u16) = (0x0028, 0x0103);
+const WINDOW_CENTER: (u16, u16) = (0x0028, 0x1050);
+const WINDOW_WIDTH: (u16, u16) = (0x0028, 0x1051);
+const RESCALE_INTERCEPT: (u16, u16) = (0x0028, 0x1052);
+const RESCALE_SLOPE: (u16, u16) = (0x0028, 0x1053);
+const VOI_LUT_FUNCTION: (u16, u16) = (0x0028, 0x1056);
+const LUT_DESCRIPTOR: (u16, u16) = (0x0028, 0x3002);
+const LUT_DATA: (u16, u16) = (0x0028, 0x3006);
+const VOI_LUT_SEQUENCE: (u16, u16) = (0x0028, 0x3010);
+const PIXEL_DATA: (u16, u16) = (0x7fe0, 0x0010);
+const ITEM: (u16, u16) = (0xfffe, 0xe000);
+const ITEM_END: (u16, u16) = (0xfffe, 0xe00d);
+const SEQUENCE_END: (u16, u16) = (0xfffe, 0xe0dd);
+const UNDEFINED: u32 = 0xffffffff;
+
+// Value representations whose explicit length takes 4 bytes after 2 reserved ones
+const LONG_VRS: [&[u8; 2]; 13] = [b"OB", b"OD", b"OF", b"OL", b"OV", b"OW", b"SQ", b"SV", b"UC", b"UN", b"UR", b"UT", b"UV"];
+
+// How stored values are mapped to the 8 bit gray levels the filters work on
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Voi
+{
+    // Stretch the full range of values in the image
+    Range,
+    // Use the first window center and width in the file, or the full range without one
+    Window,
+    // Use the VOI LUT in the file, or the window without one
+    Lut,
+}
+
+impl Voi
+{
+    pub fn from_name(name: &str) -> Voi
+    {
+        match name
+        {
+            "range" => Voi::Range,
+            "window" => Voi::Window,
+            "lut" => Voi::Lut,
+            _ => panic!("Unknown VOI mode {} (expected range, window or lut)", name),
+        }
+    }
+}
+
+// Value of a data element
+enum Value
+{
+    Bytes(Vec<u8>),
+    Items(Vec<Dataset>),
+    // Encapsulated pixel data, starting with the basic offset table
+    Fragments(Vec<Vec<u8>>),
+}
+
+// Data elements by tag, along with the byte order their values were stored in
+struct Dataset
+{
+    elements: HashMap<(u16, u16), Value>,
+    big_endian: bool,
+}
+
+impl Dataset
+{
+    fn bytes(&self, tag: (u16, u16)) -> Option<&[u8]>
+    {
+        match self.elements.get(&tag)
+        {
+            Some(Value::Bytes(bytes)) => Some(bytes),
+            _ => None,
+        }
+    }
+
+    // Reads the values of a US, SS or OW element as 16 bit words
+    fn words(&self, tag: (u16, u16)) -> Vec<u16>
+    {
+        self.bytes(tag).map(| bytes | bytes.chunks_exact(2).map(| pair | if self.big_endian { u16::from_be_bytes([pair [0], pair [1]]) } else { u16::from_le_bytes([pair [0], pair [1]]) }).collect()).unwrap_or_default()
+    }
+
+    fn word(&self, tag: (u16, u16)) -> Option<u16>
+    {
+        self.words(tag).first().cloned()
+    }
+
+    // Reads a text element with its padding removed
+    fn text(&self, tag: (u16, u16)) -> Option<String>
+    {
+        self.bytes(tag).map(| bytes | String::from_utf8_lossy(bytes).trim_matches(| c: char | c == ' ' || c == '\0').to_owned())
+    }
+
+    // Reads the values of a decimal string element, which are separated by backslashes
+    fn decimals(&self, tag: (u16, u16)) -> Vec<f64>
+    {
+        self.text(tag).map(| text | text.split('\\').filter_map(| value | value.trim().parse::<f64>().ok()).collect()).unwrap_or_default()
+    }
+}
+
+// Reads data elements in one transfer syntax
+struct Reader<'a>
+{
+    data: &'a [u8],
+    position: usize,
+    explicit: bool,
+    big_endian: bool,
+}
+
+impl<'a> Reader<'a>
+{
+    fn take(&mut self, count: usize) -> &'a [u8]
+    {
+        if self.position + count > self.data.len()
+        {
+            panic!("DICOM data ends inside an element");
+        }
+        let bytes = &self.data [self.position .. self.position + count];
+        self.position += count;
+        bytes
+    }
+
+    fn u16(&mut self) -> u16
+    {
+        let bytes = self.take(2);
+        if self.big_endian { u16::from_be_bytes([bytes [0], bytes [1]]) } else { u16::from_le_bytes([bytes [0], bytes [1]]) }
+    }
+
+    fn u32(&mut self) -> u32
+    {
+        let bytes = self.take(4);
+        let bytes = [bytes [0], bytes [1], bytes [2], bytes [3]];
+        if self.big_endian { u32::from_be_bytes(bytes) } else { u32::from_le_bytes(bytes) }
+    }
+
+    fn tag(&mut self) -> (u16, u16)
+    {
+        (self.u16(), self.u16())
+    }
+
+    // Reads the elements up to the end position, or up to an item delimiter when there is none
+    fn dataset(&mut self, end: Option<usize>) -> Dataset
+    {
+        let mut elements = HashMap::new();
+        while self.position < end.unwrap_or(self.data.len())
+        {
+            let tag = self.tag();
+            if tag == ITEM_END
+            {
+                self.u32();
+                break;
+            }
+
+            // Without explicit value representations sequences are only known by their tags or undefined lengths
+            let (sequence, length) = if self.explicit
+            {
+                let vr = self.take(2);
+                let long = LONG_VRS.iter().any(| long | &long [..] == vr);
+                let length = if long { self.take(2); self.u32() } else { self.u16() as u32 };
+                (vr == b"SQ" || (vr == b"UN" && length == UNDEFINED), length)
+            }
+            else
+            {
+                let length = self.u32();
+                (tag == VOI_LUT_SEQUENCE || (length == UNDEFINED && tag != PIXEL_DATA), length)
+            };
+
+            let value = if tag == PIXEL_DATA && length == UNDEFINED
+            {
+                Value::Fragments(self.fragments())
+            }
+            else if sequence
+            {
+                Value::Items(self.items(length))
+            }
+            else
+            {
+                Value::Bytes(self.take(length as usize).to_vec())
+            };
+            elements.insert(tag, value);
+        }
+        Dataset { elements, big_endian: self.big_endian }
+    }
+
+    // Reads the items of a sequence with the given length, which may be undefined
+    fn items(&mut self, length: u32) -> Vec<Dataset>
+    {
+        let end = if length == UNDEFINED { None } else { Some(self.position + length as usize) };
+        let mut items = Vec::new();
+        while end.is_none_or(| end | self.position < end)
+        {
+            let tag = self.tag();
+            let length = self.u32();
+            match tag
+            {
+                SEQUENCE_END => break,
+                ITEM => items.push(self.dataset(if length == UNDEFINED { None } else { Some(self.position + length as usize) })),
+                _ => panic!("Unexpected element ({:04x},{:04x}) in a DICOM sequence", tag.0, tag.1),
+            }
+        }
+        items
+    }
+
+    // Reads the fragments of encapsulated pixel data
+    fn fragments(&mut self) -> Vec<Vec<u8>>
+    {
+        let mut fragments = Vec::new();
+        loop
+        {
+            let tag = self.tag();
+            let length = self.u32();
+            match tag
+            {
+                SEQUENCE_END => return fragments,
+                ITEM => fragments.push(self.take(length as usize).to_vec()),
+                _ => panic!("Unexpected element ({:04x},{:04x}) in DICOM pixel data", tag.0, tag.1),
+            }
+        }
+    }
+}
+
+// Whether a file starts like a DICOM file, after its 128 byte preamble
+pub fn is_dicom(bytes: &[u8]) -> bool
+{
+    bytes.len() >= 132 && &bytes [128 .. 132] == b"DICM"
+}
+
+// Fails on a transfer syntax the reader cannot decode, naming it when it is a known compressed one
+fn unsupported(syntax: &str) -> !
+{
+    let name = COMPRESSED.iter().find(| (uid, _) | *uid == syntax).map(| (_, name) | format!(" ({})", name)).unwrap_or_default();
+    panic!("Unsupported DICOM transfer syntax {}{}, supported syntaxes are {}", syntax, name, SUPPORTED.join(", "));
+}
+
+// Parses the file meta information and the data set in its transfer syntax
+// Files without the preamble are read as implicit VR little endian data sets, as older ones were written
+fn parse(bytes: &[u8]) -> Dataset
+{
+    let (syntax, start) = if is_dicom(bytes)
+    {
+        // File meta information is always explicit VR little endian
+        let mut meta = Reader { data: bytes, position: 132, explicit: true, big_endian: false };
+        let mut syntax = IMPLICIT_LITTLE.to_owned();
+        while meta.position + 4 <= bytes.len() && u16::from_le_bytes([bytes [meta.position], bytes [meta.position + 1]]) == 0x0002
+        {
+            let start = meta.position;
+            let element = meta.dataset(Some(start + 1));
+            if let Some(value) = element.text(TRANSFER_SYNTAX)
+            {
+                syntax = value;
+            }
+        }
+        (syntax, meta.position)
+    }
+    else
+    {
+        (IMPLICIT_LITTLE.to_owned(), 0)
+    };
+
+    let inflated;
+    let (data, explicit, big_endian) = match syntax.as_str()
+    {
+        IMPLICIT_LITTLE => (&bytes [start ..], false, false),
+        EXPLICIT_LITTLE | RLE_LOSSLESS => (&bytes [start ..], true, false),
+        EXPLICIT_BIG => (&bytes [start ..], true, true),
+        DEFLATED_LITTLE =>
+        {
+            let mut data = Vec::new();
+            DeflateDecoder::new(&bytes [start ..]).read_to_end(&mut data).expect("Failed to inflate deflated DICOM data set");
+            inflated = data;
+            (&inflated [..], true, false)
+        },
+        _ => unsupported(&syntax),
+    };
+    let mut dataset = Reader { data, position: 0, explicit, big_endian }.dataset(None);
+    dataset.elements.insert(TRANSFER_SYNTAX, Value::Bytes(syntax.into_bytes()));
+    dataset
+}
+
+// Decodes a PackBits run length encoded segment
+fn unpack_bits(segment: &[u8], length: usize) -> Vec<u8>
+{
+    let mut out = Vec::with_capacity(length);
+    let mut i = 0;
+    while i < segment.len() && out.len() < length
+    {
+        let n = segment [i] as i8;
+        i += 1;
+        if n >= 0
+        {
+            let count = (n as usize + 1).min(segment.len() - i);
+            out.extend_from_slice(&segment [i .. i + count]);
+            i += count;
+        }
+        else if n != -128 && i < segment.len()
+        {
+            out.extend(std::iter::repeat_n(segment [i], (1 - n as isize) as usize));
+            i += 1;
+        }
+    }
+    out.resize(length, 0);
+    out
+}
+
+// Decodes the first frame of RLE lossless pixel data into native little endian samples, interleaved by pixel
+fn decode_rle(fragments: &[Vec<u8>], pixels: usize, samples: usize, bytes: usize) -> Vec<u8>
+{
+    // The first fragment is the offset table, and a frame may be split over the fragments after it
+    let offsets: Vec<usize> = fragments [0].chunks_exact(4).map(| offset | u32::from_le_bytes([offset [0], offset [1], offset [2], offset [3]]) as usize).collect();
+    let data: Vec<u8> = fragments [1 ..].iter().flat_map(| fragment | fragment.iter().cloned()).collect();
+    let frame = match offsets.get(1)
+    {
+        Some(end) => &data [.. *end],
+        None => &data [..],
+    };
+
+    let header: Vec<usize> = frame [.. 64].chunks_exact(4).map(| value | u32::from_le_bytes([value [0], value [1], value [2], value [3]]) as usize).collect();
+    let count = header [0];
+    if count != samples * bytes
+    {
+        panic!("RLE frame has {} segments but {} were expected", count, samples * bytes);
+    }
+
+    // Segments hold one byte of one sample for every pixel, most significant byte first
+    let mut out = vec![0; pixels * samples * bytes];
+    for segment in 0 .. count
+    {
+        let start = header [segment + 1];
+        let end = if segment + 1 < count { header [segment + 2] } else { frame.len() };
+        let decoded = unpack_bits(&frame [start .. end], pixels);
+        let (sample, byte) = (segment / bytes, bytes - 1 - segment % bytes);
+        for (pixel, value) in decoded.iter().enumerate()
+        {
+            out [(pixel * samples + sample) * bytes + byte] = *value;
+        }
+    }
+    out
+}
+
+// Maps a value through the window given by a center and width as the VOI LUT function describes
+fn window(value: f64, center: f64, width: f64, function: &str) -> f64
+{
+    match function
+    {
+        "SIGMOID" => 1.0 / (1.0 + (-4.0 * (value - center) / width).exp()),
+        "LINEAR_EXACT" => ((value - center) / width + 0.5).clamp(0.0, 1.0),
+        _ =>
+        {
+            if width <= 1.0
+            {
+                return if value > center - 0.5 { 1.0 } else { 0.0 };
+            }
+            ((value - (center - 0.5)) / (width - 1.0) + 0.5).clamp(0.0, 1.0)
+        }
+    }
+}
+
+// Reads a DICOM image, applying the rescale slope and intercept, the chosen VOI transform and the photometric interpretation
+pub fn read(bytes: &[u8], voi: Voi) -> image::DynamicImage
+{
+    let dataset = parse(bytes);
+    let rows = dataset.word(ROWS).expect("DICOM image has no rows") as u32;
+    let columns = dataset.word(COLUMNS).expect("DICOM image has no columns") as u32;
+    let samples = dataset.word(SAMPLES_PER_PIXEL).unwrap_or(1) as usize;
+    let allocated = dataset.word(BITS_ALLOCATED).unwrap_or(16) as usize;
+    let stored = dataset.word(BITS_STORED).unwrap_or(allocated as u16) as u32;
+    let high = dataset.word(HIGH_BIT).map(| high | high as u32).unwrap_or(stored.saturating_sub(1));
+    let signed = dataset.word(PIXEL_REPRESENTATION).unwrap_or(0) == 1;
+    let photometric = dataset.text(PHOTOMETRIC).unwrap_or_else(|| "MONOCHROME2".to_owned());
+    let pixels = (rows * columns) as usize;
+    if allocated != 8 && allocated != 16
+    {
+        panic!("Unsupported DICOM bits allocated {}", allocated);
+    }
+    if stored == 0 || stored > allocated as u32 || high + 1 < stored || high >= allocated as u32
+    {
+        panic!("Invalid DICOM bits stored {} with high bit {} in {} bits allocated", stored, high, allocated);
+    }
+    let bytes_per_sample = allocated / 8;
+
+    // Encapsulated data is decoded to native little endian samples so both cases are read the same way
+    let syntax = dataset.text(TRANSFER_SYNTAX).unwrap();
+    let (data, big_endian, planar) = match dataset.elements.get(&PIXEL_DATA)
+    {
+        Some(Value::Bytes(data)) => (data.clone(), dataset.big_endian, dataset.word(PLANAR_CONFIGURATION).unwrap_or(0) == 1),
+        Some(Value::Fragments(fragments)) if syntax == RLE_LOSSLESS => (decode_rle(fragments, pixels, samples, bytes_per_sample), false, false),
+        Some(Value::Fragments(_)) => unsupported(&syntax),
+        _ => panic!("DICOM file has no pixel data"),
+    };
+    if data.len() < pixels * samples * bytes_per_sample
+    {
+        panic!("DICOM pixel data is shorter than {}x{} pixels", columns, rows);
+    }
+
+    // Colour images only have their samples rearranged, since VOI transforms apply to grayscale
+    if samples == 3
+    {
+        if allocated != 8
+        {
+            panic!("Only 8 bit colour DICOM images are supported");
+        }
+        let index = | pixel: usize, sample: usize | if planar { sample * pixels + pixel } else { pixel * 3 + sample };
+        return image::DynamicImage::ImageRgb8(image::ImageBuffer::from_fn(columns, rows,
+            | x, y | { let pixel = (y * columns + x) as usize; image::Rgb([data [index(pixel, 0)], data [index(pixel, 1)], data [index(pixel, 2)]]) }));
+    }
+    if samples != 1
+    {
+        panic!("Unsupported DICOM samples per pixel {}", samples);
+    }
+
+    // Stored values sit in bits high - stored + 1 to high and are sign extended when signed
+    let shift = high + 1 - stored;
+    let stored_values: Vec<i64> = (0 .. pixels).map(
+        | pixel |
+        {
+            let raw = match bytes_per_sample
+            {
+                1 => data [pixel] as u32,
+                _ =>
+                {
+                    let pair = [data [pixel * 2], data [pixel * 2 + 1]];
+                    (if big_endian { u16::from_be_bytes(pair) } else { u16::from_le_bytes(pair) }) as u32
+                }
+            };
+            let value = (raw >> shift) & ((1u64 << stored) - 1) as u32;
+            if signed && value & (1 << (stored - 1)) != 0 { value as i64 - (1i64 << stored) } else { value as i64 }
+        }
+    ).collect();
+
+    // The modality transform gives real world values, which windows are defined on
+    let slope = dataset.decimals(RESCALE_SLOPE).first().cloned().unwrap_or(1.0);
+    let intercept = dataset.decimals(RESCALE_INTERCEPT).first().cloned().unwrap_or(0.0);
+    let values: Vec<f64> = stored_values.iter().map(| value | *value as f64 * slope + intercept).collect();
+
+    let lut = match (voi, dataset.elements.get(&VOI_LUT_SEQUENCE))
+    {
+        (Voi::Lut, Some(Value::Items(items))) => items.first(),
+        _ => None,
+    };
+    let centers = dataset.decimals(WINDOW_CENTER);
+    let widths = dataset.decimals(WINDOW_WIDTH);
+    let function = dataset.text(VOI_LUT_FUNCTION).unwrap_or_else(|| "LINEAR".to_owned());
+    let levels: Vec<f64> = if let Some(lut) = lut
+    {
+        // The descriptor gives the entry count (0 for 65536), the first value mapped and the bits per entry
+        let descriptor = lut.words(LUT_DESCRIPTOR);
+        if descriptor.len() < 3 || descriptor [2] == 0 || descriptor [2] > 16
+        {
+            panic!("Invalid DICOM VOI LUT descriptor {:?}", descriptor);
+        }
+        let entries = if descriptor [0] == 0 { 65536 } else { descriptor [0] as usize };
+        let first = if signed { descriptor [1] as i16 as f64 } else { descriptor [1] as f64 };
+        let bits = descriptor [2] as u32;
+        let table: Vec<u16> = match lut.bytes(LUT_DATA)
+        {
+            Some(data) if data.len() == entries && bits <= 8 => data.iter().map(| value | *value as u16).collect(),
+            _ => lut.words(LUT_DATA),
+        };
+        if table.is_empty()
+        {
+            panic!("DICOM VOI LUT has no data");
+        }
+        let max = ((1u64 << bits) - 1) as f64;
+        values.iter().map(| value | table [((value - first).max(0.0) as usize).min(table.len() - 1)] as f64 / max).collect()
+    }
+    else if voi != Voi::Range && !centers.is_empty() && !widths.is_empty()
+    {
+        values.iter().map(| value | window(*value, centers [0], widths [0], &function)).collect()
+    }
+    else
+    {
+        let min = values.iter().cloned().fold(f64::MAX, f64::min);
+        let max = values.iter().cloned().fold(f64::MIN, f64::max);
+        let range = if max > min { max - min } else { 1.0 };
+        values.iter().map(| value | (value - min) / range).collect()
+    };
+
+    // MONOCHROME1 shows the lowest values as white, so it is inverted to match the other inputs
+    let invert = photometric == "MONOCHROME1";
+    image::DynamicImage::ImageLuma8(image::ImageBuffer::from_fn(columns, rows,
+        | x, y |
+        {
+            let level = levels [(y * columns + x) as usize];
+            let level = if invert { 1.0 - level } else { level };
+            image::Luma([(level * 255.0).round() as u8])
+        }
+    ))
+}
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+
+    // Builds data elements in one transfer syntax
+    struct Writer
+    {
+        explicit: bool,
+        big_endian: bool,
+        bytes: Vec<u8>,
+    }
+
+    impl Writer
+    {
+        fn new(explicit: bool, big_endian: bool) -> Writer
+        {
+            Writer { explicit, big_endian, bytes: Vec::new() }
+        }
+
+        fn u16(&mut self, value: u16)
+        {
+            let bytes = if self.big_endian { value.to_be_bytes() } else { value.to_le_bytes() };
+            self.bytes.extend_from_slice(&bytes);
+        }
+
+        fn u32(&mut self, value: u32)
+        {
+            let bytes = if self.big_endian { value.to_be_bytes() } else { value.to_le_bytes() };
+            self.bytes.extend_from_slice(&bytes);
+        }
+
+        fn header(&mut self, tag: (u16, u16), vr: &[u8; 2], length: u32)
+        {
+            self.u16(tag.0);
+            self.u16(tag.1);
+            if !self.explicit
+            {
+                self.u32(length);
+            }
+            else if LONG_VRS.contains(&vr)
+            {
+                self.bytes.extend_from_slice(vr);
+                self.bytes.extend_from_slice(&[0, 0]);
+                self.u32(length);
+            }
+            else
+            {
+                self.bytes.extend_from_slice(vr);
+                self.u16(length as u16);
+            }
+        }
+
+        fn element(&mut self, tag: (u16, u16), vr: &[u8; 2], value: &[u8]) -> &mut Writer
+        {
+            self.header(tag, vr, value.len() as u32);
+            self.bytes.extend_from_slice(value);
+            self
+        }
+
+        // Text is padded with a space to an even length as DICOM requires
+        fn text(&mut self, tag: (u16, u16), vr: &[u8; 2], value: &str) -> &mut Writer
+        {
+            let mut value = value.as_bytes().to_vec();
+            if value.len() % 2 == 1
+            {
+                value.push(b' ');
+            }
+            self.element(tag, vr, &value)
+        }
+
+        fn words(&mut self, tag: (u16, u16), vr: &[u8; 2], values: &[u16]) -> &mut Writer
+        {
+            let bytes: Vec<u8> = values.iter().flat_map(| value | if self.big_endian { value.to_be_bytes() } else { value.to_le_bytes() }).collect();
+            self.element(tag, vr, &bytes)
+        }
+
+        // Writes a sequence of undefined length holding one item of undefined length
+        fn sequence(&mut self, tag: (u16, u16), item: &Writer) -> &mut Writer
+        {
+            self.header(tag, b"SQ", UNDEFINED);
+            self.u16(ITEM.0);
+            self.u16(ITEM.1);
+            self.u32(UNDEFINED);
+            self.bytes.extend_from_slice(&item.bytes);
+            for tag in [ITEM_END, SEQUENCE_END]
+            {
+                self.u16(tag.0);
+                self.u16(tag.1);
+                self.u32(0);
+            }
+            self
+        }
+
+        // Writes the image elements of a 16 bit grayscale image
+        fn image(&mut self, photometric: &str, columns: u16, rows: u16) -> &mut Writer
+        {
+            self.text(PHOTOMETRIC, b"CS", photometric)
+                .words(ROWS, b"US", &[rows])
+                .words(COLUMNS, b"US", &[columns])
+                .words(BITS_ALLOCATED, b"US", &[16])
+                .words(BITS_STORED, b"US", &[16])
+                .words(HIGH_BIT, b"US", &[15])
+                .words(PIXEL_REPRESENTATION, b"US", &[0]);
+            self
+        }
+
+        fn pixels(&mut self, values: &[u16]) -> &mut Writer
+        {
+            self.words(PIXEL_DATA, b"OW", values)
+        }
+    }
+
+    // Wraps a data set in a file with the preamble and file meta information naming its transfer syntax
+    fn file(syntax: &str, dataset: &Writer) -> Vec<u8>
+    {
+        let mut meta = Writer::new(true, false);
+        meta.text(TRANSFER_SYNTAX, b"UI", syntax);
+        let mut bytes = vec![0; 128];
+        bytes.extend_from_slice(b"DICM");
+        bytes.extend_from_slice(&meta.bytes);
+        bytes.extend_from_slice(&dataset.bytes);
+        bytes
+    }
+
+    fn gray(image: image::DynamicImage) -> Vec<u8>
+    {
+        image.to_luma().into_raw()
+    }
+
+    #[test]
+    fn reads_implicit_little_endian()
+    {
+        let mut dataset = Writer::new(false, false);
+        dataset.image("MONOCHROME2", 2, 2).pixels(&[0, 100, 200, 300]);
+        let image = read(&file(IMPLICIT_LITTLE, &dataset), Voi::Range);
+        assert_eq!(image::GenericImageView::dimensions(&image), (2, 2));
+        assert_eq!(gray(image), [0, 85, 170, 255]);
+
+        // Files without the preamble are read as implicit little endian data sets
+        assert_eq!(gray(read(&dataset.bytes, Voi::Range)), [0, 85, 170, 255]);
+    }
+
+    #[test]
+    fn reads_explicit_big_endian()
+    {
+        let mut dataset = Writer::new(true, true);
+        dataset.image("MONOCHROME2", 4, 1).pixels(&[0, 100, 200, 300]);
+        let image = read(&file(EXPLICIT_BIG, &dataset), Voi::Range);
+        assert_eq!(image::GenericImageView::dimensions(&image), (4, 1));
+        assert_eq!(gray(image), [0, 85, 170, 255]);
+    }
+
+    #[test]
+    fn inverts_monochrome1()
+    {
+        let mut dataset = Writer::new(true, false);
+        dataset.image("MONOCHROME1", 2, 2).pixels(&[0, 100, 200, 300]);
+        assert_eq!(gray(read(&file(EXPLICIT_LITTLE, &dataset), Voi::Range)), [255, 170, 85, 0]);
+    }
+
+    #[test]
+    fn windows_rescaled_values()
+    {
+        // Stored values 0, 50, 100 and 150 rescale to -100, 0, 100 and 200
+        let mut dataset = Writer::new(true, false);
+        dataset.image("MONOCHROME2", 2, 2)
+            .text(WINDOW_CENTER, b"DS", "50\\0")
+            .text(WINDOW_WIDTH, b"DS", "201\\1")
+            .text(RESCALE_INTERCEPT, b"DS", "-100")
+            .text(RESCALE_SLOPE, b"DS", "2")
+            .pixels(&[0, 50, 100, 150]);
+        let bytes = file(EXPLICIT_LITTLE, &dataset);
+        assert_eq!(gray(read(&bytes, Voi::Window)), [0, 64, 192, 255]);
+
+        // The range ignores the window, and the VOI LUT falls back to it when the file has none
+        assert_eq!(gray(read(&bytes, Voi::Range)), [0, 85, 170, 255]);
+        assert_eq!(gray(read(&bytes, Voi::Lut)), [0, 64, 192, 255]);
+    }
+
+    #[test]
+    fn maps_values_through_the_lut()
+    {
+        let mut lut = Writer::new(true, false);
+        lut.words(LUT_DESCRIPTOR, b"US", &[4, 1, 8]).words(LUT_DATA, b"OW", &[0, 255, 128, 64]);
+        let mut dataset = Writer::new(true, false);
+        dataset.image("MONOCHROME2", 2, 2).sequence(VOI_LUT_SEQUENCE, &lut).pixels(&[1, 2, 3, 4]);
+        let bytes = file(EXPLICIT_LITTLE, &dataset);
+        assert_eq!(gray(read(&bytes, Voi::Lut)), [0, 255, 128, 64]);
+
+        // Without a window the other modes stretch the range
+        assert_eq!(gray(read(&bytes, Voi::Window)), [0, 85, 170, 255]);
+    }
+
+    #[test]
+    #[should_panic(expected = "Unsupported DICOM transfer syntax 1.2.840.10008.1.2.4.90 (JPEG 2000 lossless), supported syntaxes are implicit VR little endian")]
+    fn names_unsupported_compression()
+    {
+        let mut dataset = Writer::new(true, false);
+        dataset.image("MONOCHROME2", 2, 2);
+        read(&file("1.2.840.10008.1.2.4.90", &dataset), Voi::Range);
+    }
+
+    #[test]
+    #[should_panic(expected = "Invalid DICOM bits stored 0")]
+    fn rejects_zero_bits_stored()
+    {
+        let mut dataset = Writer::new(true, false);
+        dataset.text(PHOTOMETRIC, b"CS", "MONOCHROME2")
+            .words(ROWS, b"US", &[1])
+            .words(COLUMNS, b"US", &[1])
+            .words(BITS_ALLOCATED, b"US", &[16])
+            .words(BITS_STORED, b"US", &[0])
+            .pixels(&[0]);
+        read(&file(EXPLICIT_LITTLE, &dataset), Voi::Range);
+    }
+
+    #[test]
+    #[should_panic(expected = "Invalid DICOM VOI LUT descriptor [4, 0]")]
+    fn rejects_short_lut_descriptor()
+    {
+        let mut lut = Writer::new(true, false);
+        lut.words(LUT_DESCRIPTOR, b"US", &[4, 0]).words(LUT_DATA, b"OW", &[0, 1, 2, 3]);
+        let mut dataset = Writer::new(true, false);
+        dataset.image("MONOCHROME2", 2, 2).sequence(VOI_LUT_SEQUENCE, &lut).pixels(&[0, 1, 2, 3]);
+        read(&file(EXPLICIT_LITTLE, &dataset), Voi::Lut);
+    }
+}
@@ -0,0 +1,472 @@
+use crate::classify::{self, CLASSIFIERS};
+use crate::features::GROUPS;
+use crate::stats::{self, format_interval, format_test};
+
+use argparse::{ArgumentParser, Store};
+use csv::{ReaderBuilder, Writer};
+use rand::SeedableRng;
+use rand::rngs::StdRng;
+use rand::seq::SliceRandom;
+use std::collections::{HashMap, HashSet};
+use std::io;
+
+// Labelled feature vectors read from a feature CSV
+pub struct Dataset
+{
+    pub images: Vec<String>,
+    // Input image each row was made from, which augmented and duplicated copies share with their original
+    pub sources: Vec<String>,
+    pub columns: Vec<String>,
+    pub x: Vec<Vec<f64>>,
+    pub y: Vec<bool>,
+}
+
+impl Dataset
+{
+    // Reads a feature CSV written by the features option, skipping unlabelled images
+    pub fn read(path: &str, positive: &str) -> Dataset
+    {
+        let mut reader = ReaderBuilder::new().from_path(path).expect("Feature file not found");
+        let header = reader.headers().unwrap().clone();
+        assert!(header.len() > 3 && &header [0] == "image" && &header [2] == "label", "Feature file must start with image, split and label columns");
+        let columns: Vec<String> = header.iter().skip(3).map(| column | column.to_owned()).collect();
+
+        let mut dataset = Dataset { images: Vec::new(), sources: Vec::new(), columns, x: Vec::new(), y: Vec::new() };
+        for record in reader.records()
+        {
+            let record = record.unwrap();
+            if record [2].is_empty()
+            {
+                continue;
+            }
+            dataset.images.push(record [0].to_owned());
+            dataset.sources.push(source(&record [0]));
+            dataset.y.push(&record [2] == positive);
+            dataset.x.push(record.iter().skip(3).map(| value | value.parse::<f64>().expect("Invalid feature value")).collect());
+        }
+        dataset
+    }
+
+    // Groups feature columns by the filter variant they came from, followed by all columns together
+    pub fn variants(&self) -> Vec<(String, Vec<usize>)>
+    {
+        let mut variants: Vec<(String, Vec<usize>)> = Vec::new();
+        for (index, column) in self.columns.iter().enumerate()
+        {
+            // Known groups may contain underscores themselves, anything else is grouped by its first word
+            let variant = match GROUPS.iter().find(| group | column.starts_with(&(group.to_string() + "_")))
+            {
+                Some(group) => group.to_string(),
+                None => column.split('_').next().unwrap().to_owned(),
+            };
+            match variants.iter_mut().find(| (name, _) | *name == variant)
+            {
+                Some((_, indices)) => indices.push(index),
+                None => variants.push((variant, vec![index])),
+            }
+        }
+        variants.push(("all".to_owned(), (0 .. self.columns.len()).collect()));
+        variants
+    }
+}
+
+// Names the input image an output was made from, removing the _aug<N> or _dup<N> suffix of augmented and duplicated copies
+pub fn source(image: &str) -> String
+{
+    let (stem, extension) = match image.rfind('.')
+    {
+        Some(index) => (&image [.. index], &image [index ..]),
+        None => (image, ""),
+    };
+    for marker in ["_aug", "_dup"]
+    {
+        if let Some(index) = stem.rfind(marker)
+        {
+            let copy = &stem [index + marker.len() ..];
+            if !copy.is_empty() && copy.chars().all(| c | c.is_ascii_digit())
+            {
+                return stem [.. index].to_owned() + extension;
+            }
+        }
+    }
+    image.to_owned()
+}
+
+// Assigns every sample to one of k folds, keeping the class balance of each fold close to the whole set
+pub fn stratified_folds(y: &[bool], k: usize, rng: &mut StdRng) -> Vec<usize>
+{
+    let mut folds = vec![0; y.len()];
+    for class in &[true, false]
+    {
+        let mut indices: Vec<usize> = (0 .. y.len()).filter(| i | y [*i] == *class).collect();
+        indices.shuffle(rng);
+        for (position, index) in indices.iter().enumerate()
+        {
+            folds [*index] = position % k;
+        }
+    }
+    folds
+}
+
+// Assigns every sample to one of k folds like stratified_folds, but with all samples of a source in the same fold,
+// so copies of an image are never trained on while the image itself is tested
+pub fn grouped_folds(sources: &[String], y: &[bool], k: usize, rng: &mut StdRng) -> Vec<usize>
+{
+    let mut groups: HashMap<&str, usize> = HashMap::new();
+    let mut labels = Vec::new();
+    let group_of: Vec<usize> = sources.iter().zip(y).map(
+        | (source, label) |
+        {
+            *groups.entry(source).or_insert_with(
+                ||
+                {
+                    labels.push(*label);
+                    labels.len() - 1
+                }
+            )
+        }
+    ).collect();
+    let folds = stratified_folds(&labels, k, rng);
+    group_of.iter().map(| group | folds [*group]).collect()
+}
+
+// Scales every feature to zero mean and unit variance using statistics of the training samples only
+fn standardize(train: &mut [Vec<f64>], test: &mut [Vec<f64>])
+{
+    let n = train.len() as f64;
+    for feature in 0 .. train [0].len()
+    {
+        let mean = train.iter().map(| sample | sample [feature]).sum::<f64>() / n;
+        let std = (train.iter().map(| sample | (sample [feature] - mean).powi(2)).sum::<f64>() / n).sqrt();
+        let std = if std > 0.0 { std } else { 1.0 };
+        train.iter_mut().chain(test.iter_mut()).for_each(| sample | sample [feature] = (sample [feature] - mean) / std);
+    }
+}
+
+// Area under the ROC curve from the Mann-Whitney rank statistic (ties count as half)
+pub fn auc(scores: &[f64], y: &[bool]) -> f64
+{
+    let mut wins = 0.0;
+    let mut pairs = 0.0;
+    for (score, _) in scores.iter().zip(y).filter(| (_, label) | **label)
+    {
+        for (other, _) in scores.iter().zip(y).filter(| (_, label) | !**label)
+        {
+            wins += if score > other { 1.0 } else if score == other { 0.5 } else { 0.0 };
+            pairs += 1.0;
+        }
+    }
+    if pairs > 0.0 { wins / pairs } else { 0.5 }
+}
+
+// Cross-validated results of one classifier on one filter variant
+pub struct Evaluation
+{
+    pub variant: String,
+    pub classifier: String,
+    pub fold_accuracies: Vec<f64>,
+    // Out-of-fold score of every image
+    pub scores: Vec<f64>,
+    pub accuracy: f64,
+    pub auc: f64,
+    pub sensitivity: f64,
+    pub specificity: f64,
+}
+
+// Runs k-fold cross-validation of a classifier on a subset of feature columns
+pub fn cross_validate(dataset: &Dataset, variant: &str, columns: &[usize], classifier: &str, folds: &[usize], k: usize, seed: u64) -> Evaluation
+{
+    let select = | sample: &Vec<f64> | -> Vec<f64> { columns.iter().map(| column | sample [*column]).collect() };
+    let mut scores = vec![0.0; dataset.y.len()];
+    let mut fold_accuracies = Vec::with_capacity(k);
+    for fold in 0 .. k
+    {
+        let train: Vec<usize> = (0 .. folds.len()).filter(| i | folds [*i] != fold).collect();
+        let test: Vec<usize> = (0 .. folds.len()).filter(| i | folds [*i] == fold).collect();
+        if test.is_empty()
+        {
+            continue;
+        }
+
+        let mut train_x: Vec<Vec<f64>> = train.iter().map(| i | select(&dataset.x [*i])).collect();
+        let mut test_x: Vec<Vec<f64>> = test.iter().map(| i | select(&dataset.x [*i])).collect();
+        let train_y: Vec<bool> = train.iter().map(| i | dataset.y [*i]).collect();
+        standardize(&mut train_x, &mut test_x);
+
+        let mut model = classify::by_name(classifier, seed + fold as u64);
+        model.fit(&train_x, &train_y);
+        let mut correct = 0;
+        for (index, sample) in test.iter().zip(&test_x)
+        {
+            scores [*index] = model.score(sample);
+            if (scores [*index] >= 0.5) == dataset.y [*index]
+            {
+                correct += 1;
+            }
+        }
+        fold_accuracies.push(correct as f64 / test.len() as f64);
+    }
+
+    // Pooled out-of-fold metrics
+    let mut counts = [0usize; 4];
+    for (score, label) in scores.iter().zip(&dataset.y)
+    {
+        let index = match (*score >= 0.5, *label)
+        {
+            (true, true) => 0,
+            (false, false) => 1,
+            (true, false) => 2,
+            (false, true) => 3,
+        };
+        counts [index] += 1;
+    }
+    let [tp, tn, fp, fneg] = counts;
+    let ratio = | a: usize, b: usize | if a + b > 0 { a as f64 / (a + b) as f64 } else { 0.0 };
+    Evaluation
+    {
+        variant: variant.to_owned(),
+        classifier: classifier.to_owned(),
+        auc: auc(&scores, &dataset.y),
+        fold_accuracies,
+        scores,
+        accuracy: ratio(tp + tn, fp + fneg),
+        sensitivity: ratio(tp, fneg),
+        specificity: ratio(tn, fp),
+    }
+}
+
+impl Evaluation
+{
+    // Whether each image was classified correctly
+    pub fn correct(&self, y: &[bool]) -> Vec<bool>
+    {
+        self.scores.iter().zip(y).map(| (score, label) | (*score >= 0.5) == *label).collect()
+    }
+}
+
+// Tests every result against the baseline variant's result for the same classifier
+// Images are paired for the bootstrap interval and McNemar's test, and folds for the t-test and Wilcoxon test
+fn print_comparisons(results: &[Evaluation], y: &[bool], baseline: &str, seed: u64)
+{
+    println!("\nCompared with {}:\n", baseline);
+    println!("| Variant | Classifier | Accuracy difference | Bootstrap 95% CI | McNemar | Paired t | Wilcoxon W |");
+    println!("|---|---|---|---|---|---|---|");
+    for result in results.iter().filter(| result | result.variant != baseline)
+    {
+        let base = match results.iter().find(| other | other.variant == baseline && other.classifier == result.classifier) { Some(base) => base, None => continue };
+        let correct = result.correct(y);
+        let base_correct = base.correct(y);
+        let differences: Vec<f64> = correct.iter().zip(&base_correct).map(| (a, b) | *a as u8 as f64 - *b as u8 as f64).collect();
+        let n = differences.len();
+        let interval = stats::bootstrap_interval(n, | indices | indices.iter().map(| i | differences [*i]).sum::<f64>() / n as f64, 1000, 0.95, seed);
+        let variant_wrong = differences.iter().filter(| d | **d < 0.0).count();
+        let baseline_wrong = differences.iter().filter(| d | **d > 0.0).count();
+        println!("| {} | {} | {:+.1}% | {} | {} | {} | {} |", result.variant, result.classifier, (result.accuracy - base.accuracy) * 100.0,
+                 format_interval(interval), format_test(&stats::mcnemar(variant_wrong, baseline_wrong)),
+                 format_test(&stats::paired_t_test(&result.fold_accuracies, &base.fold_accuracies)),
+                 format_test(&stats::wilcoxon(&result.fold_accuracies, &base.fold_accuracies)));
+    }
+}
+
+// Mean and sample standard deviation
+pub fn mean_std(values: &[f64]) -> (f64, f64)
+{
+    let n = values.len() as f64;
+    let mean = values.iter().sum::<f64>() / n;
+    let std = if values.len() > 1 { (values.iter().map(| v | (v - mean).powi(2)).sum::<f64>() / (n - 1.0)).sqrt() } else { 0.0 };
+    (mean, std)
+}
+
+// Trains lightweight classifiers on extracted feature vectors and reports cross-validated metrics per filter variant
+pub fn run(args: Vec<String>)
+{
+    let mut features = "".to_owned();
+    let mut positive = "Abnormal".to_owned();
+    let mut folds = 5;
+    let mut seed = 0u64;
+    let mut classifiers = CLASSIFIERS.join(",");
+    let mut output = "".to_owned();
+    let mut baseline = "".to_owned();
+    {
+        let mut ap = ArgumentParser::new();
+        ap.set_description("Compare filter variants by cross-validating classifiers on per-image feature vectors");
+        ap.refer(&mut features)
+            .add_argument("features", Store,
+            "Path of a feature CSV written with the features option")
+            .required();
+        ap.refer(&mut positive)
+            .add_option(&["-p", "--positive"], Store,
+            "Set the label treated as the positive class (Abnormal by default)");
+        ap.refer(&mut folds)
+            .add_option(&["-k", "--folds"], Store,
+            "Set the number of cross-validation folds (5 by default)");
+        ap.refer(&mut seed)
+            .add_option(&["--seed"], Store,
+            "Set the seed used for fold assignment and random forests (0 by default)");
+        ap.refer(&mut classifiers)
+            .add_option(&["-c", "--classifiers"], Store,
+            "Set a comma separated list of classifiers to evaluate (knn, logistic, forest; all by default)");
+        ap.refer(&mut output)
+            .add_option(&["-o", "--output"], Store,
+            "Set the path of a CSV file to also write the results to");
+        ap.refer(&mut baseline)
+            .add_option(&["-b", "--baseline"], Store,
+            "Set the variant every other variant is tested against (original when present by default)");
+        if let Err(code) = ap.parse(args, &mut io::stdout(), &mut io::stderr())
+        {
+            std::process::exit(code);
+        }
+    }
+
+    let dataset = Dataset::read(&features, &positive);
+    let positives = dataset.y.iter().filter(| label | **label).count();
+    if positives == 0 || positives == dataset.y.len()
+    {
+        panic!("Feature file needs both {} and other labels to evaluate", positive);
+    }
+    let sources = dataset.sources.iter().collect::<HashSet<_>>().len();
+    if folds < 2 || folds > sources
+    {
+        panic!("Number of folds must be between 2 and the number of labelled source images");
+    }
+    let classifiers: Vec<String> = classifiers.split(',').map(| name | name.trim().to_owned()).filter(| name | !name.is_empty()).collect();
+    println!("Evaluating {} images from {} sources ({} {}) with {}-fold cross-validation\n", dataset.y.len(), sources, positives, positive, folds);
+
+    // Every variant and classifier sees the same folds so results are paired, and copies stay in the fold of their source
+    let mut rng = StdRng::seed_from_u64(seed);
+    let assignment = grouped_folds(&dataset.sources, &dataset.y, folds, &mut rng);
+    let mut results = Vec::new();
+    for (variant, columns) in dataset.variants()
+    {
+        for classifier in &classifiers
+        {
+            results.push(cross_validate(&dataset, &variant, &columns, classifier, &assignment, folds, seed));
+        }
+    }
+
+    println!("| Variant | Classifier | Accuracy | 95% CI | Fold accuracy | AUC | Sensitivity | Specificity |");
+    println!("|---|---|---|---|---|---|---|---|");
+    for result in &results
+    {
+        let (mean, std) = mean_std(&result.fold_accuracies);
+        println!("| {} | {} | {:.3} | {} | {:.3} ± {:.3} | {:.3} | {:.3} | {:.3} |", result.variant, result.classifier, result.accuracy,
+                 format_interval(stats::wilson_interval(result.accuracy, dataset.y.len(), 0.95)), mean, std, result.auc,
+                 result.sensitivity, result.specificity);
+    }
+
+    // Significance tests are only run when there is a baseline to compare against
+    if baseline.is_empty() && results.iter().any(| result | result.variant == "original")
+    {
+        baseline = "original".to_owned();
+    }
+    if !baseline.is_empty()
+    {
+        if !results.iter().any(| result | result.variant == baseline)
+        {
+            panic!("Baseline variant {} was not evaluated", baseline);
+        }
+        print_comparisons(&results, &dataset.y, &baseline, seed);
+    }
+
+    if !output.is_empty()
+    {
+        let mut writer = Writer::from_path(&output).expect("Failed to create results file");
+        writer.write_record(["variant", "classifier", "accuracy", "accuracy_ci_low", "accuracy_ci_high", "fold_accuracy_mean", "fold_accuracy_std", "auc", "sensitivity", "specificity"]).unwrap();
+        for result in &results
+        {
+            let (mean, std) = mean_std(&result.fold_accuracies);
+            let (low, high) = stats::wilson_interval(result.accuracy, dataset.y.len(), 0.95);
+            writer.write_record(&[result.variant.clone(), result.classifier.clone(), result.accuracy.to_string(), low.to_string(),
+                                  high.to_string(), mean.to_string(), std.to_string(), result.auc.to_string(), result.sensitivity.to_string(),
+                                  result.specificity.to_string()]).unwrap();
+        }
+        writer.flush().unwrap();
+    }
+}
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+
+    #[test]
+    fn source_removes_copy_suffixes()
+    {
+        assert_eq!(source("mdb001.pgm"), "mdb001.pgm");
+        assert_eq!(source("mdb001_aug3.pgm"), "mdb001.pgm");
+        assert_eq!(source("mdb001_dup12.pgm"), "mdb001.pgm");
+        assert_eq!(source("mdb001_aug"), "mdb001_aug");
+        assert_eq!(source("scan_augment.png"), "scan_augment.png");
+        assert_eq!(source("scan_aug2"), "scan");
+    }
+
+    #[test]
+    fn stratified_folds_balance_classes()
+    {
+        let y: Vec<bool> = (0 .. 23).map(| i | i % 3 == 0).collect();
+        let mut rng = StdRng::seed_from_u64(7);
+        let folds = stratified_folds(&y, 4, &mut rng);
+        assert_eq!(folds.len(), y.len());
+        for fold in 0 .. 4
+        {
+            // 8 positives and 15 negatives spread over 4 folds differ by at most one per fold
+            let positives = (0 .. y.len()).filter(| i | folds [*i] == fold && y [*i]).count();
+            let negatives = (0 .. y.len()).filter(| i | folds [*i] == fold && !y [*i]).count();
+            assert_eq!(positives, 2);
+            assert!((3 ..= 4).contains(&negatives));
+        }
+    }
+
+    #[test]
+    fn grouped_folds_keep_copies_together()
+    {
+        let sources: Vec<String> = ["a", "a", "b", "c", "c", "c", "d", "e", "f", "f"].iter().map(| s | s.to_string()).collect();
+        let y = [true, true, false, true, true, true, false, false, true, true];
+        let mut rng = StdRng::seed_from_u64(3);
+        let folds = grouped_folds(&sources, &y, 3, &mut rng);
+        for i in 0 .. sources.len()
+        {
+            for j in 0 .. sources.len()
+            {
+                if sources [i] == sources [j]
+                {
+                    assert_eq!(folds [i], folds [j]);
+                }
+            }
+        }
+        assert!(folds.iter().all(| fold | *fold < 3));
+    }
+
+    #[test]
+    fn auc_known_values()
+    {
+        let y = [true, true, false, false];
+        assert_eq!(auc(&[0.9, 0.8, 0.2, 0.1], &y), 1.0);
+        assert_eq!(auc(&[0.1, 0.2, 0.8, 0.9], &y), 0.0);
+        assert_eq!(auc(&[0.5, 0.5, 0.5, 0.5], &y), 0.5);
+        // Positives beat negatives in 3 of the 4 pairs
+        assert_eq!(auc(&[0.9, 0.3, 0.4, 0.1], &y), 0.75);
+        // One tie among the 4 pairs counts as half
+        assert_eq!(auc(&[0.9, 0.4, 0.4, 0.1], &y), 0.875);
+        // Without both classes there are no pairs to rank
+        assert_eq!(auc(&[0.9, 0.1], &[true, true]), 0.5);
+    }
+
+    #[test]
+    fn cross_validate_separable_data()
+    {
+        let x: Vec<Vec<f64>> = (0 .. 20).map(| i | vec![if i % 2 == 0 { 1.0 } else { -1.0 } + i as f64 * 0.01, 0.5]).collect();
+        let y: Vec<bool> = (0 .. 20).map(| i | i % 2 == 0).collect();
+        let images: Vec<String> = (0 .. 20).map(| i | format!("{}.png", i)).collect();
+        let dataset = Dataset { sources: images.clone(), images, columns: vec!["a".to_owned(), "b".to_owned()], x, y };
+        let mut rng = StdRng::seed_from_u64(0);
+        let folds = grouped_folds(&dataset.sources, &dataset.y, 4, &mut rng);
+        for classifier in CLASSIFIERS
+        {
+            let result = cross_validate(&dataset, "all", &[0, 1], classifier, &folds, 4, 0);
+            assert_eq!(result.fold_accuracies.len(), 4);
+            assert_eq!(result.accuracy, 1.0, "{}", classifier);
+            assert_eq!(result.auc, 1.0, "{}", classifier);
+        }
+    }
+}
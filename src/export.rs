@@ -0,0 +1,182 @@
+use crate::container::ContainerWriter;
+use crate::npy::{self, write_npz};
+
+use std::collections::BTreeMap;
+use std::fs;
+use std::fs::File;
+use std::io::{BufWriter, Write};
+
+// Array of one variant being streamed to its .npy file as images arrive
+struct Variant
+{
+    file: BufWriter<File>,
+    shape: [usize; 3],
+    count: usize,
+}
+
+// Where filter outputs are saved to, passed to every analysis along with the mask
+// Besides writing each image, outputs can be collected into arrays, a container or both, along with the labels of the images
+// in the order they were processed
+pub struct Export
+{
+    dir: Option<String>,
+    container: Option<ContainerWriter>,
+    count: usize,
+    variants: BTreeMap<String, Variant>,
+    names: Vec<String>,
+    splits: Vec<String>,
+    labels: Vec<String>,
+}
+
+// Gets the variant an output was saved under, which is the top directory of its path, so every split and category of it
+// ends up in one array
+fn variant(path: &str) -> &str
+{
+    path.split('/').next().unwrap()
+}
+
+impl Export
+{
+    // Only writes the outputs
+    pub fn none() -> Export
+    {
+        Export::new(None, None, 0)
+    }
+
+    // Also exports every variant as an array of count images to a directory, into a container, or both
+    pub fn new(dir: Option<&str>, container: Option<&str>, count: usize) -> Export
+    {
+        if let Some(dir) = dir
+        {
+            fs::create_dir_all(dir).unwrap();
+        }
+        Export
+        {
+            dir: dir.map(| dir | dir.to_owned()),
+            container: container.map(ContainerWriter::create),
+            count,
+            variants: BTreeMap::new(),
+            names: Vec::new(),
+            splits: Vec::new(),
+            labels: Vec::new(),
+        }
+    }
+
+    // Starts the outputs of the next image, which are stored in the container under its name, split and category
+    pub fn image(&mut self, name: &str, split: &str, label: &str)
+    {
+        self.names.push(name.to_owned());
+        self.splits.push(split.to_owned());
+        self.labels.push(label.to_owned());
+    }
+
+    // Adds an image of interleaved samples to the array and container entries of the variant it was saved under
+    pub fn add(&mut self, path: &str, width: u32, height: u32, channels: usize, data: &[u8])
+    {
+        let name = variant(path).to_owned();
+        if let Some(container) = self.container.as_mut()
+        {
+            let image = self.names.last().expect("Outputs added before their image was started");
+            container.add(image, &name, self.splits.last().unwrap(), self.labels.last().unwrap(), (width, height, channels as u8), data);
+        }
+        let dir = match &self.dir
+        {
+            Some(dir) => dir.clone(),
+            None => return,
+        };
+        let shape = [height as usize, width as usize, channels];
+        let count = self.count;
+        let variant = self.variants.entry(name.clone()).or_insert_with(
+            ||
+            {
+                // The header is written up front since the number of images is known before any are processed
+                let mut file = BufWriter::new(File::create(format!("{}/{}.npy", dir, name)).unwrap());
+                file.write_all(&npy::header::<u8>(&[count, shape [0], shape [1], shape [2]])).unwrap();
+                Variant { file, shape, count: 0 }
+            }
+        );
+        if variant.shape != shape
+        {
+            panic!("Images of {} have different shapes ({:?} and {:?}), set a target size to export arrays", name, variant.shape, shape);
+        }
+        variant.file.write_all(data).unwrap();
+        variant.count += 1;
+    }
+
+    // Saves an image and adds it to the exported arrays and container
+    pub fn save<P>(&mut self, img: &image::ImageBuffer<P, Vec<u8>>, path: &str)
+        where P: image::Pixel<Subpixel = u8> + 'static
+    {
+        img.save(path).unwrap();
+        self.add(path, img.width(), img.height(), P::CHANNEL_COUNT as usize, img);
+    }
+
+    // Finishes the container and every array, writing the labels, names, splits and categories shared by all arrays to labels.npz
+    // Labels index the sorted categories, with -1 for images without one
+    pub fn finish(self)
+    {
+        if let Some(container) = self.container
+        {
+            container.finish();
+        }
+        let dir = match self.dir
+        {
+            Some(dir) => dir,
+            None => return,
+        };
+        for (name, mut variant) in self.variants
+        {
+            if variant.count != self.count
+            {
+                panic!("Exported {} images of {} but expected {}", variant.count, name, self.count);
+            }
+            variant.file.flush().unwrap();
+        }
+
+        let categories: Vec<String> = self.labels.iter().filter(| label | !label.is_empty()).cloned().collect::<std::collections::BTreeSet<String>>().into_iter().collect();
+        let labels: Vec<i64> = self.labels.iter().map(| label | categories.iter().position(| category | category == label).map(| index | index as i64).unwrap_or(-1)).collect();
+        write_npz(&format!("{}/labels.npz", dir), &[
+            ("labels", npy::to_bytes(&[labels.len()], &labels)),
+            ("filenames", npy::strings(&self.names)),
+            ("splits", npy::strings(&self.splits)),
+            ("categories", npy::strings(&categories)),
+        ]).unwrap();
+    }
+}
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+    use std::io::Read;
+
+    #[test]
+    fn streams_every_image_of_a_variant_into_one_array()
+    {
+        let dir = std::env::temp_dir().join(format!("image_affinity_export_{}", std::process::id())).to_string_lossy().into_owned();
+        let mut export = Export::new(Some(&dir), None, 3);
+        let images: Vec<Vec<u8>> = (0 .. 3u8).map(| i | (0 .. 2 * 3 * 3).map(| sample | sample * 10 + i).collect()).collect();
+        for (i, data) in images.iter().enumerate()
+        {
+            export.image(&format!("{}.bmp", i), "training", if i == 1 { "Normal" } else { "Abnormal" });
+            export.add(&format!("output_max_diff/training/x/{}.bmp", i), 3, 2, 3, data);
+            export.add(&format!("base/{}.bmp", i), 3, 2, 1, &data [.. 6]);
+        }
+        export.finish();
+
+        let bytes = fs::read(format!("{}/output_max_diff.npy", dir)).unwrap();
+        let header = npy::header::<u8>(&[3, 2, 3, 3]);
+        assert_eq!(&bytes [.. header.len()], &header [..]);
+        assert_eq!(&bytes [header.len() ..], &images.concat() [..]);
+        let bytes = fs::read(format!("{}/base.npy", dir)).unwrap();
+        let header = npy::header::<u8>(&[3, 2, 3, 1]);
+        assert_eq!(&bytes [header.len() ..], &images.iter().flat_map(| data | data [.. 6].to_vec()).collect::<Vec<u8>>() [..]);
+
+        // Labels index the sorted categories
+        let mut zip = zip::ZipArchive::new(File::open(format!("{}/labels.npz", dir)).unwrap()).unwrap();
+        let mut labels = Vec::new();
+        zip.by_name("labels.npy").unwrap().read_to_end(&mut labels).unwrap();
+        assert_eq!(labels, npy::to_bytes(&[3], &[0i64, 1, 0]));
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,145 @@
+use crate::filters;
+use crate::glcm;
+use crate::mask::{self, saturate_within};
+
+// Filter responses summarised in every feature vector
+pub const GROUPS: [&str; 4] = ["original", "affinity", "max_diff", "center_diff"];
+
+// Names of the intensity moments computed for each response
+pub const MOMENTS: [&str; 4] = ["mean", "std", "skewness", "kurtosis"];
+
+// Number of histogram bins computed for each response
+pub const BINS: usize = 16;
+
+// Lists the feature column names in the order extract returns them
+pub fn names() -> Vec<String>
+{
+    let mut names = Vec::new();
+    for group in GROUPS.iter()
+    {
+        names.extend(MOMENTS.iter().map(| moment | format!("{}_{}", group, moment)));
+        names.extend((0 .. BINS).map(| bin | format!("{}_hist_{:02}", group, bin)));
+    }
+    names.extend(glcm::FEATURES.iter().map(| feature | format!("glcm_{}", feature)));
+    names
+}
+
+// Computes the mean, standard deviation, skewness and excess kurtosis of pixel values
+// An empty set of values, such as an empty mask, gives all 0 rather than NaN
+fn moments(values: &[u8]) -> [f64; 4]
+{
+    if values.is_empty()
+    {
+        return [0.0; 4];
+    }
+    let n = values.len() as f64;
+    let mean = values.iter().map(| value | *value as f64).sum::<f64>() / n;
+    let mut m2 = 0.0;
+    let mut m3 = 0.0;
+    let mut m4 = 0.0;
+    for value in values
+    {
+        let d = *value as f64 - mean;
+        m2 += d * d;
+        m3 += d * d * d;
+        m4 += d * d * d * d;
+    }
+    m2 /= n;
+    m3 /= n;
+    m4 /= n;
+
+    // Constant responses have no spread, so their shape statistics are left at 0
+    if m2 > 0.0
+    {
+        [mean, m2.sqrt(), m3 / m2.powf(1.5), m4 / (m2 * m2) - 3.0]
+    }
+    else
+    {
+        [mean, 0.0, 0.0, 0.0]
+    }
+}
+
+// Computes a normalised histogram of pixel values spread over 0-255
+fn histogram(values: &[u8]) -> [f64; BINS]
+{
+    let mut bins = [0.0; BINS];
+    for value in values
+    {
+        bins [*value as usize * BINS / 256] += 1.0;
+    }
+    if !values.is_empty()
+    {
+        bins.iter_mut().for_each(| bin | *bin /= values.len() as f64);
+    }
+    bins
+}
+
+// Computes the fixed-length feature vector of an image: moments of each raw response, histograms of each
+// saturated response (so small ranges like affinity spread over all bins) and whole-image GLCM statistics
+// With a mask every statistic only covers the pixels inside it, and max diff and center diff use the given radius
+pub fn extract(img: &image::RgbImage, diff_radius: u32, glcm_levels: usize, glcm_distance: u32, mask: Option<&image::GrayImage>) -> Vec<f64>
+{
+    let mut features = Vec::with_capacity(GROUPS.len() * (MOMENTS.len() + BINS) + glcm::FEATURES.len());
+    for group in GROUPS.iter()
+    {
+        let mut response = filters::luma(&filters::run(group, img, diff_radius, mask));
+        features.extend_from_slice(&moments(&mask::values(&response, mask)));
+        saturate_within(&mut response, mask);
+        features.extend_from_slice(&histogram(&mask::values(&response, mask)));
+    }
+    features.extend_from_slice(&glcm::image_features(img, glcm_levels, glcm_distance, mask));
+    features
+}
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+
+    fn assert_close(actual: f64, expected: f64, name: &str)
+    {
+        assert!((actual - expected).abs() < 1e-9, "{} is {} rather than {}", name, actual, expected);
+    }
+
+    #[test]
+    fn moments_of_known_values()
+    {
+        // Values 0, 0, 0, 4 have mean 1, central moments 3, 6 and 21, so skewness 6 / 3^1.5 and kurtosis 21 / 9 - 3
+        let moments = moments(&[0, 0, 0, 4]);
+        assert_close(moments [0], 1.0, "mean");
+        assert_close(moments [1], 3.0f64.sqrt(), "std");
+        assert_close(moments [2], 6.0 / 3.0f64.powf(1.5), "skewness");
+        assert_close(moments [3], 21.0 / 9.0 - 3.0, "kurtosis");
+    }
+
+    #[test]
+    fn degenerate_values_give_zero_shape()
+    {
+        assert_eq!(moments(&[]), [0.0; 4]);
+        assert_eq!(moments(&[7; 5]), [7.0, 0.0, 0.0, 0.0]);
+        assert_eq!(histogram(&[]), [0.0; BINS]);
+    }
+
+    #[test]
+    fn histograms_are_normalised()
+    {
+        let bins = histogram(&[0, 15, 16, 255]);
+        assert_eq!(bins [0], 0.5);
+        assert_eq!(bins [1], 0.25);
+        assert_eq!(bins [BINS - 1], 0.25);
+        assert_close(bins.iter().sum(), 1.0, "total");
+    }
+
+    #[test]
+    fn vectors_match_the_column_names()
+    {
+        let img = image::ImageBuffer::from_fn(6, 5, | x, y | image::Rgb([(x * 40 + y) as u8; 3]));
+        let empty = image::GrayImage::new(6, 5);
+        for mask in [None, Some(&empty)]
+        {
+            let features = extract(&img, 0, 8, 1, mask);
+            assert_eq!(features.len(), names().len());
+            assert!(features.iter().all(| value | value.is_finite()), "features {:?} are not all finite", features);
+        }
+    }
+}
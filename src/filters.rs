@@ -0,0 +1,73 @@
+use crate::affinity::affinity_within;
+use crate::average::average;
+use crate::center_diff::center_diff_within;
+use crate::mask::saturate_within;
+use crate::max_diff::max_diff_within;
+
+// Names of the filters that can be selected on the command line
+pub const FILTERS: [&str; 5] = ["original", "affinity", "max_diff", "center_diff", "average"];
+
+// Checks that a filter name exists
+pub fn check(name: &str)
+{
+    if !FILTERS.contains(&name)
+    {
+        panic!("Unknown filter {} (expected one of {})", name, FILTERS.join(", "));
+    }
+}
+
+// Gets how far from a pixel a filter looks when max diff and center diff use the given radius, or None for filters
+// that depend on the whole image
+pub fn radius(name: &str, diff_radius: u32) -> Option<u32>
+{
+    match name
+    {
+        "original" => Some(0),
+        "affinity" => Some(1),
+        // Radius 0 is the square of the pixel and those before it, which still reaches one pixel away
+        "max_diff" | "center_diff" => Some(diff_radius.max(1)),
+        _ => None,
+    }
+}
+
+// Runs a filter by name and returns its raw output, with max diff and center diff looking the given radius from each pixel
+// With a mask, windows only take in the pixels inside it, though outputs are left to be cleared outside it by the caller
+pub fn run(name: &str, img: &image::RgbImage, diff_radius: u32, mask: Option<&image::GrayImage>) -> image::RgbImage
+{
+    match name
+    {
+        "original" => img.clone(),
+        "affinity" => affinity_within(img, mask),
+        "max_diff" => max_diff_within(img, diff_radius, mask),
+        "center_diff" => center_diff_within(img, diff_radius, mask),
+        "average" =>
+        {
+            // Matches the average output, which blends the saturated original with the saturated affinity output
+            let mut original = img.clone();
+            let mut analyzed = affinity_within(img, mask);
+            saturate_within(&mut original, mask);
+            saturate_within(&mut analyzed, mask);
+            average(&original, &analyzed)
+        },
+        _ => panic!("Unknown filter {}", name),
+    }
+}
+
+// Reduces a color image to one channel, keeping grayscale values exact
+pub fn luma(img: &image::RgbImage) -> image::GrayImage
+{
+    image::ImageBuffer::from_fn(img.width(), img.height(),
+        | x, y |
+        {
+            let p = img.get_pixel(x, y);
+            if p [0] == p [1] && p [1] == p [2]
+            {
+                image::Luma([p [0]])
+            }
+            else
+            {
+                image::Luma([((p [0] as u32 * 2126 + p [1] as u32 * 7152 + p [2] as u32 * 722 + 5000) / 10000) as u8])
+            }
+        }
+    )
+}
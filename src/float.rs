@@ -0,0 +1,298 @@
+use crate::export::Export;
+use crate::affinity::window;
+use crate::mask::{clear, contains};
+use crate::raw::{write_raw, RawFormat};
+use crate::progress::log;
+
+use std::time::Instant;
+
+// Image with floating point samples so intermediate results are only quantised when written
+pub type FloatImage = image::ImageBuffer<image::Rgb<f32>, Vec<f32>>;
+
+// Converts an 8 bit image to floating point
+pub fn from_rgb(img: &image::RgbImage) -> FloatImage
+{
+    image::ImageBuffer::from_fn(img.width(), img.height(), | x, y | image::Rgb(img.get_pixel(x, y).0.map(| value | value as f32)))
+}
+
+// Rounds every sample to the nearest 8 bit value
+pub fn quantize(img: &FloatImage) -> image::RgbImage
+{
+    image::ImageBuffer::from_fn(img.width(), img.height(), | x, y | image::Rgb(img.get_pixel(x, y).0.map(| value | value.round().clamp(0.0, 255.0) as u8)))
+}
+
+// Divides every pixel value in the image by 16 without truncating
+pub fn div16(image: &mut FloatImage)
+{
+    image.iter_mut().for_each(| pixel | *pixel /= 16.0);
+}
+
+// Scales every pixel value in the image to fit the scale 0-255
+pub fn saturate(image: &mut FloatImage)
+{
+    let min = image.iter().cloned().fold(f32::MAX, f32::min);
+    let max = image.iter().cloned().fold(f32::MIN, f32::max);
+    let scale = if max > min { 255.0 / (max - min) } else { 255.0 };
+    image.iter_mut().for_each(| pixel | *pixel = (*pixel - min) * scale);
+}
+
+// Scales the pixels inside the mask to fit the scale 0-255 using only their own range and sets the outside to 0,
+// like mask::saturate_within, or the whole image without a mask
+pub fn saturate_within(image: &mut FloatImage, mask: Option<&image::GrayImage>)
+{
+    let mask = match mask
+    {
+        Some(mask) => mask,
+        None => return saturate(image),
+    };
+    let (mut min, mut max) = (f32::MAX, f32::MIN);
+    for (_, _, pixel) in image.enumerate_pixels().filter(| (x, y, _) | contains(mask, *x, *y))
+    {
+        pixel.0.iter().for_each(| value | { min = min.min(*value); max = max.max(*value); });
+    }
+    let scale = if max > min { 255.0 / (max - min) } else { 255.0 };
+    image.enumerate_pixels_mut().for_each(
+        | (x, y, pixel) |
+        {
+            let inside = contains(mask, x, y);
+            pixel.0.iter_mut().for_each(| value | *value = if inside { (*value - min) * scale } else { 0.0 });
+        }
+    );
+}
+
+// Sets every pixel to the mean of the original and analyzed pixels
+pub fn average(original: &FloatImage, analyzed: &FloatImage) -> FloatImage
+{
+    let mut image = original.clone();
+    image.iter_mut().zip(analyzed.iter()).for_each(| (pixel, other) | *pixel = (*pixel + other) / 2.0);
+    image
+}
+
+// Uses affinity analysis to set each pixel to the highest affinity in a 3x3 square around it
+// Pairs are counted once for every 2x2 block of the window they appear in, like the 8 bit filter, from only the pixels
+// of each block inside the mask when one is given
+pub fn affinity(img: &FloatImage, mask: Option<&image::GrayImage>) -> FloatImage
+{
+    let (width, height) = img.dimensions();
+    image::ImageBuffer::from_fn(width, height,
+        | x, y |
+        {
+            let (xb, yb, wb, hb) = window(x, y, width, height);
+            let mut out = [0.0; 3];
+            for (p, value) in out.iter_mut().enumerate()
+            {
+                // Windows hold at most nine values, so a list is cheaper than a map
+                let mut joint: Vec<((f32, f32), usize)> = Vec::new();
+                for i in xb .. xb + wb - 1
+                {
+                    for j in yb .. yb + hb - 1
+                    {
+                        let mut pairs: Vec<(f32, f32)> = Vec::new();
+                        let block: Vec<f32> = [(i, j), (i + 1, j), (i, j + 1), (i + 1, j + 1)].iter()
+                            .filter(| (bx, by) | mask.is_none_or(| mask | contains(mask, *bx, *by)))
+                            .map(| (bx, by) | img.get_pixel(*bx, *by) [p]).collect();
+                        for a in 0 .. block.len()
+                        {
+                            for b in a + 1 .. block.len()
+                            {
+                                let pair = if block [a] > block [b] { (block [a], block [b]) } else { (block [b], block [a]) };
+                                if pair.0 != pair.1 && !pairs.contains(&pair)
+                                {
+                                    pairs.push(pair);
+                                }
+                            }
+                        }
+                        for pair in pairs
+                        {
+                            match joint.iter_mut().find(| (seen, _) | *seen == pair)
+                            {
+                                Some((_, count)) => *count += 1,
+                                None => joint.push((pair, 1)),
+                            }
+                        }
+                    }
+                }
+
+                // Find the strongest affinity with the largest pixel difference
+                let mut max_cooccurance = 0;
+                for ((l, r), cooccurance) in joint
+                {
+                    if cooccurance > max_cooccurance || (cooccurance == max_cooccurance && l - r > *value)
+                    {
+                        max_cooccurance = cooccurance;
+                        *value = l - r;
+                    }
+                }
+            }
+            image::Rgb(out)
+        }
+    )
+}
+
+// Gets the positions a window of the given radius covers along one side of the image, as window_min_max defines them
+// Radius 0 covers the position and the one before it, only the one before for the last position, and the position alone
+// when the side is a single pixel long
+fn span(at: u32, length: u32, radius: u32) -> std::ops::Range<u32>
+{
+    if radius > 0
+    {
+        at.saturating_sub(radius) .. (at + radius + 1).min(length)
+    }
+    else if length == 1
+    {
+        0 .. 1
+    }
+    else if at == length - 1
+    {
+        at - 1 .. at
+    }
+    else
+    {
+        at.saturating_sub(1) .. at + 1
+    }
+}
+
+// Finds the smallest and largest sample of each channel in the window around every pixel, the same windows as the 8 bit
+// window_min_max, one direction at a time
+// With a mask only pixels inside it are taken in, so a window without any gives infinity as its minimum and negative
+// infinity as its maximum
+pub fn window_min_max(img: &FloatImage, radius: u32, mask: Option<&image::GrayImage>) -> (FloatImage, FloatImage)
+{
+    let (width, height) = img.dimensions();
+    let extreme = | source: &FloatImage, pick: fn(f32, f32) -> f32, neutral: f32, across: bool |
+    {
+        image::ImageBuffer::from_fn(width, height,
+            | x, y |
+            {
+                let positions = if across { span(y, height, radius) } else { span(x, width, radius) };
+                let pixel = | position: u32 | if across { source.get_pixel(x, position).0 } else { source.get_pixel(position, y).0 };
+                // Rows are found first, so the mask only has to be checked there
+                let inside = | position: u32 | across || mask.is_none_or(| mask | contains(mask, position, y));
+                let mut out = [neutral; 3];
+                for position in positions.filter(| position | inside(*position))
+                {
+                    for (value, sample) in out.iter_mut().zip(pixel(position).iter())
+                    {
+                        *value = pick(*value, *sample);
+                    }
+                }
+                image::Rgb(out)
+            }
+        )
+    };
+    (extreme(&extreme(img, f32::min, f32::INFINITY, false), f32::min, f32::INFINITY, true),
+     extreme(&extreme(img, f32::max, f32::NEG_INFINITY, false), f32::max, f32::NEG_INFINITY, true))
+}
+
+// Sets every pixel to the largest difference in the window of the given radius around it, as the 8 bit max diff does
+pub fn max_diff(img: &FloatImage, radius: u32, mask: Option<&image::GrayImage>) -> FloatImage
+{
+    let (min, max) = window_min_max(img, radius, mask);
+    image::ImageBuffer::from_fn(img.width(), img.height(),
+        | x, y |
+        {
+            let (min, max) = (min.get_pixel(x, y), max.get_pixel(x, y));
+            image::Rgb([0, 1, 2].map(| i | (max [i] - min [i]).max(0.0)))
+        }
+    )
+}
+
+// Sets every pixel to the largest difference between it and its neighbors in the window of the given radius, as the 8 bit center diff does
+pub fn center_diff(img: &FloatImage, radius: u32, mask: Option<&image::GrayImage>) -> FloatImage
+{
+    let (min, max) = window_min_max(img, radius, mask);
+    image::ImageBuffer::from_fn(img.width(), img.height(),
+        | x, y |
+        {
+            let (center, min, max) = (img.get_pixel(x, y), min.get_pixel(x, y), max.get_pixel(x, y));
+            image::Rgb([0, 1, 2].map(| i | (max [i] - center [i]).max(center [i] - min [i]).max(0.0)))
+        }
+    )
+}
+
+// Filters run by the floating point pipeline as (name, output directory)
+pub const FILTERS: [(&str, &str); 3] = [("Affinity", "output"), ("Max Diff", "output_max_diff"), ("Center Diff", "output_center_diff")];
+
+// Runs a filter by its name in FILTERS, with max diff and center diff looking the given radius from each pixel
+fn run_filter(name: &str, img: &FloatImage, radius: u32, mask: Option<&image::GrayImage>) -> FloatImage
+{
+    match name
+    {
+        "Affinity" => affinity(img, mask),
+        "Max Diff" => max_diff(img, radius, mask),
+        "Center Diff" => center_diff(img, radius, mask),
+        _ => panic!("Unknown floating point filter {}", name),
+    }
+}
+
+// Saves the quantised image and, when float outputs are kept, the unquantised samples under a float_ prefixed directory
+fn save(img: &FloatImage, dir: &str, entry: &str, keep: Option<RawFormat>, export: &mut Export)
+{
+    export.save(&quantize(img), &(dir.to_owned() + entry));
+    if let Some(format) = keep
+    {
+        write_raw(&("float_".to_owned() + dir + entry), img.width(), img.height(), 3, img, format);
+    }
+}
+
+// Runs the base, affinity, max diff, center diff and average outputs in floating point and saves their raw and saturated versions
+// With a mask the outputs are cleared outside it and saturated using only the range inside it, like the 8 bit outputs
+// The output directory function maps a directory name to the directory the image belongs in
+#[allow(clippy::too_many_arguments)]
+pub fn analyze_float<F>(original: &FloatImage, radius: u32, base_dir: &str, average_dir: &str, entry: &str, keep: Option<RawFormat>, output_dir: F, mask: Option<&image::GrayImage>, export: &mut Export)
+    where F: Fn(&str) -> String
+{
+    let mut base = original.clone();
+    clear(&mut base, mask);
+    save(&base, &output_dir(base_dir), entry, keep, export);
+
+    let mut saturated_affinity = None;
+    for (name, dir) in FILTERS.iter()
+    {
+        let now = Instant::now();
+        let mut image = run_filter(name, original, radius, mask);
+        clear(&mut image, mask);
+        let elapsed = now.elapsed();
+        let sec = (elapsed.as_secs() as f64) + (elapsed.subsec_nanos() as f64 / 1_000_000_000.0);
+        log(&format!("Floating Point {} Analysis Completed in: {}", name, sec));
+        save(&image, &output_dir(dir), entry, keep, export);
+        saturate_within(&mut image, mask);
+        save(&image, &("saturated_".to_owned() + &output_dir(dir)), entry, keep, export);
+        if *name == "Affinity"
+        {
+            saturated_affinity = Some(image);
+        }
+    }
+
+    // The average blends the saturated original with the saturated affinity output before either is quantised
+    let mut saturated = original.clone();
+    saturate_within(&mut saturated, mask);
+    save(&saturated, &("saturated_".to_owned() + &output_dir(base_dir)), entry, keep, export);
+    let mut image = average(&saturated, &saturated_affinity.unwrap());
+    clear(&mut image, mask);
+    save(&image, &output_dir(average_dir), entry, keep, export);
+    saturate_within(&mut image, mask);
+    save(&image, &("saturated_".to_owned() + &output_dir(average_dir)), entry, keep, export);
+}
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+    use crate::center_diff::center_diff_radius;
+    use crate::max_diff::max_diff_radius;
+
+    #[test]
+    fn diffs_match_the_8_bit_filters()
+    {
+        for (width, height) in [(1, 1), (1, 5), (6, 1), (9, 7)]
+        {
+            let img = image::ImageBuffer::from_fn(width, height, | x, y | image::Rgb([(x * 37 + y * 11) as u8, (x * y * 7) as u8, ((x ^ y) * 29) as u8]));
+            for radius in [0, 1, 3, 10]
+            {
+                assert!(quantize(&max_diff(&from_rgb(&img), radius, None)).into_raw() == max_diff_radius(&img, radius).into_raw(), "max diff differs at radius {}", radius);
+                assert!(quantize(&center_diff(&from_rgb(&img), radius, None)).into_raw() == center_diff_radius(&img, radius).into_raw(), "center diff differs at radius {}", radius);
+            }
+        }
+    }
+}
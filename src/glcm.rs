@@ -0,0 +1,337 @@
+use crate::export::Export;
+use crate::affinity::DIRECTIONS;
+use crate::filters::luma;
+use crate::mask::{self, clear, contains, saturate_within};
+use crate::progress::log;
+
+use std::time::Instant;
+
+// Names of the Haralick statistics computed from each co-occurrence matrix, in feature vector order
+pub const FEATURES: [&str; 16] = ["asm", "energy", "contrast", "dissimilarity", "homogeneity", "correlation", "variance",
+                                  "sum_average", "sum_variance", "sum_entropy", "entropy", "difference_variance",
+                                  "difference_entropy", "imc1", "imc2", "max_probability"];
+
+// Features written as per-pixel maps when none are selected
+pub const DEFAULT_MAPS: [&str; 5] = ["contrast", "correlation", "energy", "homogeneity", "entropy"];
+
+// Grayscale image reduced to a small number of gray levels so matrices stay small
+pub struct Quantized
+{
+    width: u32,
+    height: u32,
+    levels: usize,
+    data: Vec<u8>,
+    // Whether each pixel takes part in co-occurrences
+    inside: Vec<bool>,
+}
+
+impl Quantized
+{
+    // Only pixels inside the mask take part in co-occurrences and set the range levels are spread over
+    pub fn masked(img: &image::RgbImage, levels: usize, mask: Option<&image::GrayImage>) -> Quantized
+    {
+        assert!((2 ..= 256).contains(&levels), "GLCM levels must be between 2 and 256");
+        let gray = luma(img);
+        let (width, height) = gray.dimensions();
+        let inside: Vec<bool> = gray.enumerate_pixels().map(| (x, y, _) | mask.is_none_or(| mask | contains(mask, x, y))).collect();
+
+        // Levels are spread over the image's own range so low range inputs like div16 still use all of them
+        let values = mask::values(&gray, mask);
+        let min = values.iter().cloned().min().unwrap_or(0) as usize;
+        let max = values.iter().cloned().max().unwrap_or(0) as usize;
+        let range = max - min + 1;
+        let data = gray.pixels().map(| pixel | ((pixel [0] as usize).clamp(min, max) - min) * levels / range).map(| level | level as u8).collect();
+        Quantized { width, height, levels, data, inside }
+    }
+
+    fn get(&self, x: u32, y: u32) -> usize
+    {
+        self.data [(y * self.width + x) as usize] as usize
+    }
+}
+
+// Builds a normalised symmetric co-occurrence matrix of the pixel pairs separated by offset inside a window (x0, y0, x1, y1)
+pub fn matrix(q: &Quantized, window: (u32, u32, u32, u32), offset: (i64, i64)) -> Vec<f64>
+{
+    let (x0, y0, x1, y1) = window;
+    let mut counts = vec![0u32; q.levels * q.levels];
+    let mut total = 0;
+    for r in x0 ..= x1
+    {
+        for c in y0 ..= y1
+        {
+            // Only count pairs whose partner pixel also lies inside the window
+            let pr = r as i64 + offset.0;
+            let pc = c as i64 + offset.1;
+            if pr < x0 as i64 || pr > x1 as i64 || pc < y0 as i64 || pc > y1 as i64
+            {
+                continue;
+            }
+            if !q.inside [(c * q.width + r) as usize] || !q.inside [(pc as u32 * q.width + pr as u32) as usize]
+            {
+                continue;
+            }
+
+            // Count the pair in both orders to keep the matrix symmetric
+            let a = q.get(r, c);
+            let b = q.get(pr as u32, pc as u32);
+            counts [a * q.levels + b] += 1;
+            counts [b * q.levels + a] += 1;
+            total += 2;
+        }
+    }
+
+    counts.iter().map(| count | if total > 0 { *count as f64 / total as f64 } else { 0.0 }).collect()
+}
+
+// Sums p log p terms of a probability distribution as an entropy (natural logarithm)
+fn entropy<'a, I>(probabilities: I) -> f64
+    where I: Iterator<Item = &'a f64>
+{
+    -probabilities.filter(| p | **p > 0.0).map(| p | p * p.ln()).sum::<f64>()
+}
+
+// Computes the Haralick statistics of a normalised co-occurrence matrix in FEATURES order
+pub fn features(p: &[f64], levels: usize) -> [f64; 16]
+{
+    let mut out = [0.0; 16];
+    if p.iter().all(| value | *value == 0.0)
+    {
+        return out;
+    }
+
+    // Marginal, sum and difference distributions (the matrix is symmetric so both marginals are the same)
+    let mut marginal = vec![0.0; levels];
+    let mut sum = vec![0.0; 2 * levels - 1];
+    let mut difference = vec![0.0; levels];
+    for i in 0 .. levels
+    {
+        for j in 0 .. levels
+        {
+            let value = p [i * levels + j];
+            marginal [i] += value;
+            sum [i + j] += value;
+            difference [(i as i64 - j as i64).unsigned_abs() as usize] += value;
+        }
+    }
+    let mean: f64 = marginal.iter().enumerate().map(| (i, m) | i as f64 * m).sum();
+    let variance: f64 = marginal.iter().enumerate().map(| (i, m) | (i as f64 - mean).powi(2) * m).sum();
+
+    let mut asm = 0.0;
+    let mut contrast = 0.0;
+    let mut dissimilarity = 0.0;
+    let mut homogeneity = 0.0;
+    let mut covariance = 0.0;
+    let mut hxy1 = 0.0;
+    let mut hxy2 = 0.0;
+    for i in 0 .. levels
+    {
+        for j in 0 .. levels
+        {
+            let value = p [i * levels + j];
+            let d = i as f64 - j as f64;
+            asm += value * value;
+            contrast += d * d * value;
+            dissimilarity += d.abs() * value;
+            homogeneity += value / (1.0 + d * d);
+            covariance += (i as f64 - mean) * (j as f64 - mean) * value;
+
+            let independent = marginal [i] * marginal [j];
+            if independent > 0.0
+            {
+                hxy1 -= value * independent.ln();
+                hxy2 -= independent * independent.ln();
+            }
+        }
+    }
+
+    let sum_average: f64 = sum.iter().enumerate().map(| (k, s) | k as f64 * s).sum();
+    let sum_variance: f64 = sum.iter().enumerate().map(| (k, s) | (k as f64 - sum_average).powi(2) * s).sum();
+    let difference_mean: f64 = difference.iter().enumerate().map(| (k, d) | k as f64 * d).sum();
+    let difference_variance: f64 = difference.iter().enumerate().map(| (k, d) | (k as f64 - difference_mean).powi(2) * d).sum();
+    let hxy = entropy(p.iter());
+    let hx = entropy(marginal.iter());
+
+    out [0] = asm;
+    out [1] = asm.sqrt();
+    out [2] = contrast;
+    out [3] = dissimilarity;
+    out [4] = homogeneity;
+    // A constant region is treated as perfectly correlated
+    out [5] = if variance > 0.0 { covariance / variance } else { 1.0 };
+    out [6] = variance;
+    out [7] = sum_average;
+    out [8] = sum_variance;
+    out [9] = entropy(sum.iter());
+    out [10] = hxy;
+    out [11] = difference_variance;
+    out [12] = entropy(difference.iter());
+    out [13] = if hx > 0.0 { (hxy - hxy1) / hx } else { 0.0 };
+    out [14] = (1.0 - (-2.0 * (hxy2 - hxy)).exp()).max(0.0).sqrt();
+    out [15] = p.iter().cloned().fold(0.0, f64::max);
+    out
+}
+
+// Averages the statistics of every direction in a window so the result does not depend on orientation
+fn window_features(q: &Quantized, window: (u32, u32, u32, u32), distance: u32) -> [f64; 16]
+{
+    let mut out = [0.0; 16];
+    for (_, dx, dy) in DIRECTIONS.iter()
+    {
+        let p = matrix(q, window, (dx * distance as i64, dy * distance as i64));
+        for (total, value) in out.iter_mut().zip(features(&p, q.levels).iter())
+        {
+            *total += value / DIRECTIONS.len() as f64;
+        }
+    }
+    out
+}
+
+// Computes the per-image feature vector from co-occurrences over the whole image, or only inside the mask when one is given
+pub fn image_features(img: &image::RgbImage, levels: usize, distance: u32, mask: Option<&image::GrayImage>) -> [f64; 16]
+{
+    let q = Quantized::masked(img, levels, mask);
+    window_features(&q, (0, 0, q.width - 1, q.height - 1), distance)
+}
+
+// Parses a comma separated list of feature names into FEATURES indices
+pub fn parse_features(list: &str) -> Vec<usize>
+{
+    list.split(',').map(| name | name.trim()).filter(| name | !name.is_empty()).map(
+        | name |
+        {
+            match FEATURES.iter().position(| feature | *feature == name)
+            {
+                Some(index) => index,
+                None => panic!("Unknown GLCM feature {} (expected one of {})", name, FEATURES.join(", ")),
+            }
+        }
+    ).collect()
+}
+
+// Linearly scales feature values to 0-255 since statistics have very different ranges
+fn to_image(values: &[f64], width: u32, height: u32) -> image::RgbImage
+{
+    let min = values.iter().cloned().fold(f64::MAX, f64::min);
+    let max = values.iter().cloned().fold(f64::MIN, f64::max);
+    let scale = if max > min { 255.0 / (max - min) } else { 0.0 };
+    image::ImageBuffer::from_fn(width, height,
+        | x, y |
+        {
+            let value = ((values [(y * width + x) as usize] - min) * scale).round() as u8;
+            image::Rgb([value; 3])
+        }
+    )
+}
+
+// Computes the selected statistics for a window of the given radius around every pixel, returning one scaled map per feature
+// With a mask the windows only count co-occurrences between pixels inside it
+pub fn feature_maps(img: &image::RgbImage, levels: usize, distance: u32, radius: u32, selected: &[usize], mask: Option<&image::GrayImage>) -> Vec<image::RgbImage>
+{
+    let q = Quantized::masked(img, levels, mask);
+    let (width, height) = (q.width, q.height);
+    let mut values: Vec<Vec<f64>> = vec![Vec::with_capacity((width * height) as usize); selected.len()];
+    for y in 0 .. height
+    {
+        for x in 0 .. width
+        {
+            // Clamp the window to the image bounds
+            let window = (x.saturating_sub(radius), y.saturating_sub(radius), (x + radius).min(width - 1), (y + radius).min(height - 1));
+            let features = window_features(&q, window, distance);
+            for (map, index) in values.iter_mut().zip(selected)
+            {
+                map.push(features [*index]);
+            }
+        }
+    }
+    values.iter().map(| map | to_image(map, width, height)).collect()
+}
+
+// Writes per-pixel GLCM feature maps for the selected features, rescaled to the range inside the mask when one is given
+// The output directory function maps a feature name to the directory the image belongs in
+#[allow(clippy::too_many_arguments)]
+pub fn analyze_glcm<F>(img: &image::RgbImage, levels: usize, distance: u32, radius: u32, selected: &[usize], entry: &str, output_dir: F, mask: Option<&image::GrayImage>, export: &mut Export)
+    where F: Fn(&str) -> String
+{
+    let now = Instant::now();
+    let mut maps = feature_maps(img, levels, distance, radius, selected, mask);
+    let elapsed = now.elapsed();
+    let sec = (elapsed.as_secs() as f64) + (elapsed.subsec_nanos() as f64 / 1_000_000_000.0);
+    log(&format!("GLCM Analysis Completed in: {}", sec));
+
+    for (map, index) in maps.iter_mut().zip(selected)
+    {
+        if mask.is_some()
+        {
+            clear(map, mask);
+            saturate_within(map, mask);
+        }
+        export.save(map, &(output_dir(FEATURES [*index]) + entry));
+    }
+}
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+
+    // The 4x4 image with gray levels 0 to 3 that Haralick's paper works through
+    fn haralick() -> image::RgbImage
+    {
+        let levels = [0, 0, 1, 1, 0, 0, 1, 1, 0, 2, 2, 2, 2, 2, 3, 3];
+        image::ImageBuffer::from_fn(4, 4, | x, y | image::Rgb([levels [(y * 4 + x) as usize]; 3]))
+    }
+
+    fn assert_close(actual: f64, expected: f64, name: &str)
+    {
+        assert!((actual - expected).abs() < 1e-9, "{} is {} rather than {}", name, actual, expected);
+    }
+
+    #[test]
+    fn horizontal_matrix_matches_the_hand_count()
+    {
+        let q = Quantized::masked(&haralick(), 4, None);
+        let p = matrix(&q, (0, 0, 3, 3), (1, 0));
+        let counts = [4.0, 2.0, 1.0, 0.0,
+                      2.0, 4.0, 0.0, 0.0,
+                      1.0, 0.0, 6.0, 1.0,
+                      0.0, 0.0, 1.0, 2.0];
+        for (i, (actual, count)) in p.iter().zip(counts.iter()).enumerate()
+        {
+            assert_close(*actual, count / 24.0, &format!("entry ({}, {})", i / 4, i % 4));
+        }
+
+        // Marginal (7, 6, 8, 3) / 24 gives a mean of 31 / 24, a variance of 599 / 576 and a covariance of 431 / 576
+        let features = features(&p, 4);
+        assert_close(features [2], 14.0 / 24.0, "contrast");
+        assert_close(features [0], 84.0 / 576.0, "asm");
+        assert_close(features [1], (84.0f64 / 576.0).sqrt(), "energy");
+        assert_close(features [4], (16.0 + 6.0 / 2.0 + 2.0 / 5.0) / 24.0, "homogeneity");
+        assert_close(features [5], 431.0 / 599.0, "correlation");
+        assert_close(features [6], 599.0 / 576.0, "variance");
+        assert_close(features [15], 6.0 / 24.0, "max_probability");
+    }
+
+    #[test]
+    fn constant_image_has_defined_features()
+    {
+        let img = image::RgbImage::from_pixel(5, 5, image::Rgb([90; 3]));
+        let features = image_features(&img, 8, 1, None);
+        assert!(features.iter().all(| value | value.is_finite()), "features {:?} are not all finite", features);
+        assert_close(features [2], 0.0, "contrast");
+        assert_close(features [1], 1.0, "energy");
+        assert_close(features [4], 1.0, "homogeneity");
+        assert_close(features [5], 1.0, "correlation");
+    }
+
+    #[test]
+    fn masked_pixels_take_no_part()
+    {
+        // Outside the mask the image is noisy, inside it is constant, so the masked features are those of a constant image
+        let img = image::ImageBuffer::from_fn(6, 6, | x, y | image::Rgb([if x < 3 { 50 } else { ((x * 7 + y * 13) % 11 * 20) as u8 }; 3]));
+        let mask = image::ImageBuffer::from_fn(6, 6, | x, _ | image::Luma([if x < 3 { 255 } else { 0 }]));
+        let features = image_features(&img, 8, 1, Some(&mask));
+        assert_close(features [2], 0.0, "contrast");
+        assert_close(features [5], 1.0, "correlation");
+    }
+}
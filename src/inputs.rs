@@ -0,0 +1,259 @@
+use crate::dicom::{self, Voi};
+
+use std::fs;
+use std::fs::File;
+use std::io::Read;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+// Extensions of the image files inputs are read from
+pub const EXTENSIONS: [&str; 16] = ["bmp", "dcm", "dicom", "gif", "hdr", "ico", "jpeg", "jpg", "pam", "pbm", "pgm", "png", "ppm", "tif", "tiff", "webp"];
+
+// Where the bytes of an input image are kept
+#[derive(Clone)]
+pub enum Source
+{
+    File(PathBuf),
+    // Zip members are read on demand since zip archives allow random access
+    Zip(PathBuf, String),
+    // Tar archives can only be read in order, so their members are read while listing
+    // This keeps every selected member of a tar archive in memory for the whole run, so large sets are better unpacked or zipped
+    Memory(Arc<Vec<u8>>),
+}
+
+// An input image with its path relative to the input directory or archive
+#[derive(Clone)]
+pub struct Input
+{
+    pub path: String,
+    pub source: Source,
+}
+
+impl Input
+{
+    // Gets the file name of the input, which is what answers refer to it by
+    pub fn name(&self) -> String
+    {
+        self.path.rsplit('/').next().unwrap().to_owned()
+    }
+
+    // Opens the input, mapping DICOM images to 8 bit gray levels with the given VOI transform
+    pub fn open(&self, voi: Voi) -> image::DynamicImage
+    {
+        let bytes = match &self.source
+        {
+            Source::File(path) => Arc::new(fs::read(path).unwrap_or_else(| _ | panic!("Failed to open {}", path.display()))),
+            Source::Zip(archive, member) =>
+            {
+                let mut zip = zip::ZipArchive::new(File::open(archive).unwrap()).unwrap();
+                let mut file = zip.by_name(member).unwrap();
+                let mut bytes = Vec::new();
+                file.read_to_end(&mut bytes).unwrap();
+                Arc::new(bytes)
+            },
+            Source::Memory(bytes) => bytes.clone(),
+        };
+        let extension = Path::new(&self.path).extension().map(| extension | extension.to_string_lossy().to_lowercase()).unwrap_or_default();
+        if dicom::is_dicom(&bytes) || extension == "dcm" || extension == "dicom"
+        {
+            return dicom::read(&bytes, voi);
+        }
+        image::load_from_memory(&bytes).unwrap_or_else(| _ | panic!("Failed to decode {}", self.path))
+    }
+}
+
+// Matches text against a glob pattern where * matches within a path component, ** across components and ? one character
+pub fn glob(pattern: &str, text: &str) -> bool
+{
+    let pattern: Vec<char> = pattern.chars().collect();
+    let text: Vec<char> = text.chars().collect();
+
+    // matches [i] [j] tells whether pattern [i ..] matches text [j ..], filled in from the end
+    let mut matches = vec![vec![false; text.len() + 1]; pattern.len() + 1];
+    matches [pattern.len()] [text.len()] = true;
+    for i in (0 .. pattern.len()).rev()
+    {
+        let double = pattern [i] == '*' && i + 1 < pattern.len() && pattern [i + 1] == '*';
+        for j in (0 ..= text.len()).rev()
+        {
+            matches [i] [j] = match pattern [i]
+            {
+                '*' if double =>
+                {
+                    // A trailing slash after ** may match nothing so **/ also covers the top level,
+                    // but only where a component starts so **/x.png does not match abx.png
+                    let slash = i + 2 < pattern.len() && pattern [i + 2] == '/';
+                    let start = j == 0 || text [j - 1] == '/';
+                    matches [i + 2] [j] || (slash && start && matches [i + 3] [j]) || (j < text.len() && matches [i] [j + 1])
+                },
+                '*' => matches [i + 1] [j] || (j < text.len() && text [j] != '/' && matches [i] [j + 1]),
+                '?' => j < text.len() && text [j] != '/' && matches [i + 1] [j + 1],
+                c => j < text.len() && text [j] == c && matches [i + 1] [j + 1],
+            };
+        }
+    }
+    matches [0] [0]
+}
+
+// Filters inputs by include and exclude patterns, which match the whole relative path when they hold a slash and the file name otherwise
+pub struct Filter
+{
+    include: Vec<String>,
+    exclude: Vec<String>,
+}
+
+impl Filter
+{
+    // Parses comma separated lists of patterns, where no include patterns means every image is included
+    pub fn new(include: &str, exclude: &str) -> Filter
+    {
+        let split = | list: &str | list.split(',').map(| pattern | pattern.trim().to_owned()).filter(| pattern | !pattern.is_empty()).collect();
+        Filter { include: split(include), exclude: split(exclude) }
+    }
+
+    fn matches(pattern: &str, path: &str) -> bool
+    {
+        if pattern.contains('/') { glob(pattern, path) } else { glob(pattern, path.rsplit('/').next().unwrap()) }
+    }
+
+    // Whether a path names an image that should be read
+    // Hidden files such as .DS_Store and the resource forks macOS adds to archives are always skipped
+    pub fn accepts(&self, path: &str) -> bool
+    {
+        let name = path.rsplit('/').next().unwrap();
+        let image = Path::new(name).extension().is_some_and(| extension | EXTENSIONS.contains(&extension.to_string_lossy().to_lowercase().as_str()));
+        let hidden = path.split('/').any(| part | part.starts_with('.') || part == "__MACOSX");
+        image && !hidden
+            && (self.include.is_empty() || self.include.iter().any(| pattern | Filter::matches(pattern, path)))
+            && !self.exclude.iter().any(| pattern | Filter::matches(pattern, path))
+    }
+}
+
+// Whether a path is an archive inputs can be read from
+fn archive(path: &Path) -> bool
+{
+    let name = path.to_string_lossy().to_lowercase();
+    name.ends_with(".zip") || name.ends_with(".tar") || name.ends_with(".tar.gz") || name.ends_with(".tgz")
+}
+
+// Lists the images inside a zip or tar archive, prefixing their paths
+// Selected tar members are read into memory here while zip members are only opened when processed
+fn read_archive(path: &Path, prefix: &str, filter: &Filter, inputs: &mut Vec<Input>)
+{
+    let name = path.to_string_lossy().to_lowercase();
+    if name.ends_with(".zip")
+    {
+        let mut zip = zip::ZipArchive::new(File::open(path).unwrap()).unwrap_or_else(| _ | panic!("Failed to read zip archive {}", path.display()));
+        for i in 0 .. zip.len()
+        {
+            let file = zip.by_index(i).unwrap();
+            let member = file.name().to_owned();
+            if file.is_file() && filter.accepts(&(prefix.to_owned() + &member))
+            {
+                inputs.push(Input { path: prefix.to_owned() + &member, source: Source::Zip(path.to_path_buf(), member) });
+            }
+        }
+        return;
+    }
+
+    let file = File::open(path).unwrap();
+    let reader: Box<dyn Read> = if name.ends_with(".tar") { Box::new(file) } else { Box::new(flate2::read::GzDecoder::new(file)) };
+    let mut tar = tar::Archive::new(reader);
+    for entry in tar.entries().unwrap_or_else(| _ | panic!("Failed to read tar archive {}", path.display()))
+    {
+        let mut entry = entry.unwrap();
+        let member = entry.path().unwrap().to_string_lossy().trim_start_matches("./").to_owned();
+        if entry.header().entry_type().is_file() && filter.accepts(&(prefix.to_owned() + &member))
+        {
+            let mut bytes = Vec::new();
+            entry.read_to_end(&mut bytes).unwrap();
+            inputs.push(Input { path: prefix.to_owned() + &member, source: Source::Memory(Arc::new(bytes)) });
+        }
+    }
+}
+
+// Walks a directory tree or archive for images, also reading any archives found in the tree
+// Paths are relative to the root, with archive members appearing under the archive's path
+pub fn list(root: &str, filter: &Filter) -> Vec<Input>
+{
+    let mut inputs = Vec::new();
+    let root = Path::new(root);
+    if root.is_file()
+    {
+        if !archive(root)
+        {
+            panic!("Input {} is neither a directory nor a zip or tar archive", root.display());
+        }
+        read_archive(root, "", filter, &mut inputs);
+        return inputs;
+    }
+
+    let mut pending = vec![(root.to_path_buf(), "".to_owned())];
+    while let Some((dir, prefix)) = pending.pop()
+    {
+        let mut entries: Vec<fs::DirEntry> = fs::read_dir(&dir).unwrap_or_else(| _ | panic!("Failed to read directory {}", dir.display())).map(| entry | entry.unwrap()).collect();
+        entries.sort_by_key(| entry | entry.file_name());
+        for entry in entries
+        {
+            let name = entry.file_name().to_string_lossy().into_owned();
+            let path = prefix.clone() + &name;
+            if entry.file_type().unwrap().is_dir()
+            {
+                if !name.starts_with('.')
+                {
+                    pending.push((entry.path(), path + "/"));
+                }
+            }
+            else if archive(&entry.path())
+            {
+                read_archive(&entry.path(), &(path + "/"), filter, &mut inputs);
+            }
+            else if filter.accepts(&path)
+            {
+                inputs.push(Input { path, source: Source::File(entry.path()) });
+            }
+        }
+    }
+    inputs
+}
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+
+    #[test]
+    fn double_star_matches_whole_components()
+    {
+        assert!(glob("**/x.png", "x.png"));
+        assert!(glob("**/x.png", "a/x.png"));
+        assert!(glob("**/x.png", "a/b/x.png"));
+        assert!(!glob("**/x.png", "abx.png"));
+        assert!(!glob("**/x.png", "a/bx.png"));
+        assert!(glob("a/**/x.png", "a/x.png"));
+        assert!(glob("a/**/x.png", "a/b/c/x.png"));
+        assert!(!glob("a/**/x.png", "ab/x.png"));
+        assert!(glob("a/**", "a/b/c.png"));
+        assert!(glob("**.png", "a/b.png"));
+    }
+
+    #[test]
+    fn single_star_and_question_mark_stay_in_a_component()
+    {
+        assert!(glob("*.png", "scan.png"));
+        assert!(!glob("*.png", "a/scan.png"));
+        assert!(glob("scan?.png", "scan1.png"));
+        assert!(!glob("a?b", "a/b"));
+    }
+
+    #[test]
+    fn filter_matches_names_without_a_slash()
+    {
+        let filter = Filter::new("*.png", "bad*");
+        assert!(filter.accepts("dir/good.png"));
+        assert!(!filter.accepts("dir/bad.png"));
+        assert!(!filter.accepts("dir/good.jpg"));
+        assert!(!filter.accepts(".hidden/good.png"));
+        assert!(!filter.accepts("__MACOSX/good.png"));
+    }
+}
@@ -0,0 +1,313 @@
+use crate::affinity::{get_frequencies, strongest_pair, window};
+use crate::filters::{self, luma};
+
+use argparse::{ArgumentParser, Store};
+use csv::Writer;
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+use crate::image::GenericImageView;
+
+const CHANNELS: [&str; 3] = ["red", "green", "blue"];
+
+// Colour maps used to render heatmaps and overlays
+#[derive(Clone, Copy)]
+pub enum ColorMap
+{
+    Gray,
+    Hot,
+    Jet,
+}
+
+impl ColorMap
+{
+    pub fn from_name(name: &str) -> ColorMap
+    {
+        match name
+        {
+            "gray" => ColorMap::Gray,
+            "hot" => ColorMap::Hot,
+            "jet" => ColorMap::Jet,
+            _ => panic!("Unknown colour map {} (expected gray, hot or jet)", name),
+        }
+    }
+
+    // Maps a value between 0 and 1 to a colour
+    pub fn color(self, t: f64) -> image::Rgb<u8>
+    {
+        let t = t.clamp(0.0, 1.0);
+        let scale = | value: f64 | (value.clamp(0.0, 1.0) * 255.0).round() as u8;
+        match self
+        {
+            ColorMap::Gray => image::Rgb([scale(t); 3]),
+            ColorMap::Hot => image::Rgb([scale(3.0 * t), scale(3.0 * t - 1.0), scale(3.0 * t - 2.0)]),
+            ColorMap::Jet => image::Rgb([scale(1.5 - (4.0 * t - 3.0).abs()), scale(1.5 - (4.0 * t - 2.0).abs()), scale(1.5 - (4.0 * t - 1.0).abs())]),
+        }
+    }
+}
+
+// Parses a region given as x,y,width,height
+fn parse_region(region: &str) -> (u32, u32, u32, u32)
+{
+    let values: Vec<u32> = region.split(',').map(| value | value.trim().parse::<u32>().expect("Invalid region value")).collect();
+    match values.as_slice()
+    {
+        [x, y, width, height] => (*x, *y, *width, *height),
+        _ => panic!("Region must be given as x,y,width,height"),
+    }
+}
+
+// Sorts a frequency table, most frequent first
+fn sorted<K: Ord + Copy>(table: &HashMap<K, usize>) -> Vec<(K, usize)>
+{
+    let mut entries: Vec<(K, usize)> = table.iter().map(| (key, count) | (*key, *count)).collect();
+    entries.sort_by(| a, b | b.1.cmp(&a.1).then(a.0.cmp(&b.0)));
+    entries
+}
+
+// Prints the single and joint frequency tables of one channel
+fn print_tables(channel: &str, singles: &[(u8, usize)], pairs: &[((u8, u8), usize)], joint: &HashMap<(u8, u8), usize>)
+{
+    println!("\n{} channel\n", channel);
+    println!("| Value | Single frequency |");
+    println!("|---|---|");
+    singles.iter().for_each(| (value, count) | println!("| {} | {} |", value, count));
+    println!("\n| Pair | Difference | Joint frequency |");
+    println!("|---|---|---|");
+    pairs.iter().for_each(| ((l, r), count) | println!("| ({}, {}) | {} | {} |", l, r, l - r, count));
+    match strongest_pair(joint)
+    {
+        Some(((l, r), count)) => println!("\nStrongest pair ({}, {}) occurs {} times, giving an affinity of {}", l, r, count, l - r),
+        None => println!("\nNo pairs of different values, giving an affinity of 0"),
+    }
+}
+
+// Renders a joint frequency table as a symmetric heatmap over the range of values present, with a cell per value pair
+fn heatmap(single: &HashMap<u8, usize>, joint: &HashMap<(u8, u8), usize>, colormap: ColorMap) -> image::RgbImage
+{
+    let min = *single.keys().min().unwrap_or(&0) as u32;
+    let max = *single.keys().max().unwrap_or(&0) as u32;
+    let span = max - min + 1;
+    let cell = (512 / span).max(1);
+    let peak = joint.values().cloned().max().unwrap_or(1) as f64;
+    image::ImageBuffer::from_fn(span * cell, span * cell,
+        | x, y |
+        {
+            let a = (min + y / cell) as u8;
+            let b = (min + x / cell) as u8;
+            let key = if a > b { (a, b) } else { (b, a) };
+            match joint.get(&key)
+            {
+                Some(count) => colormap.color(*count as f64 / peak),
+                None => image::Rgb([0; 3]),
+            }
+        }
+    )
+}
+
+// Blends a colour mapped filter response over the original image and outlines the inspected region
+fn overlay(img: &image::RgbImage, response: &image::RgbImage, colormap: ColorMap, alpha: f64, region: (u32, u32, u32, u32)) -> image::RgbImage
+{
+    // Responses are stretched to the full colour map since raw outputs are often dark
+    let gray = luma(response);
+    let min = gray.pixels().map(| pixel | pixel [0]).min().unwrap_or(0) as f64;
+    let max = gray.pixels().map(| pixel | pixel [0]).max().unwrap_or(0) as f64;
+    let range = if max > min { max - min } else { 1.0 };
+    let (rx, ry, rw, rh) = region;
+    image::ImageBuffer::from_fn(img.width(), img.height(),
+        | x, y |
+        {
+            let inside = x >= rx && x < rx + rw && y >= ry && y < ry + rh;
+            if inside && (x == rx || y == ry || x == rx + rw - 1 || y == ry + rh - 1)
+            {
+                return image::Rgb([255; 3]);
+            }
+            let color = colormap.color((gray.get_pixel(x, y) [0] as f64 - min) / range);
+            let original = img.get_pixel(x, y);
+            let mut out = [0; 3];
+            for (i, value) in out.iter_mut().enumerate()
+            {
+                *value = ((1.0 - alpha) * original [i] as f64 + alpha * color [i] as f64).round() as u8;
+            }
+            image::Rgb(out)
+        }
+    )
+}
+
+// Explains affinity values by dumping the frequency tables of a pixel's window or a region, rendering them as heatmaps and overlaying filter responses
+pub fn run(args: Vec<String>)
+{
+    let mut path = "".to_owned();
+    let mut x = -1i64;
+    let mut y = -1i64;
+    let mut region = "".to_owned();
+    let mut output = "inspect/".to_owned();
+    let mut colormap = "jet".to_owned();
+    let mut overlays = "".to_owned();
+    let mut diff_radius = 0;
+    let mut alpha = 0.5;
+    {
+        let mut ap = ArgumentParser::new();
+        ap.set_description("Inspect the co-occurrence frequencies behind the affinity value of a pixel or region");
+        ap.refer(&mut path)
+            .add_argument("image", Store,
+            "Path of the image to inspect")
+            .required();
+        ap.refer(&mut x)
+            .add_option(&["-x"], Store,
+            "Set the column of the pixel to inspect (the centre by default)");
+        ap.refer(&mut y)
+            .add_option(&["-y"], Store,
+            "Set the row of the pixel to inspect (the centre by default)");
+        ap.refer(&mut region)
+            .add_option(&["-r", "--region"], Store,
+            "Inspect a region given as x,y,width,height instead of a single pixel's window");
+        ap.refer(&mut output)
+            .add_option(&["-o", "--output"], Store,
+            "Set the directory heatmaps, overlays and frequency tables are written to (inspect/ by default)");
+        ap.refer(&mut colormap)
+            .add_option(&["-c", "--colormap"], Store,
+            "Set the colour map used for heatmaps and overlays (gray, hot or jet; jet by default)");
+        ap.refer(&mut overlays)
+            .add_option(&["--overlay"], Store,
+            "Set a comma separated list of filters whose responses are overlaid on the image (original, affinity, max_diff, center_diff, average)");
+        ap.refer(&mut diff_radius)
+            .add_option(&["--diff-radius"], Store,
+            "Set how far max diff and center diff overlays look from each pixel, as the main --diff-radius option does (0 by default)");
+        ap.refer(&mut alpha)
+            .add_option(&["--alpha"], Store,
+            "Set the opacity of overlaid responses between 0 and 1 (0.5 by default)");
+        if let Err(code) = ap.parse(args, &mut io::stdout(), &mut io::stderr())
+        {
+            std::process::exit(code);
+        }
+    }
+    if !output.ends_with('/')
+    {
+        output.push('/');
+    }
+    if !(0.0 ..= 1.0).contains(&alpha)
+    {
+        panic!("Overlay opacity must be between 0 and 1");
+    }
+    let colormap = ColorMap::from_name(&colormap);
+    let overlays: Vec<&str> = overlays.split(',').map(| name | name.trim()).filter(| name | !name.is_empty()).collect();
+    overlays.iter().for_each(| name | filters::check(name));
+
+    let img = image::open(&path).unwrap_or_else(| _ | panic!("Failed to open {}", path)).to_rgb();
+    let (width, height) = img.dimensions();
+    if width < 2 || height < 2
+    {
+        panic!("Image must be at least 2x2 pixels");
+    }
+
+    // A pixel is inspected through the same window the affinity filter uses for it
+    let bounds = if region.is_empty()
+    {
+        let x = if x < 0 { width / 2 } else { x as u32 };
+        let y = if y < 0 { height / 2 } else { y as u32 };
+        if x >= width || y >= height
+        {
+            panic!("Pixel ({}, {}) lies outside the {}x{} image", x, y, width, height);
+        }
+        let bounds = window(x, y, width, height);
+        println!("Pixel ({}, {}) of {} uses the {}x{} window at ({}, {})", x, y, path, bounds.2, bounds.3, bounds.0, bounds.1);
+        bounds
+    }
+    else
+    {
+        let bounds = parse_region(&region);
+        if bounds.2 < 2 || bounds.3 < 2 || bounds.0 + bounds.2 > width || bounds.1 + bounds.3 > height
+        {
+            panic!("Region must be at least 2x2 pixels and lie inside the {}x{} image", width, height);
+        }
+        println!("Region of {} with the {}x{} window at ({}, {})", path, bounds.2, bounds.3, bounds.0, bounds.1);
+        bounds
+    };
+
+    let subimage = img.view(bounds.0, bounds.1, bounds.2, bounds.3);
+    let (single, joint) = get_frequencies(&subimage, None);
+
+    fs::create_dir_all(&output).unwrap();
+    let mut writer = Writer::from_path(output.clone() + "frequencies.csv").expect("Failed to create frequency table");
+    writer.write_record(["channel", "value", "partner", "frequency"]).unwrap();
+    for (channel, (single, joint)) in CHANNELS.iter().zip(single.iter().zip(&joint))
+    {
+        let singles = sorted(single);
+        let pairs = sorted(joint);
+        print_tables(channel, &singles, &pairs, joint);
+        for (value, count) in &singles
+        {
+            writer.write_record(&[channel.to_string(), value.to_string(), "".to_owned(), count.to_string()]).unwrap();
+        }
+        for ((l, r), count) in &pairs
+        {
+            writer.write_record(&[channel.to_string(), l.to_string(), r.to_string(), count.to_string()]).unwrap();
+        }
+
+        let min = single.keys().min().unwrap_or(&0);
+        let max = single.keys().max().unwrap_or(&0);
+        println!("Heatmap {}joint_{}.png spans values {} to {} from the top left", output, channel, min, max);
+        heatmap(single, joint, colormap).save(output.clone() + "joint_" + channel + ".png").unwrap();
+    }
+    writer.flush().unwrap();
+
+    for name in overlays
+    {
+        let response = filters::run(name, &img, diff_radius, None);
+        overlay(&img, &response, colormap, alpha, bounds).save(output.clone() + "overlay_" + name + ".png").unwrap();
+        println!("Overlay of {} written to {}overlay_{}.png", name, output, name);
+    }
+}
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+
+    #[test]
+    fn colour_maps_span_their_endpoints()
+    {
+        assert_eq!(ColorMap::Gray.color(0.0), image::Rgb([0; 3]));
+        assert_eq!(ColorMap::Gray.color(1.0), image::Rgb([255; 3]));
+        assert_eq!(ColorMap::Hot.color(0.0), image::Rgb([0; 3]));
+        assert_eq!(ColorMap::Hot.color(1.0 / 3.0), image::Rgb([255, 0, 0]));
+        assert_eq!(ColorMap::Hot.color(1.0), image::Rgb([255; 3]));
+        assert_eq!(ColorMap::Jet.color(0.0), image::Rgb([0, 0, 128]));
+        assert_eq!(ColorMap::Jet.color(0.5), image::Rgb([128, 255, 128]));
+        assert_eq!(ColorMap::Jet.color(1.0), image::Rgb([128, 0, 0]));
+
+        // Values outside 0-1 are clamped to the ends
+        for colormap in [ColorMap::Gray, ColorMap::Hot, ColorMap::Jet]
+        {
+            assert_eq!(colormap.color(-1.0), colormap.color(0.0));
+            assert_eq!(colormap.color(2.0), colormap.color(1.0));
+        }
+    }
+
+    #[test]
+    fn heatmaps_are_symmetric_cells_over_the_value_range()
+    {
+        // Two values two apart give a 3x3 grid of cells, where only the pair of different values occurs
+        let img = image::RgbImage::from_raw(2, 2, vec![10, 10, 10, 12, 12, 12, 12, 12, 12, 10, 10, 10]).unwrap();
+        let (single, joint) = get_frequencies(&img.view(0, 0, 2, 2), None);
+        let map = heatmap(&single [0], &joint [0], ColorMap::Gray);
+        let cell = 512 / 3;
+        assert_eq!(map.dimensions(), (3 * cell, 3 * cell));
+        assert!(map.enumerate_pixels().all(| (x, y, pixel) | map.get_pixel(y, x) == pixel), "heatmap is not symmetric");
+        assert_eq!(*map.get_pixel(2 * cell, 0), image::Rgb([255; 3]));
+        assert_eq!(*map.get_pixel(0, 2 * cell + cell - 1), image::Rgb([255; 3]));
+        for (column, row) in [(0, 0), (2, 2), (1, 0), (1, 2), (1, 1)]
+        {
+            assert_eq!(*map.get_pixel(column * cell, row * cell), image::Rgb([0; 3]), "cell ({}, {}) should be empty", column, row);
+        }
+    }
+
+    #[test]
+    fn tables_are_sorted_most_frequent_first()
+    {
+        let table: HashMap<u8, usize> = [(3, 1), (1, 5), (2, 5)].iter().cloned().collect();
+        assert_eq!(sorted(&table), [(1, 5), (2, 5), (3, 1)]);
+        assert_eq!(parse_region("1, 2,30,40"), (1, 2, 30, 40));
+    }
+}
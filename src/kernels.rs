@@ -0,0 +1,393 @@
+use crate::mask::contains;
+
+#[cfg(target_arch = "x86_64")]
+use std::arch::x86_64::*;
+
+// Radius from which windows are found with the van Herk/Gil-Werman algorithm rather than by comparing every offset,
+// across rows, where whole rows are combined with vector instructions either way, and within rows, where only comparing
+// offsets is vectorised so it stays faster for longer
+pub const LARGE_RADIUS: u32 = 4;
+pub const LARGE_RADIUS_WITHIN_ROW: u32 = 64;
+
+// Elementwise operations applied to rows of samples
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Op
+{
+    Min,
+    Max,
+    // Saturating subtraction, so differences below zero become zero
+    Sub,
+}
+
+// Applies an operation to each sample of acc and the sample of other at the same index, storing the result in acc
+// Portable version, also used for slices too short for a vector and the samples after the last full vector
+#[inline]
+pub fn apply_scalar(op: Op, acc: &mut [u8], other: &[u8])
+{
+    assert_eq!(acc.len(), other.len());
+    for (a, b) in acc.iter_mut().zip(other)
+    {
+        *a = combine(op, *a, *b);
+    }
+}
+
+#[inline]
+fn combine(op: Op, a: u8, b: u8) -> u8
+{
+    match op
+    {
+        Op::Min => a.min(b),
+        Op::Max => a.max(b),
+        Op::Sub => a.saturating_sub(b),
+    }
+}
+
+#[cfg(target_arch = "x86_64")]
+#[target_feature(enable = "avx2")]
+unsafe fn apply_avx2(op: Op, acc: &mut [u8], other: &[u8]) -> usize
+{
+    let end = acc.len() / 32 * 32;
+    for i in (0 .. end).step_by(32)
+    {
+        let a = _mm256_loadu_si256(acc.as_ptr().add(i) as *const __m256i);
+        let b = _mm256_loadu_si256(other.as_ptr().add(i) as *const __m256i);
+        let out = match op
+        {
+            Op::Min => _mm256_min_epu8(a, b),
+            Op::Max => _mm256_max_epu8(a, b),
+            Op::Sub => _mm256_subs_epu8(a, b),
+        };
+        _mm256_storeu_si256(acc.as_mut_ptr().add(i) as *mut __m256i, out);
+    }
+    end
+}
+
+#[cfg(target_arch = "x86_64")]
+#[target_feature(enable = "sse2")]
+unsafe fn apply_sse2(op: Op, acc: &mut [u8], other: &[u8]) -> usize
+{
+    let end = acc.len() / 16 * 16;
+    for i in (0 .. end).step_by(16)
+    {
+        let a = _mm_loadu_si128(acc.as_ptr().add(i) as *const __m128i);
+        let b = _mm_loadu_si128(other.as_ptr().add(i) as *const __m128i);
+        let out = match op
+        {
+            Op::Min => _mm_min_epu8(a, b),
+            Op::Max => _mm_max_epu8(a, b),
+            Op::Sub => _mm_subs_epu8(a, b),
+        };
+        _mm_storeu_si128(acc.as_mut_ptr().add(i) as *mut __m128i, out);
+    }
+    end
+}
+
+// Applies the operation to as many full vectors as fit, returning how many samples were done
+#[cfg(target_arch = "x86_64")]
+fn apply_vectors(op: Op, acc: &mut [u8], other: &[u8]) -> usize
+{
+    // SSE2 is part of x86_64, AVX2 has to be checked for (the check is cached after the first call)
+    if is_x86_feature_detected!("avx2")
+    {
+        unsafe { apply_avx2(op, acc, other) }
+    }
+    else
+    {
+        unsafe { apply_sse2(op, acc, other) }
+    }
+}
+
+#[cfg(target_arch = "aarch64")]
+fn apply_vectors(op: Op, acc: &mut [u8], other: &[u8]) -> usize
+{
+    use std::arch::aarch64::*;
+
+    // NEON is part of aarch64
+    let end = acc.len() / 16 * 16;
+    for i in (0 .. end).step_by(16)
+    {
+        unsafe
+        {
+            let a = vld1q_u8(acc.as_ptr().add(i));
+            let b = vld1q_u8(other.as_ptr().add(i));
+            let out = match op
+            {
+                Op::Min => vminq_u8(a, b),
+                Op::Max => vmaxq_u8(a, b),
+                Op::Sub => vqsubq_u8(a, b),
+            };
+            vst1q_u8(acc.as_mut_ptr().add(i), out);
+        }
+    }
+    end
+}
+
+#[cfg(not(any(target_arch = "x86_64", target_arch = "aarch64")))]
+fn apply_vectors(_op: Op, _acc: &mut [u8], _other: &[u8]) -> usize
+{
+    0
+}
+
+// Applies an operation to each sample of acc and the sample of other at the same index, storing the result in acc
+#[inline]
+pub fn apply(op: Op, acc: &mut [u8], other: &[u8])
+{
+    // The vector loops rely on both slices having the same length
+    assert_eq!(acc.len(), other.len());
+    let done = if acc.len() >= 16 { apply_vectors(op, acc, other) } else { 0 };
+    apply_scalar(op, &mut acc [done ..], &other [done ..]);
+}
+
+// Finds the minimum or maximum of every window along one direction of a buffer, where samples step apart are neighbours
+// Windows reach radius neighbours either way, clamped to the buffer, with radius 0 meaning the sample and the one before it,
+// or only the one before for the last sample, which is the window max diff and center diff were written with
+fn extreme(op: Op, input: &[u8], step: usize, radius: usize, out: &mut [u8])
+{
+    let count = input.len() / step;
+    let length = input.len();
+    if count < 2
+    {
+        out.copy_from_slice(input);
+    }
+    else if radius == 0
+    {
+        out.copy_from_slice(input);
+        apply(op, &mut out [step .. length - step], &input [.. length - 2 * step]);
+        out [length - step ..].copy_from_slice(&input [length - 2 * step .. length - step]);
+    }
+    else if radius < (if step < 16 { LARGE_RADIUS_WITHIN_ROW } else { LARGE_RADIUS }) as usize
+    {
+        // Every offset is compared as a whole shifted buffer, which keeps the work in vector instructions
+        out.copy_from_slice(input);
+        for offset in 1 ..= radius.min(count - 1)
+        {
+            let shift = offset * step;
+            apply(op, &mut out [shift ..], &input [.. length - shift]);
+            apply(op, &mut out [.. length - shift], &input [shift ..]);
+        }
+    }
+    else
+    {
+        // Pad by the radius with values that never win, split into blocks of one window and keep running extremes
+        // forwards (prefix) and backwards (suffix) within each block, so any window is the suffix at its start
+        // combined with the prefix at its end, at three operations a sample however large the radius
+        let size = 2 * radius + 1;
+        let padded = count + 2 * radius;
+        let neutral = if op == Op::Min { 255 } else { 0 };
+        let mut prefix = vec![neutral; padded * step];
+        prefix [radius * step .. (radius + count) * step].copy_from_slice(input);
+        let mut suffix = prefix.clone();
+        for start in (0 .. padded).step_by(size)
+        {
+            let end = (start + size).min(padded);
+            if step < 16
+            {
+                // Neighbours within a row are too close together for vectors, so samples are combined one at a time
+                for i in (start + 1) * step .. end * step
+                {
+                    prefix [i] = combine(op, prefix [i], prefix [i - step]);
+                }
+                for i in (start * step .. (end - 1) * step).rev()
+                {
+                    suffix [i] = combine(op, suffix [i], suffix [i + step]);
+                }
+                continue;
+            }
+            for i in start + 1 .. end
+            {
+                let (before, after) = prefix.split_at_mut(i * step);
+                apply(op, &mut after [.. step], &before [(i - 1) * step ..]);
+            }
+            for i in (start .. end - 1).rev()
+            {
+                let (before, after) = suffix.split_at_mut((i + 1) * step);
+                apply(op, &mut before [i * step ..], &after [.. step]);
+            }
+        }
+        out.copy_from_slice(&suffix [.. length]);
+        apply(op, out, &prefix [2 * radius * step .. 2 * radius * step + length]);
+    }
+}
+
+// Finds the smallest or largest sample of each channel in the window around every pixel
+fn window_extreme(op: Op, img: &image::RgbImage, radius: u32) -> image::RgbImage
+{
+    let (width, height) = img.dimensions();
+    let row = width as usize * 3;
+    let mut rows = vec![0; img.len()];
+    for (input, out) in img.chunks(row).zip(rows.chunks_mut(row))
+    {
+        extreme(op, input, 3, radius as usize, out);
+    }
+    let mut out = vec![0; img.len()];
+    extreme(op, &rows, row, radius as usize, &mut out);
+    image::RgbImage::from_raw(width, height, out).unwrap()
+}
+
+// Finds the smallest and largest sample of each channel in the window around every pixel
+// Windows are squares reaching radius pixels from the center, or for radius 0 the 2x2 square of the pixel and those before it,
+// and are found one direction at a time since the extreme of a square is the extreme of its column extremes
+pub fn window_min_max(img: &image::RgbImage, radius: u32) -> (image::RgbImage, image::RgbImage)
+{
+    (window_extreme(Op::Min, img, radius), window_extreme(Op::Max, img, radius))
+}
+
+// Finds the smallest and largest sample of each channel among the pixels of every window that lie inside the mask
+// Pixels outside are replaced with values that never win, so a window without any pixel of the mask has a minimum of 255
+// and a maximum of 0, which differences saturate to 0
+pub fn window_min_max_within(img: &image::RgbImage, radius: u32, mask: Option<&image::GrayImage>) -> (image::RgbImage, image::RgbImage)
+{
+    let mask = match mask
+    {
+        Some(mask) => mask,
+        None => return window_min_max(img, radius),
+    };
+    let filled = | value: u8 |
+    {
+        let mut filled = img.clone();
+        filled.enumerate_pixels_mut().filter(| (x, y, _) | !contains(mask, *x, *y)).for_each(| (_, _, pixel) | *pixel = image::Rgb([value; 3]));
+        filled
+    };
+    (window_extreme(Op::Min, &filled(255), radius), window_extreme(Op::Max, &filled(0), radius))
+}
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+    use crate::center_diff::{center_diff_radius, center_diff_within};
+    use crate::max_diff::{max_diff_radius, max_diff_within};
+
+    fn noise(length: usize, seed: u32) -> Vec<u8>
+    {
+        let mut state = seed.max(1);
+        (0 .. length).map(
+            | _ |
+            {
+                state ^= state << 13;
+                state ^= state >> 17;
+                state ^= state << 5;
+                (state >> 24) as u8
+            }
+        ).collect()
+    }
+
+    // Gets the ranges of columns and rows in the window around a pixel, as the filters were written before the row kernels
+    // Radius 0 is the 2x2 square of the pixel and those before it, which is empty along a single row or column where the
+    // old code underflowed, so there the window is the pixel alone as the kernels treat it
+    fn reference_window(x: u32, y: u32, width: u32, height: u32, radius: u32) -> (std::ops::Range<u32>, std::ops::Range<u32>)
+    {
+        if radius > 0
+        {
+            return (x.saturating_sub(radius) .. (x + radius + 1).min(width), y.saturating_sub(radius) .. (y + radius + 1).min(height));
+        }
+        let rl = if x > 0 { x - 1 } else { x };
+        let rr = if x < width - 1 { x + 1 } else { x };
+        let cl = if y > 0 { y - 1 } else { y };
+        let cr = if y < height - 1 { y + 1 } else { y };
+        (rl .. if rl == rr { rr + 1 } else { rr }, cl .. if cl == cr { cr + 1 } else { cr })
+    }
+
+    // Max diff and center diff found by reading every pixel of every window, skipping those outside the mask
+    fn reference(img: &image::RgbImage, radius: u32, mask: Option<&image::GrayImage>) -> (image::RgbImage, image::RgbImage)
+    {
+        let (width, height) = img.dimensions();
+        let mut max_diff = image::RgbImage::new(width, height);
+        let mut center_diff = image::RgbImage::new(width, height);
+        for (x, y, center) in img.enumerate_pixels()
+        {
+            let (columns, rows) = reference_window(x, y, width, height, radius);
+            for i in 0 .. 3
+            {
+                let (mut min, mut max, mut from_center) = (255, 0, 0);
+                for r in columns.clone()
+                {
+                    for c in rows.clone().filter(| c | mask.is_none_or(| mask | contains(mask, r, *c)))
+                    {
+                        let num = img.get_pixel(r, c) [i];
+                        min = min.min(num);
+                        max = max.max(num);
+                        from_center = from_center.max(num.abs_diff(center [i]));
+                    }
+                }
+                max_diff.get_pixel_mut(x, y) [i] = max.saturating_sub(min);
+                center_diff.get_pixel_mut(x, y) [i] = from_center;
+            }
+        }
+        (max_diff, center_diff)
+    }
+
+    #[test]
+    fn windows_match_reading_every_pixel()
+    {
+        // Sizes cover a single pixel wide image, rows shorter and longer than a vector, and radii on either side of
+        // the thresholds where the kernels change algorithm, as well as beyond the image
+        for width in [1, 2, 3, 17, 33]
+        {
+            for height in [1, 2, 3, 17, 33]
+            {
+                let img = image::RgbImage::from_raw(width, height, noise((width * height * 3) as usize, width * 100 + height)).unwrap();
+                for radius in [0, 1, 3, 4, 63, 64, 200]
+                {
+                    let (max_diff, center_diff) = reference(&img, radius, None);
+                    assert!(max_diff_radius(&img, radius).into_raw() == max_diff.into_raw(), "max diff differs at {}x{} radius {}", width, height, radius);
+                    assert!(center_diff_radius(&img, radius).into_raw() == center_diff.into_raw(), "center diff differs at {}x{} radius {}", width, height, radius);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn masked_windows_only_read_pixels_inside()
+    {
+        for (width, height) in [(1, 9), (9, 1), (17, 13), (40, 33)]
+        {
+            let img = image::RgbImage::from_raw(width, height, noise((width * height * 3) as usize, width + height * 100)).unwrap();
+            // About a third of the pixels are outside, leaving some windows without any pixel of the mask at radius 0
+            let mask = image::GrayImage::from_raw(width, height, noise((width * height) as usize, width * height).iter().map(| value | if *value < 85 { 0 } else { 255 }).collect()).unwrap();
+            for radius in [0, 1, 4, 64]
+            {
+                let (max_diff, center_diff) = reference(&img, radius, Some(&mask));
+                assert!(max_diff_within(&img, radius, Some(&mask)).into_raw() == max_diff.into_raw(), "max diff differs at {}x{} radius {}", width, height, radius);
+                assert!(center_diff_within(&img, radius, Some(&mask)).into_raw() == center_diff.into_raw(), "center diff differs at {}x{} radius {}", width, height, radius);
+            }
+        }
+    }
+
+    #[test]
+    fn vectors_match_the_portable_loop()
+    {
+        // Lengths cover slices shorter than a vector and full vectors followed by every length of tail
+        for length in 0 .. 100
+        {
+            let acc = noise(length, length as u32 + 1);
+            let other = noise(length, length as u32 + 1000);
+            for op in [Op::Min, Op::Max, Op::Sub]
+            {
+                let mut expected = acc.clone();
+                apply_scalar(op, &mut expected, &other);
+                let mut out = acc.clone();
+                apply(op, &mut out, &other);
+                assert_eq!(out, expected, "{:?} differs at length {}", op, length);
+
+                #[cfg(target_arch = "x86_64")]
+                {
+                    let mut out = acc.clone();
+                    let done = unsafe { apply_sse2(op, &mut out, &other) };
+                    assert_eq!(done, length / 16 * 16);
+                    apply_scalar(op, &mut out [done ..], &other [done ..]);
+                    assert_eq!(out, expected, "SSE2 {:?} differs at length {}", op, length);
+
+                    if is_x86_feature_detected!("avx2")
+                    {
+                        let mut out = acc.clone();
+                        let done = unsafe { apply_avx2(op, &mut out, &other) };
+                        assert_eq!(done, length / 32 * 32);
+                        apply_scalar(op, &mut out [done ..], &other [done ..]);
+                        assert_eq!(out, expected, "AVX2 {:?} differs at length {}", op, length);
+                    }
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,17 @@
+// Filters and the modules they save their outputs through, built as a library so benchmarks can call them
+extern crate image;
+
+pub mod affinity;
+pub mod average;
+pub mod blend;
+pub mod center_diff;
+pub mod container;
+pub mod div16;
+pub mod export;
+pub mod filters;
+pub mod kernels;
+pub mod mask;
+pub mod max_diff;
+pub mod npy;
+pub mod progress;
+pub mod saturate;
@@ -1,16 +1,55 @@
 //Import helper functions from other modules
-mod affinity;
-use affinity::analyze_affinity;
-mod average;
+use image_affinity::affinity;
+use affinity::{analyze_affinity, analyze_directional};
+mod augment;
+use augment::Augmentation;
+use image_affinity::average;
 use average::analyze_average;
-mod center_diff;
+mod balance;
+use balance::{Job, Origin, Strategy};
+use image_affinity::blend;
+use blend::{analyze_blend, BlendOp};
+mod classify;
+use image_affinity::container;
+mod evaluate;
+use image_affinity::export;
+use export::Export;
+mod inspect;
+use image_affinity::center_diff;
 use center_diff::analyze_center_diff;
-mod div16;
+mod dicom;
+use dicom::Voi;
+use image_affinity::div16;
 use div16::div16;
-mod max_diff;
+use image_affinity::kernels;
+use image_affinity::mask;
+use image_affinity::max_diff;
 use max_diff::analyze_max_diff;
-mod saturate;
-use saturate::saturate;
+use image_affinity::progress;
+use progress::{log, Progress};
+mod preprocess;
+use preprocess::Preprocess;
+mod pyramid;
+use pyramid::{analyze_pyramid, Downsample};
+mod report;
+mod raw;
+use raw::RawFormat;
+mod signed;
+use signed::{analyze_signed, SampleType};
+mod stats;
+mod stack;
+use stack::{analyze_stack, StackFormat};
+mod features;
+use image_affinity::filters;
+mod float;
+use float::analyze_float;
+mod glcm;
+mod inputs;
+use glcm::analyze_glcm;
+use image_affinity::npy;
+mod tfrecord;
+mod tiff;
+mod tiled;
 
 extern crate image;                 // Used for image processing
 extern crate rand;                  // Used for randomly splitting data
@@ -20,6 +59,7 @@ use std::collections::HashMap;      // Used for storing examples in the answers
 use std::collections::HashSet;      // Used for storing categories from the answers file
 use argparse::{ArgumentParser, Store, StoreTrue};   // Used for argument parsing
 use csv::ReaderBuilder;             // Used to read answers CSV file
+use csv::Writer;                    // Used to write per-image feature CSV files
 
 
 const IMAGE_DIR: &str = "images";               // Stores the default image directory globally
@@ -28,6 +68,11 @@ const OUTPUT_DIR: &str = "output";              // Stores affinity output direct
 const OUTPUT_MAX_DIFF_DIR: &str = "output_max_diff";           // Stores max diff output directory globally
 const OUTPUT_CENTER_DIFF_DIR: &str = "output_center_diff";     // Stores center diff output directory globally
 const OUTPUT_AVERAGE_DIR: &str = "output_average";             // Stores average output directory globally
+const OUTPUT_DIRECTIONAL_DIR: &str = "output_directional";     // Stores the prefix of directional affinity output directories globally
+const OUTPUT_STACK_DIR: &str = "output_stack";                 // Stores channel stack output directory globally
+const OUTPUT_BLEND_DIR: &str = "output_blend";                 // Stores blend output directory globally
+const OUTPUT_GLCM_DIR: &str = "output_glcm";                   // Stores the prefix of GLCM feature map directories globally
+const MASK_DIR: &str = "mask";                                 // Stores foreground mask directory globally
 
 // Stores a list of output directories for directory creation
 const DIRS: [&str; 5] = [BASE_DIR, OUTPUT_DIR, OUTPUT_MAX_DIFF_DIR, OUTPUT_CENTER_DIFF_DIR, OUTPUT_AVERAGE_DIR];
@@ -51,13 +96,13 @@ fn create_dir(dir: &str, del: bool, val: bool, categories: &HashSet<String>)
     // Create validation and test folders
     if val
     {
-        let sub = dir.to_owned() + &"validation/";
+        let sub = dir.to_owned() + "validation/";
         match fs::create_dir(&sub)
         {
             Ok(()) => println!("Made subdirectory {}", sub),
             Err(_) => println!("Subdirectory {} already exists", sub),
         }
-        let sub = dir.to_owned() + &"training/";
+        let sub = dir.to_owned() + "training/";
         match fs::create_dir(&sub)
         {
             Ok(()) => println!("Made subdirectory {}", sub),
@@ -73,13 +118,13 @@ fn create_dir(dir: &str, del: bool, val: bool, categories: &HashSet<String>)
             {
                 if val
                 {
-                    let sub = dir.to_owned() + &"validation/" + category + &"/";
+                    let sub = dir.to_owned() + "validation/" + category + "/";
                     match fs::create_dir(&sub)
                     {
                         Ok(()) => println!("Made subdirectory {}", sub),
                         Err(_) => println!("Subdirectory {} already exists", sub),
                     }
-                    let sub = dir.to_owned() + &"training/" + category + &"/";
+                    let sub = dir.to_owned() + "training/" + category + "/";
                     match fs::create_dir(&sub)
                     {
                         Ok(()) => println!("Made subdirectory {}", sub),
@@ -88,7 +133,7 @@ fn create_dir(dir: &str, del: bool, val: bool, categories: &HashSet<String>)
                 }
                 else
                 {
-                    let sub = dir.to_owned() + category + &"/";
+                    let sub = dir.to_owned() + category + "/";
                     match fs::create_dir(&sub)
                     {
                         Ok(()) => println!("Made subdirectory {}", sub),
@@ -111,18 +156,86 @@ fn output_dir(dir: &str, sample: &str, examples: &HashMap<String, String>) -> St
 
 fn main()
 {
+    // Hand subcommands their own arguments before parsing the processing options
+    let args: Vec<String> = std::env::args().collect();
+    let subcommand: Option<fn(Vec<String>)> = match args.get(1).map(| arg | arg.as_str())
+    {
+        Some("evaluate") => Some(evaluate::run),
+        Some("report") => Some(report::run),
+        Some("inspect") => Some(inspect::run),
+        Some("tfrecord") => Some(tfrecord::run),
+        Some("container") => Some(container::run),
+        Some("tiled") => Some(tiled::run),
+        _ => None,
+    };
+    if let Some(run) = subcommand
+    {
+        run(vec![args [0].clone() + " " + &args [1]].into_iter().chain(args [2 ..].iter().cloned()).collect());
+        return;
+    }
+
     // Read arguments from user
-    let mut image_dir = IMAGE_DIR.to_owned() + &"/";
+    let mut image_dir = IMAGE_DIR.to_owned() + "/";
     let mut answers = "".to_owned();
+    let mut include = "".to_owned();
+    let mut exclude = "".to_owned();
     let mut validation = 0;
     let mut delete = false;
     let mut verbose = false;
+    let mut no_progress = false;
+    let mut pyramid_levels = 0;
+    let mut pyramid_kind = "gaussian".to_owned();
+    let mut pyramid_stack = false;
+    let mut directional = 0;
+    let mut stack = "".to_owned();
+    let mut stack_format = "bmp".to_owned();
+    let mut blend = "".to_owned();
+    let mut blend_op = "mean".to_owned();
+    let mut glcm_maps = false;
+    let mut glcm_features = glcm::DEFAULT_MAPS.join(",");
+    let mut glcm_levels = 8;
+    let mut glcm_distance = 1;
+    let mut glcm_window = 2;
+    let mut glcm_csv = "".to_owned();
+    let mut features = "".to_owned();
+    let mut features_only = false;
+    let mut signed_outputs = false;
+    let mut signed_type = "i16".to_owned();
+    let mut raw_format = "tiff".to_owned();
+    let mut float_pipeline = false;
+    let mut keep_float = false;
+    let mut masked = false;
+    let mut mask_threshold = -1;
+    let mut crop = false;
+    let mut resize = "".to_owned();
+    let mut interpolation = "bilinear".to_owned();
+    let mut pad = false;
+    let mut orient = "".to_owned();
+    let mut augment_count = 0;
+    let mut augment_operations = augment::OPERATIONS.join(",");
+    let mut augment_seed = 0u64;
+    let mut balance_strategy = "none".to_owned();
+    let mut balance_seed = 0u64;
+    let mut manifest = "manifest.csv".to_owned();
+    let mut npy_dir = "".to_owned();
+    let mut container_path = "".to_owned();
+    let mut voi = "window".to_owned();
+    let mut diff_radius = 0;
     {
         let mut ap = ArgumentParser::new();
         ap.set_description("Pre-process images to demonstrate affinity analysis's usefulness in machine learning");
         ap.refer(&mut image_dir)
             .add_option(&["-i", "--images"], Store,
-            "Set the directory of input images (set to images/ in executable directory by default)");
+            "Set the directory or zip/tar archive of input images, searched recursively and skipping non-image files, with images in tar archives held in memory and DICOM images needing an uncompressed, deflated or RLE transfer syntax (set to images/ in executable directory by default)");
+        ap.refer(&mut include)
+            .add_option(&["--include"], Store,
+            "Set comma separated glob patterns an input must match to be read, matching the file name or, for patterns with a slash, the path inside the input directory (* and ? stay within a folder, ** crosses folders)");
+        ap.refer(&mut exclude)
+            .add_option(&["--exclude"], Store,
+            "Set comma separated glob patterns of inputs to skip, matched like --include");
+        ap.refer(&mut voi)
+            .add_option(&["--voi"], Store,
+            "Set how DICOM inputs are mapped to gray levels after their rescale slope and intercept (range to stretch the values in the image, window to use the window in the file or lut to use the VOI LUT in the file, each falling back to the one before; window by default)");
         ap.refer(&mut answers)
             .add_option(&["-a", "--answers"], Store,
             "Set the path of a CSV file with answers to classify the provided images");
@@ -135,13 +248,127 @@ fn main()
         ap.refer(&mut verbose)
             .add_option(&["-v", "--verbose"], StoreTrue,
             "Print verbose logging messages");
+        ap.refer(&mut no_progress)
+            .add_option(&["--no-progress"], StoreTrue,
+            "Disable the progress display (it is also disabled automatically when output is not a terminal)");
+        ap.refer(&mut pyramid_levels)
+            .add_option(&["-p", "--pyramid"], Store,
+            "Set the number of pyramid levels (including the native resolution) to run affinity, max diff and center diff on (disabled when less than 2; halving stops at 2 pixels and further levels repeat the smallest)");
+        ap.refer(&mut pyramid_kind)
+            .add_option(&["--pyramid-kernel"], Store,
+            "Set the downsampling kernel used between pyramid levels (gaussian or box, gaussian by default)");
+        ap.refer(&mut pyramid_stack)
+            .add_option(&["--pyramid-stack"], StoreTrue,
+            "Upsample every level back to native resolution and combine them into the channels of one image instead of writing per-level outputs (supports up to 3 levels)");
+        ap.refer(&mut directional)
+            .add_option(&["--directional"], Store,
+            "Set the pixel distance for directional affinity, which counts co-occurrences separately for horizontal, vertical and both diagonal offsets and writes one output per direction (disabled when 0)");
+        ap.refer(&mut stack)
+            .add_option(&["-s", "--stack"], Store,
+            "Set a comma separated list of filters (original, affinity, max_diff, center_diff, average) whose single channel responses get packed into the channels of one output image");
+        ap.refer(&mut stack_format)
+            .add_option(&["--stack-format"], Store,
+            "Set the file format of channel stacks (bmp for exactly 3 filters, or tiff and npy for any number, bmp by default)");
+        ap.refer(&mut blend)
+            .add_option(&["-b", "--blend"], Store,
+            "Set a comma separated list of filter:weight pairs (original, affinity, max_diff, center_diff, average; weight 1 by default) whose saturated outputs get blended into one image");
+        ap.refer(&mut blend_op)
+            .add_option(&["--blend-op"], Store,
+            "Set the blend operator (mean, max, min, product, difference or alpha, where the first filter is the alpha mask; mean by default)");
+        ap.refer(&mut glcm_maps)
+            .add_option(&["--glcm"], StoreTrue,
+            "Write per-pixel grey-level co-occurrence matrix (GLCM) texture feature maps");
+        ap.refer(&mut glcm_features)
+            .add_option(&["--glcm-features"], Store,
+            "Set a comma separated list of GLCM features to write maps for (contrast, correlation, energy, homogeneity and entropy by default)");
+        ap.refer(&mut glcm_levels)
+            .add_option(&["--glcm-levels"], Store,
+            "Set the number of gray levels pixels get quantised to before building co-occurrence matrices (8 by default)");
+        ap.refer(&mut glcm_distance)
+            .add_option(&["--glcm-distance"], Store,
+            "Set the pixel distance between co-occurring pairs, which are averaged over all four directions (1 by default)");
+        ap.refer(&mut glcm_window)
+            .add_option(&["--glcm-window"], Store,
+            "Set the radius of the window around each pixel used for GLCM feature maps (2 by default)");
+        ap.refer(&mut glcm_csv)
+            .add_option(&["--glcm-csv"], Store,
+            "Set the path of a CSV file to write per-image GLCM feature vectors to");
+        ap.refer(&mut features)
+            .add_option(&["-f", "--features"], Store,
+            "Set the path of a CSV file to write a fixed-length feature vector per image to, labelled with the answers file (moments and histograms of the original, affinity, max diff and center diff responses plus GLCM statistics; augmented and duplicated copies are left out so they cannot leak between evaluation folds; only CSV is written, columnar formats such as Parquet are not supported)");
+        ap.refer(&mut features_only)
+            .add_option(&["--features-only"], StoreTrue,
+            "Only extract feature vectors without writing any processed images (requires the features file to be set)");
+        ap.refer(&mut signed_outputs)
+            .add_option(&["--signed"], StoreTrue,
+            "Also write signed affinity, max diff and center diff outputs, where the sign tells which side of the difference a pixel lies on, along with separate magnitude and sign images");
+        ap.refer(&mut signed_type)
+            .add_option(&["--signed-type"], Store,
+            "Set the sample type of signed outputs (i16 or f32, i16 by default)");
+        ap.refer(&mut raw_format)
+            .add_option(&["--raw-format"], Store,
+            "Set the file format of outputs that do not fit in 8 bits (tiff or npy, tiff by default)");
+        ap.refer(&mut float_pipeline)
+            .add_option(&["--float"], StoreTrue,
+            "Compute the base, affinity, max diff, center diff and average outputs in floating point (including division by 16 and saturation), only rounding to 8 bits when saving");
+        ap.refer(&mut keep_float)
+            .add_option(&["--keep-float"], StoreTrue,
+            "Also write the unquantised floating point outputs to float_ prefixed directories in the raw format (implies --float)");
+        ap.refer(&mut diff_radius)
+            .add_option(&["--diff-radius"], Store,
+            "Set how far max diff and center diff look from each pixel, including their signed and floating point outputs, pyramid levels, stacks, blends and features, using squares of 2 x radius + 1 pixels (0 keeps the original 2x2 square of the pixel and those before it; 0 by default)");
+        ap.refer(&mut masked)
+            .add_option(&["-m", "--mask"], StoreTrue,
+            "Segment the breast as the largest bright region (dropping labels and markers), restrict filter windows to it, clear every output outside it, normalise saturated outputs and compute feature statistics only inside it, and write the mask");
+        ap.refer(&mut mask_threshold)
+            .add_option(&["--mask-threshold"], Store,
+            "Set the gray level pixels must exceed to be foreground (chosen per image with Otsu's method by default)");
+        ap.refer(&mut crop)
+            .add_option(&["--crop"], StoreTrue,
+            "Crop images to the bounding box of the foreground mask before filtering");
+        ap.refer(&mut resize)
+            .add_option(&["--resize"], Store,
+            "Resize images to WIDTHxHEIGHT (or a single number for a square) before filtering");
+        ap.refer(&mut interpolation)
+            .add_option(&["--interpolation"], Store,
+            "Set the interpolation used when resizing (nearest, bilinear, bicubic, gaussian or lanczos; bilinear by default)");
+        ap.refer(&mut pad)
+            .add_option(&["--pad"], StoreTrue,
+            "Preserve the aspect ratio when resizing by scaling images to fit and padding the rest with black");
+        ap.refer(&mut orient)
+            .add_option(&["--orient"], Store,
+            "Flip images left to right where needed so every breast lies against the same side (left or right)");
+        ap.refer(&mut augment_count)
+            .add_option(&["--augment"], Store,
+            "Set the number of augmented copies made of every image in the training split before filtering (requires a validation split)");
+        ap.refer(&mut augment_operations)
+            .add_option(&["--augment-ops"], Store,
+            "Set a comma separated list of augmentations applied to every copy (rotate, flip, scale, elastic and intensity by default)");
+        ap.refer(&mut augment_seed)
+            .add_option(&["--augment-seed"], Store,
+            "Set the seed augmentations are generated from (0 by default)");
+        ap.refer(&mut balance_strategy)
+            .add_option(&["--balance"], Store,
+            "Set how training categories are evened out (none, undersample whole source images with their copies, oversample with duplicates or augment to oversample with augmented copies; none by default, and not applied with --features-only)");
+        ap.refer(&mut balance_seed)
+            .add_option(&["--balance-seed"], Store,
+            "Set the seed images are picked from when balancing (0 by default)");
+        ap.refer(&mut manifest)
+            .add_option(&["--manifest"], Store,
+            "Set the path of the CSV file listing every output image with its source, split, category and how it was made when answers are provided (manifest.csv by default)");
+        ap.refer(&mut npy_dir)
+            .add_option(&["--npy"], Store,
+            "Set a directory to also export every output directory to as one N x H x W x C NumPy array, with the labels, file names and splits of the images in labels.npz (images must share a size, see --resize)");
+        ap.refer(&mut container_path)
+            .add_option(&["--container"], Store,
+            "Set the path of a single file to also store every output image in, compressed and indexed by image and output directory (read it back with the container subcommand)");
         ap.parse_args_or_exit();
     }
 
     // Process provided examples, if available
     let mut examples: HashMap<String, String> = HashMap::new();
     let mut categories: HashSet<String> = HashSet::new();
-    if answers.len() > 0
+    if !answers.is_empty()
     {
         let mut reader = ReaderBuilder::new().flexible(true).from_path(answers).unwrap();
 
@@ -201,8 +428,95 @@ fn main()
         panic!("Validation size set without any provided categorization");
     }
 
-    // Create directories to store images
-    for dir in &DIRS
+    // Validate pyramid settings before any directories get created
+    let pyramid_kind = Downsample::from_name(&pyramid_kind);
+    if pyramid_stack && pyramid_levels > 3
+    {
+        panic!("Pyramid stacking supports at most 3 levels since each level is stored in one color channel");
+    }
+
+    // Validate channel stack settings
+    let stack_format = StackFormat::from_name(&stack_format);
+    let stack = stack::parse_filters(&stack, stack_format);
+
+    // Validate blend settings
+    let blend_op = BlendOp::from_name(&blend_op);
+    let blend = blend::parse_inputs(&blend);
+    blend::check(&blend, blend_op);
+
+    // Validate GLCM settings
+    let glcm_features = glcm::parse_features(&glcm_features);
+    if !(2 ..= 256).contains(&glcm_levels)
+    {
+        panic!("GLCM levels must be between 2 and 256");
+    }
+
+    // Validate signed output settings
+    let signed_type = SampleType::from_name(&signed_type);
+    let raw_format = RawFormat::from_name(&raw_format);
+    let float_pipeline = float_pipeline || keep_float;
+    let keep_float = if keep_float { Some(raw_format) } else { None };
+
+    // Validate mask settings
+    if mask_threshold > 255
+    {
+        panic!("Mask threshold must be at most 255");
+    }
+    let mask_threshold = if mask_threshold < 0 { None } else { Some(mask_threshold as u8) };
+
+    // Validate preprocessing settings
+    let preprocess = Preprocess
+    {
+        crop,
+        size: preprocess::parse_size(&resize),
+        filter: preprocess::interpolation(&interpolation),
+        pad,
+        orient: if orient.is_empty() { None } else { Some(preprocess::Side::from_name(&orient)) },
+    };
+    if pad && preprocess.size.is_none()
+    {
+        panic!("Padding set without a target size");
+    }
+
+    // Validate augmentation settings
+    let augmentation = Augmentation { count: augment_count, operations: augment::parse_operations(&augment_operations), seed: augment_seed };
+    if augmentation.count > 0 && validation == 0
+    {
+        panic!("Augmentation set without a training split");
+    }
+
+    // Validate balancing settings
+    let balance_strategy = Strategy::from_name(&balance_strategy);
+    let voi = Voi::from_name(&voi);
+    if balance_strategy != Strategy::None && validation == 0
+    {
+        panic!("Balancing set without a training split");
+    }
+
+    if features_only && features.is_empty()
+    {
+        panic!("Feature only mode set without a features file");
+    }
+
+    // Create directories to store images (none are needed when only extracting features)
+    let mut dirs: Vec<String> = if features_only { Vec::new() } else { DIRS.iter().map(| dir | dir.to_string()).collect() };
+    if pyramid_levels > 1
+    {
+        dirs.extend(pyramid::dirs(pyramid_levels, pyramid_stack));
+    }
+    if directional > 0
+    {
+        dirs.extend(affinity::DIRECTIONS.iter().map(| (name, _, _) | OUTPUT_DIRECTIONAL_DIR.to_owned() + "_" + name));
+    }
+    if !stack.is_empty()
+    {
+        dirs.push(OUTPUT_STACK_DIR.to_owned());
+    }
+    if !blend.is_empty()
+    {
+        dirs.push(OUTPUT_BLEND_DIR.to_owned());
+    }
+    for dir in &dirs
     {
         let val = validation > 0;
         let d = "".to_owned() + dir + "/";
@@ -214,38 +528,371 @@ fn main()
         let d = "saturated_".to_owned() + dir + "_div16/";
         create_dir(&d, delete, val, &categories);
     }
-    println!("");
+    if masked && !features_only
+    {
+        create_dir(&(MASK_DIR.to_owned() + "/"), delete, validation > 0, &categories);
+    }
+    if keep_float.is_some() && !features_only
+    {
+        // Every variant of the outputs the floating point pipeline writes gets a float_ copy
+        for dir in DIRS.iter()
+        {
+            for d in [dir.to_string() + "/", "saturated_".to_owned() + dir + "/", dir.to_string() + "_div16/", "saturated_".to_owned() + dir + "_div16/"].iter()
+            {
+                create_dir(&("float_".to_owned() + d), delete, validation > 0, &categories);
+            }
+        }
+    }
+    if glcm_maps && !features_only
+    {
+        for index in &glcm_features
+        {
+            let d = OUTPUT_GLCM_DIR.to_owned() + "_" + glcm::FEATURES [*index];
+            create_dir(&(d.clone() + "/"), delete, validation > 0, &categories);
+            create_dir(&(d + "_div16/"), delete, validation > 0, &categories);
+        }
+    }
+    if signed_outputs && !features_only
+    {
+        // Signed outputs keep their full range so they have no saturated versions
+        for d in signed::dirs()
+        {
+            create_dir(&(d.clone() + "/"), delete, validation > 0, &categories);
+            create_dir(&(d + "_div16/"), delete, validation > 0, &categories);
+        }
+    }
+    println!();
+
+    // Open the per-image GLCM feature file with one column per feature
+    let mut glcm_writer = if glcm_csv.is_empty() { None } else { Some(Writer::from_path(&glcm_csv).expect("Failed to create GLCM feature file")) };
+    if let Some(writer) = glcm_writer.as_mut()
+    {
+        let mut header = vec!["image".to_owned(), "variant".to_owned()];
+        header.extend(glcm::FEATURES.iter().map(| feature | "glcm_".to_owned() + feature));
+        writer.write_record(&header).unwrap();
+    }
+
+    // Open the feature vector file with the split and label of each image ahead of its features
+    let mut features_writer = if features.is_empty() { None } else { Some(Writer::from_path(&features).expect("Failed to create feature file")) };
+    if let Some(writer) = features_writer.as_mut()
+    {
+        let mut header = vec!["image".to_owned(), "split".to_owned(), "label".to_owned()];
+        header.extend(features::names());
+        writer.write_record(&header).unwrap();
+    }
 
-    for entry in fs::read_dir(image_dir).expect("Image directory not found")
+    // Collect the inputs up front so the progress display knows the total
+    // Training images are queued once more for every augmented copy, unless only features are extracted
+    let inputs = inputs::list(&image_dir, &inputs::Filter::new(&include, &exclude));
+    let training = | name: &str | examples.get(name).and_then(| path | path.strip_prefix("training/")).map(| label | label.to_owned());
+    let mut names: HashSet<String> = HashSet::new();
+    let mut jobs: Vec<Job> = Vec::new();
+    for input in inputs
+    {
+        // Answers refer to inputs by file name alone, so it has to be unique across folders and archives
+        let name_in = input.name();
+        if !names.insert(name_in.clone())
+        {
+            panic!("Input {} has the same file name as another input", input.path);
+        }
+        jobs.push(Job { input: input.clone(), name: name_in.clone(), origin: Origin::Original });
+        if training(&name_in).is_some() && !features_only
+        {
+            jobs.extend((1 ..= augmentation.count).map(| copy | Job { input: input.clone(), name: name_in.clone(), origin: Origin::Augmented(copy) }));
+        }
+    }
+    println!("Found {} input images in {}", names.len(), image_dir);
+
+    // Balancing only ever changes the training split, and is left out when only features are extracted for evaluation
+    if balance_strategy != Strategy::None && !features_only
+    {
+        let before = balance::counts(&jobs, &training);
+        jobs = balance::balance(jobs, training, balance_strategy, augmentation.count, balance_seed);
+        let after = balance::counts(&jobs, &training);
+        for (label, count) in &before
+        {
+            println!("Balanced training category {} from {} to {} images", label, count, after.get(label).unwrap_or(&0));
+        }
+        println!();
+    }
+
+    // Outputs are named after the file name with a .bmp extension, so inputs differing only in their extension, or named like the
+    // copies of another input, would overwrite each other's outputs
+    let mut outputs: HashMap<String, &str> = HashMap::new();
+    for job in &jobs
+    {
+        let name_out = std::path::Path::new(&job.origin.name(&job.name)).with_extension("bmp").to_str().unwrap().to_owned();
+        if let Some(other) = outputs.insert(name_out.clone(), &job.input.path)
+        {
+            panic!("Inputs {} and {} would both be written as {}", other, job.input.path, name_out);
+        }
+    }
+
+    // Open the manifest listing how every output image was made
+    let mut manifest_writer = if examples.is_empty() || features_only { None } else { Some(Writer::from_path(&manifest).expect("Failed to create manifest")) };
+    if let Some(writer) = manifest_writer.as_mut()
+    {
+        writer.write_record(["image", "source", "split", "label", "copy", "balancing"]).unwrap();
+    }
+    // Arrays hold every image of a variant, so without a target size the images have to share theirs, which is checked before
+    // anything is written rather than when the first image of another size arrives
+    if !npy_dir.is_empty() && !features_only && preprocess.size.is_none()
     {
-        let entry = entry.unwrap();
-        let name_in = entry.file_name().into_string().unwrap();
-        let bmp = entry.path().with_extension("bmp");
+        if preprocess.crop
+        {
+            panic!("Cropped images differ in size, set a target size with --resize to export arrays");
+        }
+        let mut first: Option<(String, (u32, u32))> = None;
+        for job in jobs.iter().filter(| job | job.origin == Origin::Original)
+        {
+            let size = image::GenericImageView::dimensions(&job.input.open(voi));
+            match &first
+            {
+                Some((name, first)) if *first != size =>
+                    panic!("{} is {}x{} but {} is {}x{}, set a target size with --resize to export arrays", job.name, size.0, size.1, name, first.0, first.1),
+                Some(_) => (),
+                None => first = Some((job.name.clone(), size)),
+            }
+        }
+    }
+    let mut export = if (!npy_dir.is_empty() || !container_path.is_empty()) && !features_only
+    {
+        let set = | value: &&str | !value.is_empty();
+        Export::new(Some(npy_dir.as_str()).filter(set), Some(container_path.as_str()).filter(set), jobs.len())
+    }
+    else
+    {
+        Export::none()
+    };
+    let mut progress = Progress::new(jobs.len(), !no_progress, verbose);
+
+    for job in jobs
+    {
+        let origin = job.origin;
+        let name_in = job.name;
+        let name = origin.name(&name_in);
+        let bmp = std::path::Path::new(&name).with_extension("bmp");
         let name_out = bmp.file_name().unwrap().to_str().unwrap();
         if !examples.is_empty() { assert!(examples.contains_key(&name_in)); }
 
-        let mut original = image::open(entry.path()).unwrap().to_rgb();
-        original.save(output_dir(&BASE_DIR, &name_in, &examples) + name_out).unwrap();
-        println!("Name: {} | Dimensions: {:?}", name_in, original.dimensions());
-        analyze_affinity(&original, name_out, &output_dir(&OUTPUT_DIR, &name_in, &examples));
-        analyze_max_diff(&original, name_out, &output_dir(&OUTPUT_MAX_DIFF_DIR, &name_in, &examples));
-        analyze_center_diff(&original, name_out, &output_dir(&OUTPUT_CENTER_DIFF_DIR, &name_in, &examples));
-        let analyzed = image::open("saturated_".to_owned() + &output_dir(&OUTPUT_DIR, &name_in, &examples) + name_out).unwrap().to_rgb();
-        saturate(&mut original);
-        original.save("saturated_".to_owned() + &output_dir(&BASE_DIR, &name_in, &examples) + name_out).unwrap();
-        analyze_average(&original, &analyzed, name_out, &output_dir(&OUTPUT_AVERAGE_DIR, &name_in, &examples));
+        let original = job.input.open(voi).to_rgb();
+        log(&format!("Name: {} | Dimensions: {:?}", name, original.dimensions()));
+
+        // The mask is also found when only preprocessing needs it, but then nothing is cleared with it
+        let mask = if masked || preprocess.needs_mask()
+        {
+            progress.filter("mask");
+            Some(mask::segment(&original, mask_threshold))
+        }
+        else
+        {
+            None
+        };
+        progress.filter("preprocess");
+        let (prepared, mask) = preprocess.apply(original, mask);
+        let mask = if masked { mask } else { None };
+        let (prepared, mask) = match origin
+        {
+            Origin::Augmented(copy) =>
+            {
+                progress.filter("augment");
+                augmentation.apply(&prepared, mask.as_ref(), &name_in, copy)
+            }
+            _ => (prepared, mask),
+        };
+        // Filter windows only take in pixels inside the mask so the background does not show up as an edge along its boundary,
+        // and outputs are cleared outside it
+        let mut original = prepared.clone();
+        log(&format!("Preprocessed Dimensions: {:?}", original.dimensions()));
+
+        // Answers are stored as the category path, optionally behind the training or validation set
+        let (split, label) = match examples.get(&name_in)
+        {
+            Some(path) => match path.rfind('/')
+            {
+                Some(index) => (path [.. index].to_owned(), path [index + 1 ..].to_owned()),
+                None => ("".to_owned(), path.to_owned()),
+            },
+            None => ("".to_owned(), "".to_owned()),
+        };
+        // Copies are only made for training, and would put the same image on both sides of an evaluation split
+        if let Some(writer) = features_writer.as_mut().filter(| _ | origin == Origin::Original)
+        {
+            progress.filter("features");
+            let mut record = vec![name.clone(), split.clone(), label.clone()];
+            record.extend(features::extract(&original, diff_radius, glcm_levels, glcm_distance, mask.as_ref()).iter().map(| value | value.to_string()));
+            writer.write_record(&record).unwrap();
+        }
+        if features_only
+        {
+            progress.image_done();
+            continue;
+        }
+        if let Some(writer) = manifest_writer.as_mut()
+        {
+            // Copies beyond the augmented count given for every training image were added by balancing
+            let balancing = match origin
+            {
+                Origin::Original => false,
+                Origin::Duplicate(_) => true,
+                Origin::Augmented(copy) => copy > augmentation.count,
+            };
+            writer.write_record([name_out, &name_in, &split, &label, origin.kind(), if balancing { "yes" } else { "no" }]).unwrap();
+        }
+        export.image(name_out, &split, &label);
+        if let Some(mask) = &mask
+        {
+            export.save(mask, &(output_dir(MASK_DIR, &name_in, &examples) + name_out));
+        }
+        if float_pipeline
+        {
+            progress.filter("floating point");
+            analyze_float(&float::from_rgb(&original), diff_radius, BASE_DIR, OUTPUT_AVERAGE_DIR, name_out, keep_float, | dir | output_dir(dir, &name_in, &examples), mask.as_ref(), &mut export);
+        }
+        else
+        {
+            let mut base = original.clone();
+            mask::clear(&mut base, mask.as_ref());
+            export.save(&base, &(output_dir(BASE_DIR, &name_in, &examples) + name_out));
+            progress.filter("affinity");
+            analyze_affinity(&original, name_out, &output_dir(OUTPUT_DIR, &name_in, &examples), mask.as_ref(), &mut export);
+            progress.filter("max diff");
+            analyze_max_diff(&original, diff_radius, name_out, &output_dir(OUTPUT_MAX_DIFF_DIR, &name_in, &examples), mask.as_ref(), &mut export);
+            progress.filter("center diff");
+            analyze_center_diff(&original, diff_radius, name_out, &output_dir(OUTPUT_CENTER_DIFF_DIR, &name_in, &examples), mask.as_ref(), &mut export);
+        }
+        if signed_outputs
+        {
+            progress.filter("signed");
+            analyze_signed(&original, diff_radius, signed_type, raw_format, name_out, | dir | output_dir(dir, &name_in, &examples), mask.as_ref(), &mut export);
+        }
+        if pyramid_levels > 1
+        {
+            progress.filter("pyramid");
+            let levels = pyramid::build(&original, pyramid_levels, pyramid_kind);
+            analyze_pyramid(&levels, diff_radius, name_out, pyramid_stack, | dir | output_dir(dir, &name_in, &examples), mask.as_ref(), &mut export);
+        }
+        if directional > 0
+        {
+            progress.filter("directional affinity");
+            analyze_directional(&original, directional, name_out, | direction | output_dir(&(OUTPUT_DIRECTIONAL_DIR.to_owned() + "_" + direction), &name_in, &examples), mask.as_ref(), &mut export);
+        }
+        if !stack.is_empty()
+        {
+            progress.filter("stack");
+            analyze_stack(&original, diff_radius, &stack, stack_format, name_out, &output_dir(OUTPUT_STACK_DIR, &name_in, &examples), mask.as_ref(), &mut export);
+        }
+        if glcm_maps
+        {
+            progress.filter("glcm");
+            analyze_glcm(&original, glcm_levels, glcm_distance, glcm_window, &glcm_features, name_out, | feature | output_dir(&(OUTPUT_GLCM_DIR.to_owned() + "_" + feature), &name_in, &examples), mask.as_ref(), &mut export);
+        }
+        if let Some(writer) = glcm_writer.as_mut()
+        {
+            progress.filter("glcm features");
+            let mut record = vec![name.clone(), "original".to_owned()];
+            record.extend(glcm::image_features(&original, glcm_levels, glcm_distance, mask.as_ref()).iter().map(| value | value.to_string()));
+            writer.write_record(&record).unwrap();
+        }
+        if !blend.is_empty()
+        {
+            progress.filter("blend");
+            analyze_blend(&original, diff_radius, &blend, blend_op, name_out, &output_dir(OUTPUT_BLEND_DIR, &name_in, &examples), mask.as_ref(), &mut export);
+        }
+        if !float_pipeline
+        {
+            let analyzed = image::open("saturated_".to_owned() + &output_dir(OUTPUT_DIR, &name_in, &examples) + name_out).unwrap().to_rgb();
+            progress.filter("average");
+            mask::saturate_within(&mut original, mask.as_ref());
+            export.save(&original, &("saturated_".to_owned() + &output_dir(BASE_DIR, &name_in, &examples) + name_out));
+            analyze_average(&original, &analyzed, name_out, &output_dir(OUTPUT_AVERAGE_DIR, &name_in, &examples), mask.as_ref(), &mut export);
+        }
         
-        println!("\tDividing by 16:");
-        let mut original = image::open(entry.path()).unwrap().to_rgb();
+        log("\tDividing by 16:");
+        progress.filter("div16");
+        let mut original = prepared;
+        if float_pipeline
+        {
+            // The exact quotient is kept, the truncated copy below only feeds the other 8 bit outputs
+            progress.filter("floating point (div16)");
+            let mut exact = float::from_rgb(&original);
+            float::div16(&mut exact);
+            analyze_float(&exact, diff_radius, BASE_DIR, OUTPUT_AVERAGE_DIR, name_out, keep_float, | dir | output_dir(&(dir.to_owned() + "_div16/"), &name_in, &examples), mask.as_ref(), &mut export);
+        }
         div16(&mut original);
-        original.save(output_dir(&(BASE_DIR.to_owned() + "_div16/"), &name_in, &examples) + name_out).unwrap();
-        analyze_affinity(&original, name_out, &output_dir(&(OUTPUT_DIR.to_owned() + "_div16/"), &name_in, &examples));
-        analyze_max_diff(&original, name_out, &output_dir(&(OUTPUT_MAX_DIFF_DIR.to_owned() + "_div16/"), &name_in, &examples));
-        analyze_center_diff(&original, name_out, &output_dir(&(OUTPUT_CENTER_DIFF_DIR.to_owned() + "_div16/"), &name_in, &examples));
-        let analyzed = image::open("saturated_".to_owned() + &output_dir(&(OUTPUT_DIR.to_owned() + "_div16/"), &name_in, &examples) + name_out).unwrap().to_rgb();
-        saturate(&mut original);
-        original.save("saturated_".to_owned() + &output_dir(&(BASE_DIR.to_owned() + "_div16/"), &name_in, &examples) + name_out).unwrap();
-        analyze_average(&original, &analyzed, name_out, &output_dir(&(OUTPUT_AVERAGE_DIR.to_owned() + "_div16/"), &name_in, &examples));
-        println!("");
+        if !float_pipeline
+        {
+            progress.filter("affinity (div16)");
+            let mut base = original.clone();
+            mask::clear(&mut base, mask.as_ref());
+            export.save(&base, &(output_dir(&(BASE_DIR.to_owned() + "_div16/"), &name_in, &examples) + name_out));
+            analyze_affinity(&original, name_out, &output_dir(&(OUTPUT_DIR.to_owned() + "_div16/"), &name_in, &examples), mask.as_ref(), &mut export);
+            progress.filter("max diff (div16)");
+            analyze_max_diff(&original, diff_radius, name_out, &output_dir(&(OUTPUT_MAX_DIFF_DIR.to_owned() + "_div16/"), &name_in, &examples), mask.as_ref(), &mut export);
+            progress.filter("center diff (div16)");
+            analyze_center_diff(&original, diff_radius, name_out, &output_dir(&(OUTPUT_CENTER_DIFF_DIR.to_owned() + "_div16/"), &name_in, &examples), mask.as_ref(), &mut export);
+        }
+        if signed_outputs
+        {
+            progress.filter("signed (div16)");
+            analyze_signed(&original, diff_radius, signed_type, raw_format, name_out, | dir | output_dir(&(dir.to_owned() + "_div16/"), &name_in, &examples), mask.as_ref(), &mut export);
+        }
+        if pyramid_levels > 1
+        {
+            progress.filter("pyramid (div16)");
+            let levels = pyramid::build(&original, pyramid_levels, pyramid_kind);
+            analyze_pyramid(&levels, diff_radius, name_out, pyramid_stack, | dir | output_dir(&(dir.to_owned() + "_div16/"), &name_in, &examples), mask.as_ref(), &mut export);
+        }
+        if directional > 0
+        {
+            progress.filter("directional affinity (div16)");
+            analyze_directional(&original, directional, name_out, | direction | output_dir(&(OUTPUT_DIRECTIONAL_DIR.to_owned() + "_" + direction + "_div16/"), &name_in, &examples), mask.as_ref(), &mut export);
+        }
+        if !stack.is_empty()
+        {
+            progress.filter("stack (div16)");
+            analyze_stack(&original, diff_radius, &stack, stack_format, name_out, &output_dir(&(OUTPUT_STACK_DIR.to_owned() + "_div16/"), &name_in, &examples), mask.as_ref(), &mut export);
+        }
+        if glcm_maps
+        {
+            progress.filter("glcm (div16)");
+            analyze_glcm(&original, glcm_levels, glcm_distance, glcm_window, &glcm_features, name_out, | feature | output_dir(&(OUTPUT_GLCM_DIR.to_owned() + "_" + feature + "_div16/"), &name_in, &examples), mask.as_ref(), &mut export);
+        }
+        if let Some(writer) = glcm_writer.as_mut()
+        {
+            progress.filter("glcm features (div16)");
+            let mut record = vec![name.clone(), "div16".to_owned()];
+            record.extend(glcm::image_features(&original, glcm_levels, glcm_distance, mask.as_ref()).iter().map(| value | value.to_string()));
+            writer.write_record(&record).unwrap();
+        }
+        if !blend.is_empty()
+        {
+            progress.filter("blend (div16)");
+            analyze_blend(&original, diff_radius, &blend, blend_op, name_out, &output_dir(&(OUTPUT_BLEND_DIR.to_owned() + "_div16/"), &name_in, &examples), mask.as_ref(), &mut export);
+        }
+        if !float_pipeline
+        {
+            let analyzed = image::open("saturated_".to_owned() + &output_dir(&(OUTPUT_DIR.to_owned() + "_div16/"), &name_in, &examples) + name_out).unwrap().to_rgb();
+            progress.filter("average (div16)");
+            mask::saturate_within(&mut original, mask.as_ref());
+            export.save(&original, &("saturated_".to_owned() + &output_dir(&(BASE_DIR.to_owned() + "_div16/"), &name_in, &examples) + name_out));
+            analyze_average(&original, &analyzed, name_out, &output_dir(&(OUTPUT_AVERAGE_DIR.to_owned() + "_div16/"), &name_in, &examples), mask.as_ref(), &mut export);
+        }
+        log("");
+        progress.image_done();
+    }
+    progress.finish();
+    export.finish();
+    if let Some(mut writer) = manifest_writer
+    {
+        writer.flush().unwrap();
+    }
+    if let Some(mut writer) = glcm_writer
+    {
+        writer.flush().unwrap();
+    }
+    if let Some(mut writer) = features_writer
+    {
+        writer.flush().unwrap();
     }
 }
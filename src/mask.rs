@@ -0,0 +1,215 @@
+use crate::filters::luma;
+use crate::saturate::saturate;
+
+// Picks the threshold that maximises the between-class variance of the gray level histogram (Otsu's method)
+pub fn otsu(gray: &image::GrayImage) -> u8
+{
+    let mut histogram = [0usize; 256];
+    gray.pixels().for_each(| pixel | histogram [pixel [0] as usize] += 1);
+    let total = gray.pixels().len() as f64;
+    let sum: f64 = histogram.iter().enumerate().map(| (value, count) | value as f64 * *count as f64).sum();
+
+    let mut best = (0.0, 0);
+    let mut background = 0.0;
+    let mut background_sum = 0.0;
+    for (value, count) in histogram.iter().enumerate()
+    {
+        background += *count as f64;
+        background_sum += value as f64 * *count as f64;
+        let foreground = total - background;
+        if background == 0.0 || foreground == 0.0
+        {
+            continue;
+        }
+        let difference = background_sum / background - (sum - background_sum) / foreground;
+        let variance = background * foreground * difference * difference;
+        if variance > best.0
+        {
+            best = (variance, value as u8);
+        }
+    }
+    best.1
+}
+
+// Labels the 8-connected regions of set pixels, returning the label of every pixel (0 for unset) and the size of each label
+fn components(set: &[bool], width: u32, height: u32) -> (Vec<usize>, Vec<usize>)
+{
+    let mut labels = vec![0; set.len()];
+    let mut sizes = vec![0];
+    let mut stack = Vec::new();
+    for start in 0 .. set.len()
+    {
+        if !set [start] || labels [start] != 0
+        {
+            continue;
+        }
+
+        // Flood fill the region with a new label
+        let label = sizes.len();
+        sizes.push(0);
+        labels [start] = label;
+        stack.push(start);
+        while let Some(index) = stack.pop()
+        {
+            sizes [label] += 1;
+            let (x, y) = ((index as u32 % width) as i64, (index as u32 / width) as i64);
+            for dy in -1 ..= 1
+            {
+                for dx in -1 ..= 1
+                {
+                    let (nx, ny) = (x + dx, y + dy);
+                    if nx < 0 || ny < 0 || nx >= width as i64 || ny >= height as i64
+                    {
+                        continue;
+                    }
+                    let neighbour = (ny * width as i64 + nx) as usize;
+                    if set [neighbour] && labels [neighbour] == 0
+                    {
+                        labels [neighbour] = label;
+                        stack.push(neighbour);
+                    }
+                }
+            }
+        }
+    }
+    (labels, sizes)
+}
+
+// Segments the foreground of an image as 255 inside and 0 outside
+// Pixels above the threshold (Otsu's when none is given) are kept if they belong to the largest connected region,
+// which drops labels and markers lying apart from the breast, and holes inside that region are filled
+pub fn segment(img: &image::RgbImage, threshold: Option<u8>) -> image::GrayImage
+{
+    let gray = luma(img);
+    let (width, height) = gray.dimensions();
+    let threshold = threshold.unwrap_or_else(|| otsu(&gray));
+    let set: Vec<bool> = gray.pixels().map(| pixel | pixel [0] > threshold).collect();
+
+    let (labels, sizes) = components(&set, width, height);
+    let largest = (1 .. sizes.len()).max_by_key(| label | sizes [*label]).unwrap_or(0);
+    let mut inside: Vec<bool> = labels.iter().map(| label | largest != 0 && *label == largest).collect();
+
+    // Background regions that do not reach the border are holes
+    let outside: Vec<bool> = inside.iter().map(| inside | !inside).collect();
+    let (labels, sizes) = components(&outside, width, height);
+    let mut border = vec![false; sizes.len()];
+    for x in 0 .. width
+    {
+        border [labels [x as usize]] = true;
+        border [labels [((height - 1) * width + x) as usize]] = true;
+    }
+    for y in 0 .. height
+    {
+        border [labels [(y * width) as usize]] = true;
+        border [labels [(y * width + width - 1) as usize]] = true;
+    }
+    inside.iter_mut().zip(&labels).filter(| (_, label) | **label != 0 && !border [**label]).for_each(| (inside, _) | *inside = true);
+
+    image::ImageBuffer::from_fn(width, height, | x, y | image::Luma([if inside [(y * width + x) as usize] { 255 } else { 0 }]))
+}
+
+// Whether a pixel lies inside the mask
+pub fn contains(mask: &image::GrayImage, x: u32, y: u32) -> bool
+{
+    mask.get_pixel(x, y) [0] != 0
+}
+
+// Sets every pixel outside the mask to 0, whatever the sample type
+pub fn clear<P>(image: &mut image::ImageBuffer<P, Vec<P::Subpixel>>, mask: Option<&image::GrayImage>)
+    where P: image::Pixel + 'static, P::Subpixel: Default + 'static
+{
+    if let Some(mask) = mask
+    {
+        image.enumerate_pixels_mut().filter(| (x, y, _) | !contains(mask, *x, *y)).for_each(
+            | (_, _, pixel) | pixel.channels_mut().iter_mut().for_each(| value | *value = Default::default()));
+    }
+}
+
+// Scales the pixels inside the mask to fit the scale 0-255 using only their own range, leaving the outside at 0
+// Without a mask this is the same as saturate
+pub fn saturate_within<P>(image: &mut image::ImageBuffer<P, Vec<u8>>, mask: Option<&image::GrayImage>)
+    where P: image::Pixel<Subpixel = u8> + 'static
+{
+    let mask = match mask
+    {
+        Some(mask) => mask,
+        None => return saturate(image),
+    };
+    let values: Vec<u8> = image.enumerate_pixels().filter(| (x, y, _) | contains(mask, *x, *y)).flat_map(| (_, _, pixel) | pixel.channels().to_vec()).collect();
+    let min = values.iter().cloned().min().unwrap_or(0);
+    let max = values.iter().cloned().max().unwrap_or(0);
+    let scale = if max != min { 255.0 / (max - min) as f64 } else { 255.0 };
+    image.enumerate_pixels_mut().for_each(
+        | (x, y, pixel) |
+        {
+            let inside = contains(mask, x, y);
+            pixel.channels_mut().iter_mut().for_each(| value | *value = if inside { ((*value - min) as f64 * scale) as u8 } else { 0 });
+        }
+    );
+}
+
+// Gets the values of the pixels inside the mask, or every pixel without one
+pub fn values(gray: &image::GrayImage, mask: Option<&image::GrayImage>) -> Vec<u8>
+{
+    gray.enumerate_pixels().filter(| (x, y, _) | mask.is_none_or(| mask | contains(mask, *x, *y))).map(| (_, _, pixel) | pixel [0]).collect()
+}
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+
+    // A dark image with a bright blob holding a dark hole, and a small bright marker apart from it in the corner
+    fn scan() -> image::RgbImage
+    {
+        image::ImageBuffer::from_fn(32, 24,
+            | x, y |
+            {
+                let blob = (4 .. 20).contains(&x) && (4 .. 20).contains(&y);
+                let hole = (10 .. 13).contains(&x) && (10 .. 13).contains(&y);
+                let marker = (26 .. 30).contains(&x) && (2 .. 5).contains(&y);
+                let value = if marker { 240 } else if blob && !hole { 180 } else { 20 };
+                image::Rgb([value; 3])
+            }
+        )
+    }
+
+    #[test]
+    fn otsu_splits_the_two_levels()
+    {
+        let threshold = otsu(&luma(&scan()));
+        assert!((20 .. 180).contains(&threshold), "threshold {} does not separate the levels", threshold);
+    }
+
+    #[test]
+    fn segment_keeps_the_largest_region_and_fills_its_holes()
+    {
+        let mask = segment(&scan(), None);
+        for (x, y, pixel) in mask.enumerate_pixels()
+        {
+            let blob = (4 .. 20).contains(&x) && (4 .. 20).contains(&y);
+            assert_eq!(pixel [0], if blob { 255 } else { 0 }, "pixel ({}, {}) is misclassified", x, y);
+        }
+
+        // Above the blob's level only the marker is left, which then becomes the largest region
+        let mask = segment(&scan(), Some(200));
+        assert_eq!(values(&mask, None).iter().filter(| value | **value == 255).count(), 12);
+        assert!(contains(&mask, 27, 3));
+    }
+
+    #[test]
+    fn outputs_are_cleared_and_saturated_inside()
+    {
+        let mask = image::GrayImage::from_raw(4, 1, vec![0, 255, 255, 0]).unwrap();
+        let mut image = image::GrayImage::from_raw(4, 1, vec![200, 10, 30, 250]).unwrap();
+        assert_eq!(values(&image, Some(&mask)), vec![10, 30]);
+
+        let mut cleared = image.clone();
+        clear(&mut cleared, Some(&mask));
+        assert_eq!(cleared.into_raw(), vec![0, 10, 30, 0]);
+
+        // The range comes from the inside alone, so the bright outside pixels do not compress it
+        saturate_within(&mut image, Some(&mask));
+        assert_eq!(image.into_raw(), vec![0, 0, 255, 0]);
+    }
+}
@@ -1,59 +1,47 @@
-use crate::saturate::saturate;
+use crate::export::Export;
+use crate::kernels::{apply, window_min_max_within, Op};
+use crate::mask::{clear, saturate_within};
+use crate::progress::log;
 
 use std::time::Instant;
 
-// Sets every pixel to the largest difference in a 3x3 square around it
-pub fn analyze_max_diff(img: &image::RgbImage, entry: &str, output_dir: &str)
+// Sets every pixel to the largest difference in the 2x2 square of it and the pixels before it
+pub fn max_diff(img: &image::RgbImage) -> image::RgbImage
 {
-    // Setup image to be copied to and start counting time
-    let (width, height) = img.dimensions();
-    let mut image: image::RgbImage = image::ImageBuffer::new(width, height);
-    let now = Instant::now();
-    
-    image.enumerate_pixels_mut().for_each(
-        | (x, y, pixel) |
-        {
-            // Handle edge cases to allow keeping the image 1024x1024
-            let rl = if x > 0 { x - 1 } else { x };
-            let rr = if x < width - 1 { x + 1 } else { x };
-            let cl = if y > 0 { y - 1 } else { y };
-            let cr = if y < height - 1 { y + 1 } else { y };
+    max_diff_radius(img, 0)
+}
+
+// Sets every pixel to the largest difference in the window of the given radius around it (see window_min_max)
+pub fn max_diff_radius(img: &image::RgbImage, radius: u32) -> image::RgbImage
+{
+    max_diff_within(img, radius, None)
+}
 
-            let mut out = [0; 3];
-            for i in 0 .. 3
-            {
-                let mut min = 255;
-                let mut max = 0;
-                for r in rl .. rr
-                {
-                    for c in cl .. cr
-                    {
-                        let num = img.get_pixel(r, c) [i];
-                        if num < min
-                        {
-                            min = num;
-                        }
-                        if num > max
-                        {
-                            max = num;
-                        }
-                    }
-                }
-                out [i] = max - min;
-            }
-            
-            *pixel = image::Rgb(out);
-        }
-    );
+// Sets every pixel to the largest difference between the pixels of its window that lie inside the mask
+pub fn max_diff_within(img: &image::RgbImage, radius: u32, mask: Option<&image::GrayImage>) -> image::RgbImage
+{
+    // The largest difference in a window is between its largest and smallest values
+    let (min, mut image) = window_min_max_within(img, radius, mask);
+    apply(Op::Sub, &mut image, &min);
+    image
+}
+
+// Runs max diff analysis on the image and saves both the raw and saturated outputs
+pub fn analyze_max_diff(img: &image::RgbImage, radius: u32, entry: &str, output_dir: &str, mask: Option<&image::GrayImage>, export: &mut Export)
+{
+    // Start counting time and run the analysis
+    let now = Instant::now();
+    let mut image = max_diff_within(img, radius, mask);
+    clear(&mut image, mask);
 
     let elapsed = now.elapsed();
-    let sec = (elapsed.as_secs() as f64) + (elapsed.subsec_nanos() as f64 / 1000_000_000.0);
-    println!("Max Diff Analysis Completed in: {}", sec);
-    image.save(output_dir.to_owned() + entry).unwrap();
+    let sec = (elapsed.as_secs() as f64) + (elapsed.subsec_nanos() as f64 / 1_000_000_000.0);
+    log(&format!("Max Diff Analysis Completed in: {}", sec));
+    export.save(&image, &(output_dir.to_owned() + entry));
     let now = Instant::now();
-    saturate(&mut image);
+    saturate_within(&mut image, mask);
     let elapsed = now.elapsed();
-    let sec = (elapsed.as_secs() as f64) + (elapsed.subsec_nanos() as f64 / 1000_000_000.0);
-    println!("Output Saturated in: {}", sec);
-    image.save("saturated_".to_owned() + output_dir + entry).unwrap();
+    let sec = (elapsed.as_secs() as f64) + (elapsed.subsec_nanos() as f64 / 1_000_000_000.0);
+    log(&format!("Output Saturated in: {}", sec));
+    export.save(&image, &("saturated_".to_owned() + output_dir + entry));
 }
@@ -0,0 +1,195 @@
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use zip::write::SimpleFileOptions;
+use zip::{CompressionMethod, ZipWriter};
+
+// Array element types that can be stored in NumPy files
+pub trait Element: Copy
+{
+    // NumPy dtype description of the element
+    const DESCR: &'static str;
+
+    // Appends the element as little endian bytes
+    fn extend(&self, out: &mut Vec<u8>);
+}
+
+impl Element for u8
+{
+    const DESCR: &'static str = "|u1";
+
+    fn extend(&self, out: &mut Vec<u8>)
+    {
+        out.push(*self);
+    }
+}
+
+impl Element for i16
+{
+    const DESCR: &'static str = "<i2";
+
+    fn extend(&self, out: &mut Vec<u8>)
+    {
+        out.extend_from_slice(&self.to_le_bytes());
+    }
+}
+
+impl Element for i64
+{
+    const DESCR: &'static str = "<i8";
+
+    fn extend(&self, out: &mut Vec<u8>)
+    {
+        out.extend_from_slice(&self.to_le_bytes());
+    }
+}
+
+impl Element for f32
+{
+    const DESCR: &'static str = "<f4";
+
+    fn extend(&self, out: &mut Vec<u8>)
+    {
+        out.extend_from_slice(&self.to_le_bytes());
+    }
+}
+
+// Builds the NumPy format 1.0 header describing a C-ordered array of the given shape
+pub fn header<T: Element>(shape: &[usize]) -> Vec<u8>
+{
+    describe(T::DESCR, shape)
+}
+
+// Builds the NumPy format 1.0 header for any dtype description
+fn describe(descr: &str, shape: &[usize]) -> Vec<u8>
+{
+    let shape = match shape.len()
+    {
+        1 => format!("({},)", shape [0]),
+        _ => format!("({})", shape.iter().map(| dim | dim.to_string()).collect::<Vec<String>>().join(", ")),
+    };
+    let mut dict = format!("{{'descr': '{}', 'fortran_order': False, 'shape': {}, }}", descr, shape);
+
+    // The header is padded with spaces so the data starts on a 64 byte boundary
+    let unpadded = 10 + dict.len() + 1;
+    dict.push_str(&" ".repeat((64 - unpadded % 64) % 64));
+    dict.push('\n');
+
+    let mut out = b"\x93NUMPY\x01\x00".to_vec();
+    out.extend_from_slice(&(dict.len() as u16).to_le_bytes());
+    out.extend_from_slice(dict.as_bytes());
+    out
+}
+
+// Encodes an array of the given shape as the contents of a .npy file
+pub fn to_bytes<T: Element>(shape: &[usize], data: &[T]) -> Vec<u8>
+{
+    assert_eq!(shape.iter().product::<usize>(), data.len());
+    let mut bytes = header::<T>(shape);
+    data.iter().for_each(| element | element.extend(&mut bytes));
+    bytes
+}
+
+// Encodes a list of strings as the contents of a .npy file holding a fixed width unicode array
+pub fn strings(values: &[String]) -> Vec<u8>
+{
+    // Every string takes as many UCS-4 code points as the longest one, padded with zeros
+    let width = values.iter().map(| value | value.chars().count()).max().unwrap_or(0).max(1);
+    let mut bytes = describe(&format!("<U{}", width), &[values.len()]);
+    for value in values
+    {
+        let mut chars: Vec<u32> = value.chars().map(| c | c as u32).collect();
+        chars.resize(width, 0);
+        chars.iter().for_each(| c | bytes.extend_from_slice(&c.to_le_bytes()));
+    }
+    bytes
+}
+
+// Writes an array of the given shape to a .npy file
+pub fn write_npy<T: Element>(path: &str, shape: &[usize], data: &[T]) -> io::Result<()>
+{
+    let bytes = to_bytes(shape, data);
+    let mut out = BufWriter::new(File::create(path)?);
+    out.write_all(&bytes)?;
+    out.flush()
+}
+
+// Writes named .npy contents to a .npz file, which is an uncompressed zip archive like numpy.savez makes
+pub fn write_npz(path: &str, arrays: &[(&str, Vec<u8>)]) -> io::Result<()>
+{
+    // Entries are dated 1980-01-01, the earliest date zip archives can hold, so the same arrays give the same file
+    let options = SimpleFileOptions::DEFAULT.compression_method(CompressionMethod::Stored);
+    let mut zip = ZipWriter::new(BufWriter::new(File::create(path)?));
+    for (name, data) in arrays
+    {
+        zip.start_file(name.to_string() + ".npy", options)?;
+        zip.write_all(data)?;
+    }
+    zip.finish()?.flush()
+}
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+    use std::io::Read;
+
+    // Splits .npy contents into the header dictionary and the data after it
+    fn parse(bytes: &[u8]) -> (String, &[u8])
+    {
+        assert_eq!(&bytes [.. 8], b"\x93NUMPY\x01\x00");
+        let length = u16::from_le_bytes([bytes [8], bytes [9]]) as usize;
+        (String::from_utf8(bytes [10 .. 10 + length].to_vec()).unwrap(), &bytes [10 + length ..])
+    }
+
+    #[test]
+    fn headers_are_aligned_and_describe_the_array()
+    {
+        for shape in [vec![3, 48, 64, 3], vec![1, 1, 1, 1], vec![1000, 1024, 1024, 1], vec![7]]
+        {
+            let header = header::<u8>(&shape);
+            assert_eq!(header.len() % 64, 0);
+            let (dict, _) = parse(&header);
+            assert!(dict.ends_with('\n'));
+            assert!(dict.starts_with("{'descr': '|u1', 'fortran_order': False, 'shape': ("), "{}", dict);
+        }
+        let (dict, _) = parse(&header::<u8>(&[3, 48, 64, 3]));
+        assert!(dict.contains("'shape': (3, 48, 64, 3), "));
+        let (dict, _) = parse(&header::<f32>(&[7]));
+        assert!(dict.contains("'descr': '<f4'") && dict.contains("'shape': (7,), "));
+    }
+
+    #[test]
+    fn arrays_follow_their_header()
+    {
+        let bytes = to_bytes(&[2], &[1i64, -2]);
+        let (dict, data) = parse(&bytes);
+        assert!(dict.contains("'descr': '<i8'"));
+        assert_eq!(data, [1i64.to_le_bytes(), (-2i64).to_le_bytes()].concat());
+
+        let bytes = strings(&["ab".to_owned(), "c".to_owned()]);
+        let (dict, data) = parse(&bytes);
+        assert!(dict.contains("'descr': '<U2'") && dict.contains("'shape': (2,), "));
+        assert_eq!(data, [97u32, 98, 99, 0].iter().flat_map(| c | c.to_le_bytes()).collect::<Vec<u8>>());
+    }
+
+    #[test]
+    fn npz_files_are_zip_archives()
+    {
+        let path = std::env::temp_dir().join(format!("image_affinity_npz_{}.npz", std::process::id())).to_string_lossy().into_owned();
+        let labels = to_bytes(&[3], &[0i64, 1, -1]);
+        let names = strings(&["a".to_owned(), "b".to_owned(), "c".to_owned()]);
+        write_npz(&path, &[("labels", labels.clone()), ("filenames", names.clone())]).unwrap();
+
+        let mut zip = zip::ZipArchive::new(File::open(&path).unwrap()).unwrap();
+        assert_eq!(zip.len(), 2);
+        for (name, expected) in [("labels.npy", labels), ("filenames.npy", names)]
+        {
+            let mut file = zip.by_name(name).unwrap();
+            assert_eq!(file.compression(), CompressionMethod::Stored);
+            let mut bytes = Vec::new();
+            file.read_to_end(&mut bytes).unwrap();
+            assert_eq!(bytes, expected);
+        }
+        std::fs::remove_file(path).unwrap();
+    }
+}
@@ -0,0 +1,240 @@
+use crate::mask::contains;
+
+use image::imageops::{self, FilterType};
+
+// Sides of the image the breast can be turned to face
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Side
+{
+    Left,
+    Right,
+}
+
+impl Side
+{
+    pub fn from_name(name: &str) -> Side
+    {
+        match name
+        {
+            "left" => Side::Left,
+            "right" => Side::Right,
+            _ => panic!("Unknown side {} (expected left or right)", name),
+        }
+    }
+}
+
+// Gets the resampling filter for an interpolation name
+pub fn interpolation(name: &str) -> FilterType
+{
+    match name
+    {
+        "nearest" => FilterType::Nearest,
+        "bilinear" => FilterType::Triangle,
+        "bicubic" => FilterType::CatmullRom,
+        "gaussian" => FilterType::Gaussian,
+        "lanczos" => FilterType::Lanczos3,
+        _ => panic!("Unknown interpolation {} (expected nearest, bilinear, bicubic, gaussian or lanczos)", name),
+    }
+}
+
+// Parses a target size given as WIDTHxHEIGHT, or a single number for a square
+pub fn parse_size(size: &str) -> Option<(u32, u32)>
+{
+    if size.is_empty()
+    {
+        return None;
+    }
+    let values: Vec<u32> = size.split('x').map(| value | value.trim().parse::<u32>().expect("Invalid target size")).collect();
+    let size = match values.as_slice()
+    {
+        [side] => (*side, *side),
+        [width, height] => (*width, *height),
+        _ => panic!("Target size must be given as WIDTHxHEIGHT"),
+    };
+    if size.0 == 0 || size.1 == 0
+    {
+        panic!("Target size must be at least 1x1");
+    }
+    Some(size)
+}
+
+// Settings of the preprocessing stage run on every image before filtering
+pub struct Preprocess
+{
+    pub crop: bool,
+    pub size: Option<(u32, u32)>,
+    pub filter: FilterType,
+    pub pad: bool,
+    pub orient: Option<Side>,
+}
+
+impl Preprocess
+{
+    // Whether any step needs the foreground mask to find the breast
+    pub fn needs_mask(&self) -> bool
+    {
+        self.crop || self.orient.is_some()
+    }
+
+    // Flips, crops, resizes and pads the image in that order, transforming the mask along with it
+    pub fn apply(&self, img: image::RgbImage, mask: Option<image::GrayImage>) -> (image::RgbImage, Option<image::GrayImage>)
+    {
+        let (mut img, mut mask) = (img, mask);
+        if let (Some(target), Some(current)) = (self.orient, mask.as_ref())
+        {
+            if side(current) != target
+            {
+                img = imageops::flip_horizontal(&img);
+                mask = Some(imageops::flip_horizontal(current));
+            }
+        }
+        if self.crop
+        {
+            if let Some((x, y, width, height)) = mask.as_ref().and_then(bounds)
+            {
+                img = imageops::crop(&mut img, x, y, width, height).to_image();
+                mask = mask.map(| mut mask | imageops::crop(&mut mask, x, y, width, height).to_image());
+            }
+        }
+        if let Some(size) = self.size
+        {
+            // Masks are resampled with nearest neighbour so they stay binary
+            img = fit(&img, size, self.filter, self.pad, self.orient);
+            mask = mask.map(| mask | fit(&mask, size, FilterType::Nearest, self.pad, self.orient));
+        }
+        (img, mask)
+    }
+}
+
+// Finds the side of the image holding most of the mask
+fn side(mask: &image::GrayImage) -> Side
+{
+    let half = mask.width() / 2;
+    let (mut left, mut right) = (0usize, 0usize);
+    mask.enumerate_pixels().filter(| (x, y, _) | contains(mask, *x, *y)).for_each(
+        | (x, _, _) |
+        {
+            if x < half
+            {
+                left += 1;
+            }
+            else if x >= mask.width() - half
+            {
+                right += 1;
+            }
+        }
+    );
+    if right > left { Side::Right } else { Side::Left }
+}
+
+// Gets the bounding box of the mask as (x, y, width, height), or None when it is empty
+fn bounds(mask: &image::GrayImage) -> Option<(u32, u32, u32, u32)>
+{
+    let inside: Vec<(u32, u32)> = mask.enumerate_pixels().filter(| (x, y, _) | contains(mask, *x, *y)).map(| (x, y, _) | (x, y)).collect();
+    let left = inside.iter().map(| (x, _) | *x).min()?;
+    let right = inside.iter().map(| (x, _) | *x).max()?;
+    let top = inside.iter().map(| (_, y) | *y).min()?;
+    let bottom = inside.iter().map(| (_, y) | *y).max()?;
+    Some((left, top, right - left + 1, bottom - top + 1))
+}
+
+// Resizes an image to the target size, either stretching it or scaling it to fit and padding it with black
+// Padded images are centered, except horizontally when oriented so the breast stays against its side
+fn fit<P>(img: &image::ImageBuffer<P, Vec<u8>>, size: (u32, u32), filter: FilterType, pad: bool, side: Option<Side>) -> image::ImageBuffer<P, Vec<u8>>
+    where P: image::Pixel<Subpixel = u8> + 'static
+{
+    if !pad
+    {
+        return imageops::resize(img, size.0, size.1, filter);
+    }
+    let scale = (size.0 as f64 / img.width() as f64).min(size.1 as f64 / img.height() as f64);
+    let width = ((img.width() as f64 * scale).round() as u32).clamp(1, size.0);
+    let height = ((img.height() as f64 * scale).round() as u32).clamp(1, size.1);
+    let resized = imageops::resize(img, width, height, filter);
+    let mut padded = image::ImageBuffer::new(size.0, size.1);
+    let x = match side
+    {
+        Some(Side::Left) => 0,
+        Some(Side::Right) => size.0 - width,
+        None => (size.0 - width) / 2,
+    };
+    imageops::replace(&mut padded, &resized, x, (size.1 - height) / 2);
+    padded
+}
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+
+    // A 40x30 image with a bright 10x16 block at (24, 6), standing in for a breast on the right, and its mask
+    fn scan() -> (image::RgbImage, image::GrayImage)
+    {
+        let inside = | x: u32, y: u32 | (24 .. 34).contains(&x) && (6 .. 22).contains(&y);
+        let img = image::ImageBuffer::from_fn(40, 30, | x, y | image::Rgb([if inside(x, y) { 200 } else { 10 }; 3]));
+        let mask = image::ImageBuffer::from_fn(40, 30, | x, y | image::Luma([if inside(x, y) { 255 } else { 0 }]));
+        (img, mask)
+    }
+
+    fn settings(crop: bool, size: Option<(u32, u32)>, filter: FilterType, pad: bool, orient: Option<Side>) -> Preprocess
+    {
+        Preprocess { crop, size, filter, pad, orient }
+    }
+
+    #[test]
+    fn crops_to_the_mask_bounds()
+    {
+        let (img, mask) = scan();
+        assert_eq!(bounds(&mask), Some((24, 6, 10, 16)));
+        let (img, mask) = settings(true, None, FilterType::Nearest, false, None).apply(img, Some(mask));
+        assert_eq!(img.dimensions(), (10, 16));
+        assert!(img.pixels().all(| pixel | pixel [0] == 200));
+        assert!(mask.unwrap().pixels().all(| pixel | pixel [0] == 255));
+    }
+
+    #[test]
+    fn resizes_and_pads_to_the_target_size()
+    {
+        for pad in [false, true]
+        {
+            for filter in [FilterType::Nearest, FilterType::Triangle, FilterType::Lanczos3]
+            {
+                let (img, mask) = scan();
+                let (img, mask) = settings(false, Some((25, 50)), filter, pad, None).apply(img, Some(mask));
+                let mask = mask.unwrap();
+                assert_eq!(img.dimensions(), (25, 50));
+                assert_eq!(mask.dimensions(), (25, 50));
+                assert!(mask.pixels().all(| pixel | pixel [0] == 0 || pixel [0] == 255), "mask is not binary after {:?}", filter);
+            }
+        }
+
+        // Fitting 40x30 into 20x20 scales it to 20x15 with 2 and 3 black rows above and below
+        let (img, _) = scan();
+        let (img, _) = settings(false, Some((20, 20)), FilterType::Nearest, true, None).apply(img, None);
+        assert!((0 .. 20).all(| x | img.get_pixel(x, 1) [0] == 0 && img.get_pixel(x, 2) [0] == 10 && img.get_pixel(x, 17) [0] == 0));
+    }
+
+    #[test]
+    fn orients_the_breast_to_the_requested_side()
+    {
+        let (img, mask) = scan();
+        assert_eq!(side(&mask), Side::Right);
+        let (img, mask) = settings(false, None, FilterType::Nearest, false, Some(Side::Left)).apply(img, Some(mask));
+        assert_eq!(side(mask.as_ref().unwrap()), Side::Left);
+        assert_eq!(img.get_pixel(6, 10) [0], 200);
+
+        // Padding keeps an oriented image against its side
+        let (img, mask) = settings(true, Some((20, 8)), FilterType::Nearest, true, Some(Side::Left)).apply(img, mask);
+        assert_eq!(img.get_pixel(0, 4) [0], 200);
+        assert_eq!(img.get_pixel(19, 4) [0], 0);
+        assert!(contains(mask.as_ref().unwrap(), 0, 4));
+    }
+
+    #[test]
+    fn parses_target_sizes()
+    {
+        assert_eq!(parse_size(""), None);
+        assert_eq!(parse_size("64"), Some((64, 64)));
+        assert_eq!(parse_size("64x32"), Some((64, 32)));
+    }
+}
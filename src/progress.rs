@@ -0,0 +1,170 @@
+use std::io::{self, IsTerminal, Write};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Instant;
+
+// Whether a progress display is currently drawn on the terminal
+static ACTIVE: AtomicBool = AtomicBool::new(false);
+
+// Whether per-filter log lines are printed while the progress display is drawn
+static VERBOSE: AtomicBool = AtomicBool::new(false);
+
+// Whether log lines are dropped altogether, for callers such as benchmarks that run filters many times over
+static QUIET: AtomicBool = AtomicBool::new(false);
+
+// Turns every log line off or back on
+pub fn quiet(quiet: bool)
+{
+    QUIET.store(quiet, Ordering::Relaxed);
+}
+
+// Prints a log line, staying quiet while the progress display is active unless verbose logging was requested
+pub fn log(line: &str)
+{
+    if QUIET.load(Ordering::Relaxed)
+    {
+        return;
+    }
+    if !ACTIVE.load(Ordering::Relaxed)
+    {
+        println!("{}", line);
+    }
+    else if VERBOSE.load(Ordering::Relaxed)
+    {
+        // Clear the progress line first so the log line does not get appended to it
+        eprint!("\r\x1b[K");
+        println!("{}", line);
+    }
+}
+
+// Formats a number of seconds as hours, minutes and seconds
+fn format_duration(sec: f64) -> String
+{
+    let sec = sec.round() as u64;
+    if sec >= 3600
+    {
+        format!("{}h {:02}m {:02}s", sec / 3600, (sec % 3600) / 60, sec % 60)
+    }
+    else if sec >= 60
+    {
+        format!("{}m {:02}s", sec / 60, sec % 60)
+    }
+    else
+    {
+        format!("{}s", sec)
+    }
+}
+
+// Single line progress display showing images done, the current filter, throughput and ETA
+pub struct Progress
+{
+    total: usize,
+    done: usize,
+    filter: String,
+    start: Instant,
+    enabled: bool,
+}
+
+impl Progress
+{
+    // Creates a progress display for the given number of images
+    // It is drawn on stderr, and only when stdout is a terminal as well, so log lines redirected to a file are never dropped
+    pub fn new(total: usize, enabled: bool, verbose: bool) -> Progress
+    {
+        let enabled = enabled && io::stdout().is_terminal() && io::stderr().is_terminal();
+        ACTIVE.store(enabled, Ordering::Relaxed);
+        VERBOSE.store(verbose, Ordering::Relaxed);
+        Progress
+        {
+            total,
+            done: 0,
+            filter: "".to_owned(),
+            start: Instant::now(),
+            enabled,
+        }
+    }
+
+    // Sets the name of the filter currently being run
+    pub fn filter(&mut self, filter: &str)
+    {
+        self.filter = filter.to_owned();
+        self.draw();
+    }
+
+    // Marks another image as completely processed
+    pub fn image_done(&mut self)
+    {
+        self.done += 1;
+        self.draw();
+    }
+
+    // Moves past the progress line and restores normal logging
+    pub fn finish(&mut self)
+    {
+        if self.enabled
+        {
+            self.filter = "done".to_owned();
+            self.draw();
+            eprintln!();
+            self.enabled = false;
+        }
+        ACTIVE.store(false, Ordering::Relaxed);
+    }
+
+    fn draw(&self)
+    {
+        if !self.enabled
+        {
+            return;
+        }
+
+        let elapsed = self.start.elapsed();
+        let sec = (elapsed.as_secs() as f64) + (elapsed.subsec_nanos() as f64 / 1_000_000_000.0);
+        let percent = if self.total > 0 { 100.0 * self.done as f64 / self.total as f64 } else { 100.0 };
+
+        // Throughput and ETA are only meaningful once at least one image has finished
+        let (throughput, eta) = if self.done > 0 && sec > 0.0
+        {
+            let rate = self.done as f64 / sec;
+            let remaining = self.total.saturating_sub(self.done) as f64 / rate;
+            (format!("{:.2} img/s", rate), format_duration(remaining))
+        }
+        else
+        {
+            ("-- img/s".to_owned(), "--".to_owned())
+        };
+
+        let width = self.total.to_string().len();
+        eprint!("\r\x1b[K[{:>width$}/{}] {:5.1}% | {} | {} | elapsed {} | ETA {}",
+                self.done, self.total, percent, self.filter, throughput, format_duration(sec), eta, width = width);
+        io::stderr().flush().unwrap_or(());
+    }
+}
+
+impl Drop for Progress
+{
+    fn drop(&mut self)
+    {
+        self.finish();
+    }
+}
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+
+    #[test]
+    fn durations_switch_units_at_the_boundaries()
+    {
+        assert_eq!(format_duration(0.0), "0s");
+        assert_eq!(format_duration(59.0), "59s");
+        // Rounding happens before the unit is picked, so 59.6 seconds is already a minute
+        assert_eq!(format_duration(59.6), "1m 00s");
+        assert_eq!(format_duration(60.0), "1m 00s");
+        assert_eq!(format_duration(61.0), "1m 01s");
+        assert_eq!(format_duration(3599.0), "59m 59s");
+        assert_eq!(format_duration(3600.0), "1h 00m 00s");
+        assert_eq!(format_duration(3661.0), "1h 01m 01s");
+        assert_eq!(format_duration(90000.0), "25h 00m 00s");
+    }
+}
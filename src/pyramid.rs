@@ -0,0 +1,298 @@
+use crate::export::Export;
+use crate::affinity::affinity_within;
+use crate::max_diff::max_diff_within;
+use crate::center_diff::center_diff_within;
+use crate::mask::{clear, saturate_within};
+use crate::progress::log;
+
+use std::time::Instant;
+
+// Kernels used to shrink an image by half between pyramid levels
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Downsample
+{
+    Gaussian,
+    Box,
+}
+
+impl Downsample
+{
+    pub fn from_name(name: &str) -> Downsample
+    {
+        match name
+        {
+            "gaussian" => Downsample::Gaussian,
+            "box" => Downsample::Box,
+            _ => panic!("Unknown pyramid downsampling kernel {} (expected gaussian or box)", name),
+        }
+    }
+}
+
+// Blurs the image with a separable 5-tap binomial kernel and keeps every other pixel
+fn downsample_gaussian(img: &image::RgbImage) -> image::RgbImage
+{
+    const KERNEL: [u32; 5] = [1, 4, 6, 4, 1];
+    let (width, height) = img.dimensions();
+
+    // Blur horizontally into an intermediate buffer (edges are clamped)
+    let mut horizontal: Vec<u32> = vec![0; (width * height * 3) as usize];
+    for y in 0 .. height
+    {
+        for x in 0 .. width
+        {
+            for i in 0 .. 3
+            {
+                let mut sum = 0;
+                for (k, weight) in KERNEL.iter().enumerate()
+                {
+                    let r = (x as i64 + k as i64 - 2).max(0).min(width as i64 - 1) as u32;
+                    sum += weight * img.get_pixel(r, y) [i] as u32;
+                }
+                horizontal [((y * width + x) * 3 + i as u32) as usize] = sum;
+            }
+        }
+    }
+
+    // Blur vertically only at the pixels that survive decimation
+    let (w, h) = (width.div_ceil(2), height.div_ceil(2));
+    image::ImageBuffer::from_fn(w, h,
+        | x, y |
+        {
+            let mut out = [0; 3];
+            for (i, channel) in out.iter_mut().enumerate()
+            {
+                let mut sum = 0;
+                for (k, weight) in KERNEL.iter().enumerate()
+                {
+                    let c = (2 * y as i64 + k as i64 - 2).max(0).min(height as i64 - 1) as u32;
+                    sum += weight * horizontal [((c * width + 2 * x) * 3) as usize + i];
+                }
+                *channel = ((sum + 128) / 256) as u8;
+            }
+            image::Rgb(out)
+        }
+    )
+}
+
+// Averages every 2x2 block of pixels into one
+fn downsample_box(img: &image::RgbImage) -> image::RgbImage
+{
+    let (width, height) = img.dimensions();
+    let (w, h) = (width.div_ceil(2), height.div_ceil(2));
+    image::ImageBuffer::from_fn(w, h,
+        | x, y |
+        {
+            let mut out = [0; 3];
+            for (i, channel) in out.iter_mut().enumerate()
+            {
+                let mut sum = 0;
+                let mut count = 0;
+                for r in 2 * x .. (2 * x + 2).min(width)
+                {
+                    for c in 2 * y .. (2 * y + 2).min(height)
+                    {
+                        sum += img.get_pixel(r, c) [i] as u32;
+                        count += 1;
+                    }
+                }
+                *channel = ((sum + count / 2) / count) as u8;
+            }
+            image::Rgb(out)
+        }
+    )
+}
+
+// Builds an image pyramid where level 0 is the original and every following level is half the size
+// The affinity window needs at least a 2x2 image, so halving stops once a side is down to 2 pixels and the remaining
+// levels repeat the smallest one, keeping an output for every level of every image
+pub fn build(img: &image::RgbImage, levels: usize, kind: Downsample) -> Vec<image::RgbImage>
+{
+    let mut pyramid = vec![img.clone()];
+    for level in 1 .. levels
+    {
+        let previous = &pyramid [level - 1];
+        let (width, height) = previous.dimensions();
+        if width <= 2 || height <= 2
+        {
+            log(&format!("Image too small for {} pyramid levels, repeating the {}x{} level {} for the rest", levels, width, height, level - 1));
+            let smallest = previous.clone();
+            pyramid.resize(levels, smallest);
+            break;
+        }
+
+        let next = match kind
+        {
+            Downsample::Gaussian => downsample_gaussian(previous),
+            Downsample::Box => downsample_box(previous),
+        };
+        pyramid.push(next);
+    }
+    pyramid
+}
+
+// Resizes a coarse level back to the native resolution with bilinear interpolation
+pub fn upsample(img: &image::RgbImage, width: u32, height: u32) -> image::RgbImage
+{
+    image::imageops::resize(img, width, height, image::imageops::FilterType::Triangle)
+}
+
+// Packs the first channel of each level's response into the channels of one native resolution image
+fn stack(levels: &[image::RgbImage], width: u32, height: u32) -> image::RgbImage
+{
+    let upsampled: Vec<image::RgbImage> = levels.iter().map(| level | upsample(level, width, height)).collect();
+    image::ImageBuffer::from_fn(width, height,
+        | x, y |
+        {
+            let mut out = [0; 3];
+            for (i, level) in upsampled.iter().enumerate()
+            {
+                out [i] = level.get_pixel(x, y) [0];
+            }
+            image::Rgb(out)
+        }
+    )
+}
+
+// Names of the filters run at every pyramid level, paired with the output directory prefix they write to
+pub const FILTERS: [(&str, &str); 3] = [("Affinity", "output"), ("Max Diff", "output_max_diff"), ("Center Diff", "output_center_diff")];
+
+// Runs a filter by its pyramid name, looking only at pixels inside a mask of the level's size
+// Max diff and center diff look the given radius from each pixel of the level
+fn run_filter(name: &str, img: &image::RgbImage, diff_radius: u32, mask: Option<&image::GrayImage>) -> image::RgbImage
+{
+    match name
+    {
+        "Affinity" => affinity_within(img, mask),
+        "Max Diff" => max_diff_within(img, diff_radius, mask),
+        "Center Diff" => center_diff_within(img, diff_radius, mask),
+        _ => unreachable!(),
+    }
+}
+
+// Saves the raw and saturated versions of an output image, masked with a mask of the same size
+fn save(mut image: image::RgbImage, entry: &str, output_dir: &str, mask: Option<&image::GrayImage>, export: &mut Export)
+{
+    clear(&mut image, mask);
+    export.save(&image, &(output_dir.to_owned() + entry));
+    saturate_within(&mut image, mask);
+    export.save(&image, &("saturated_".to_owned() + output_dir + entry));
+}
+
+// Runs every filter on each pyramid level and writes either per-level outputs or upsampled stacks combined into channels
+// The mask has the size of level 0 and is shrunk with nearest neighbour sampling for the coarser levels
+// The output directory function maps a filter's directory prefix to the directory the image belongs in
+pub fn analyze_pyramid<F>(pyramid: &[image::RgbImage], diff_radius: u32, entry: &str, stacked: bool, output_dir: F, mask: Option<&image::GrayImage>, export: &mut Export)
+    where F: Fn(&str) -> String
+{
+    let (width, height) = pyramid [0].dimensions();
+    let masks: Vec<Option<image::GrayImage>> = pyramid.iter().map(
+        | level | mask.map(| mask | image::imageops::resize(mask, level.width(), level.height(), image::imageops::FilterType::Nearest))
+    ).collect();
+    for (name, prefix) in FILTERS.iter()
+    {
+        let now = Instant::now();
+        if stacked
+        {
+            // Level 0 is included so each channel holds one scale of the same response
+            let responses: Vec<image::RgbImage> = pyramid.iter().zip(&masks).map(| (level, mask) | run_filter(name, level, diff_radius, mask.as_ref())).collect();
+            save(stack(&responses, width, height), entry, &output_dir(&(prefix.to_string() + "_pyramid")), mask, export);
+        }
+        else
+        {
+            // Level 0 is already written by the native resolution analysis
+            for (level, img) in pyramid.iter().enumerate().skip(1)
+            {
+                save(run_filter(name, img, diff_radius, masks [level].as_ref()), entry, &output_dir(&format!("{}_level{}", prefix, level)), masks [level].as_ref(), export);
+            }
+        }
+        let elapsed = now.elapsed();
+        let sec = (elapsed.as_secs() as f64) + (elapsed.subsec_nanos() as f64 / 1_000_000_000.0);
+        log(&format!("{} Pyramid Analysis Completed in: {}", name, sec));
+    }
+}
+
+// Lists the directory prefixes the pyramid analysis writes to
+pub fn dirs(levels: usize, stacked: bool) -> Vec<String>
+{
+    let mut dirs = Vec::new();
+    for (_, prefix) in FILTERS.iter()
+    {
+        if stacked
+        {
+            dirs.push(prefix.to_string() + "_pyramid");
+        }
+        else
+        {
+            for level in 1 .. levels
+            {
+                dirs.push(format!("{}_level{}", prefix, level));
+            }
+        }
+    }
+    dirs
+}
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+
+    #[test]
+    fn levels_halve_rounding_up()
+    {
+        let img = image::RgbImage::new(13, 8);
+        for kind in [Downsample::Gaussian, Downsample::Box]
+        {
+            let sizes: Vec<(u32, u32)> = build(&img, 3, kind).iter().map(| level | level.dimensions()).collect();
+            assert_eq!(sizes, [(13, 8), (7, 4), (4, 2)]);
+        }
+    }
+
+    #[test]
+    fn small_images_repeat_the_smallest_level()
+    {
+        let img = image::ImageBuffer::from_fn(5, 9, | x, y | image::Rgb([(x * 40 + y) as u8; 3]));
+        let pyramid = build(&img, 5, Downsample::Box);
+        let sizes: Vec<(u32, u32)> = pyramid.iter().map(| level | level.dimensions()).collect();
+        assert_eq!(sizes, [(5, 9), (3, 5), (2, 3), (2, 3), (2, 3)]);
+        assert_eq!(pyramid [4].clone().into_raw(), pyramid [2].clone().into_raw());
+    }
+
+    #[test]
+    fn downsampling_keeps_constant_images()
+    {
+        let img = image::RgbImage::from_pixel(9, 6, image::Rgb([10, 128, 255]));
+        for kind in [Downsample::Gaussian, Downsample::Box]
+        {
+            for level in build(&img, 3, kind)
+            {
+                assert!(level.pixels().all(| pixel | *pixel == image::Rgb([10, 128, 255])), "{:?} changed a constant image", kind);
+            }
+        }
+    }
+
+    #[test]
+    fn box_averages_each_block()
+    {
+        let img = image::RgbImage::from_raw(3, 2, vec![0, 0, 0, 10, 10, 10, 100, 100, 100, 20, 20, 20, 30, 30, 30, 200, 200, 200]).unwrap();
+        let level = downsample_box(&img);
+        // The last column has no partner, so its block is the 1x2 pair alone
+        assert_eq!(level.clone().into_raw(), vec![15, 15, 15, 150, 150, 150]);
+    }
+
+    #[test]
+    fn stacked_levels_fill_one_channel_each()
+    {
+        // Levels already at the native size are not resampled, so each channel holds the first channel of its level exactly
+        let levels: Vec<image::RgbImage> = [10, 20, 30].iter().map(
+            | value | image::ImageBuffer::from_fn(8, 6, | x, y | image::Rgb([*value + (x + y) as u8, 99, 99]))).collect();
+        let stacked = stack(&levels, 8, 6);
+        assert_eq!(stacked.dimensions(), (8, 6));
+        assert!(stacked.enumerate_pixels().all(| (x, y, pixel) | *pixel == image::Rgb([10, 20, 30].map(| value | value + (x + y) as u8))));
+
+        // Coarser levels are stretched back over the whole image, where resizing can truncate a value by one
+        let levels: Vec<image::RgbImage> = [(8, 6), (4, 3), (2, 2)].iter().map(| (width, height) | image::RgbImage::from_pixel(*width, *height, image::Rgb([100, 0, 0]))).collect();
+        let stacked = stack(&levels, 8, 6);
+        assert!(stacked.pixels().all(| pixel | pixel.0.iter().all(| value | value.abs_diff(100) <= 1)));
+    }
+}
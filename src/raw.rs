@@ -0,0 +1,67 @@
+use crate::npy::{write_npy, Element};
+use crate::tiff::{self, write_tiff};
+
+// File formats for images whose samples do not fit in 8 bit BMPs
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum RawFormat
+{
+    Tiff,
+    Npy,
+}
+
+impl RawFormat
+{
+    pub fn from_name(name: &str) -> RawFormat
+    {
+        match name
+        {
+            "tiff" => RawFormat::Tiff,
+            "npy" => RawFormat::Npy,
+            _ => panic!("Unknown raw format {} (expected tiff or npy)", name),
+        }
+    }
+
+    pub fn extension(self) -> &'static str
+    {
+        match self
+        {
+            RawFormat::Tiff => "tiff",
+            RawFormat::Npy => "npy",
+        }
+    }
+}
+
+// Sample types that can be written to both TIFF and NumPy files
+pub trait Sample: Element
+{
+    const BITS: u16;
+    const SAMPLE_FORMAT: u16;
+}
+
+impl Sample for i16
+{
+    const BITS: u16 = 16;
+    const SAMPLE_FORMAT: u16 = tiff::SIGNED;
+}
+
+impl Sample for f32
+{
+    const BITS: u16 = 32;
+    const SAMPLE_FORMAT: u16 = tiff::FLOAT;
+}
+
+// Writes interleaved samples in row, column, channel order, replacing the extension of the path with the format's
+pub fn write_raw<T: Sample>(path: &str, width: u32, height: u32, channels: usize, data: &[T], format: RawFormat)
+{
+    let path = std::path::Path::new(path).with_extension(format.extension()).to_str().unwrap().to_owned();
+    match format
+    {
+        RawFormat::Tiff =>
+        {
+            let mut bytes = Vec::with_capacity(data.len() * (T::BITS / 8) as usize);
+            data.iter().for_each(| sample | sample.extend(&mut bytes));
+            write_tiff(&path, width, height, channels as u16, T::BITS, T::SAMPLE_FORMAT, &bytes).unwrap();
+        },
+        RawFormat::Npy => write_npy(&path, &[height as usize, width as usize, channels], data).unwrap(),
+    }
+}
@@ -0,0 +1,469 @@
+use crate::evaluate::mean_std;
+use crate::stats::{self, format_interval, format_test};
+
+use argparse::{ArgumentParser, Store, List};
+use csv::Writer;
+use std::collections::BTreeMap;
+use std::fs;
+use std::io;
+use std::path::Path;
+
+// Metrics logged by the retrain script at one training step
+#[derive(Default, Clone)]
+pub struct Step
+{
+    pub step: usize,
+    pub train_accuracy: Option<f64>,
+    pub cross_entropy: Option<f64>,
+    pub validation_accuracy: Option<f64>,
+    pub validation_n: Option<usize>,
+}
+
+// Everything parsed from one retrain log
+pub struct Run
+{
+    pub variant: String,
+    pub run: String,
+    pub steps: Vec<Step>,
+    pub test_accuracy: Option<f64>,
+    pub test_n: Option<usize>,
+    // File names of misclassified test images, when the log lists them
+    pub misclassified: Option<Vec<String>>,
+}
+
+// Parses a percentage followed by an optional (N=...) count, such as "64.7% (N=34)"
+fn parse_accuracy(text: &str) -> (Option<f64>, Option<usize>)
+{
+    let accuracy = text.split('%').next().and_then(| value | value.trim().parse::<f64>().ok()).map(| value | value / 100.0);
+    let n = text.find("(N=").and_then(| start | text [start + 3 ..].split(')').next()).and_then(| value | value.parse::<usize>().ok());
+    (accuracy, n)
+}
+
+// Parses a retrain log, only reading the INFO:tensorflow lines since absl repeats every message
+pub fn parse_log(text: &str, variant: &str, run: &str) -> Run
+{
+    let mut steps: BTreeMap<usize, Step> = BTreeMap::new();
+    let mut result = Run { variant: variant.to_owned(), run: run.to_owned(), steps: Vec::new(), test_accuracy: None, test_n: None, misclassified: None };
+    let mut listing = false;
+    for line in text.lines().filter(| line | line.starts_with("INFO:tensorflow:"))
+    {
+        // With --print_misclassified_test_images every misclassified image is listed as "<path>  <predicted label>"
+        if listing
+        {
+            let fields: Vec<&str> = line ["INFO:tensorflow:".len() ..].split_whitespace().collect();
+            if fields.len() == 2 && fields [0].contains('/')
+            {
+                let name = Path::new(fields [0]).file_name().unwrap().to_string_lossy().into_owned();
+                result.misclassified.get_or_insert_with(Vec::new).push(name);
+                continue;
+            }
+            listing = false;
+        }
+
+        if line.contains("=== MISCLASSIFIED TEST IMAGES ===")
+        {
+            listing = true;
+            result.misclassified = Some(Vec::new());
+        }
+        else if let Some(start) = line.find("Final test accuracy = ")
+        {
+            let (accuracy, n) = parse_accuracy(&line [start + 22 ..]);
+            result.test_accuracy = accuracy;
+            result.test_n = n;
+        }
+        else if let Some(start) = line.find("Step ")
+        {
+            // Step lines look like "<timestamp>: Step 3999: Train accuracy = 100.0%"
+            let rest = &line [start + 5 ..];
+            let colon = match rest.find(':') { Some(colon) => colon, None => continue };
+            let number = match rest [.. colon].trim().parse::<usize>() { Ok(number) => number, Err(_) => continue };
+            let metric = rest [colon + 1 ..].trim();
+            let step = steps.entry(number).or_insert_with(|| Step { step: number, ..Default::default() });
+            if let Some(value) = metric.strip_prefix("Train accuracy = ")
+            {
+                step.train_accuracy = parse_accuracy(value).0;
+            }
+            else if let Some(value) = metric.strip_prefix("Validation accuracy = ")
+            {
+                let (accuracy, n) = parse_accuracy(value);
+                step.validation_accuracy = accuracy;
+                step.validation_n = n;
+            }
+            else if let Some(value) = metric.strip_prefix("Cross entropy = ")
+            {
+                step.cross_entropy = value.trim().parse::<f64>().ok();
+            }
+        }
+    }
+    result.steps = steps.into_values().collect();
+    result
+}
+
+// Reads every .out log in the run directories, naming each variant after its log file
+pub fn read_runs(dirs: &[String]) -> Vec<Run>
+{
+    let mut runs = Vec::new();
+    for dir in dirs
+    {
+        let mut paths: Vec<_> = fs::read_dir(dir).unwrap_or_else(| _ | panic!("Log directory {} not found", dir))
+            .map(| entry | entry.unwrap().path())
+            .filter(| path | path.extension().is_some_and(| extension | extension == "out"))
+            .collect();
+        paths.sort();
+        for path in paths
+        {
+            let text = fs::read_to_string(&path).unwrap_or_else(| _ | panic!("Failed to read {}", path.display()));
+            let variant = path.file_stem().unwrap().to_str().unwrap().to_owned();
+            runs.push(parse_log(&text, &variant, dir));
+        }
+    }
+    runs
+}
+
+// Metrics of one variant across all of its runs
+pub struct Summary
+{
+    pub variant: String,
+    pub test_accuracies: Vec<f64>,
+    pub test_n: Vec<f64>,
+    pub train_accuracies: Vec<f64>,
+    pub validation_accuracies: Vec<f64>,
+    pub cross_entropies: Vec<f64>,
+}
+
+// Groups runs by variant, keeping the final test accuracy and the metrics of each run's last step
+pub fn summarize(runs: &[Run]) -> Vec<Summary>
+{
+    let mut summaries: BTreeMap<String, Summary> = BTreeMap::new();
+    for run in runs
+    {
+        let summary = summaries.entry(run.variant.clone()).or_insert_with(
+            || Summary { variant: run.variant.clone(), test_accuracies: Vec::new(), test_n: Vec::new(), train_accuracies: Vec::new(),
+                         validation_accuracies: Vec::new(), cross_entropies: Vec::new() });
+        if let Some(accuracy) = run.test_accuracy
+        {
+            summary.test_accuracies.push(accuracy);
+        }
+        if let Some(n) = run.test_n
+        {
+            summary.test_n.push(n as f64);
+        }
+
+        // Later steps may only log some metrics, so take the last value of each
+        if let Some(value) = run.steps.iter().rev().find_map(| step | step.train_accuracy) { summary.train_accuracies.push(value); }
+        if let Some(value) = run.steps.iter().rev().find_map(| step | step.validation_accuracy) { summary.validation_accuracies.push(value); }
+        if let Some(value) = run.steps.iter().rev().find_map(| step | step.cross_entropy) { summary.cross_entropies.push(value); }
+    }
+    summaries.into_values().collect()
+}
+
+// Formats the mean and standard deviation of a metric, or a dash when it was never logged
+// The standard deviation is also a dash for a single run, where it is undefined
+fn cell(values: &[f64], percent: bool) -> String
+{
+    if values.is_empty()
+    {
+        return "-".to_owned();
+    }
+    let (mean, std) = mean_std(values);
+    let std = if values.len() > 1 { Some(std) } else { None };
+    if percent
+    {
+        format!("{:.1}% ± {}", mean * 100.0, std.map_or("-".to_owned(), | std | format!("{:.1}", std * 100.0)))
+    }
+    else
+    {
+        format!("{:.3} ± {}", mean, std.map_or("-".to_owned(), | std | format!("{:.3}", std)))
+    }
+}
+
+// 95% confidence interval of the test accuracy, across runs when there are several and from the test set size otherwise
+fn test_interval(summary: &Summary) -> (f64, f64)
+{
+    match (summary.test_accuracies.len(), summary.test_n.first())
+    {
+        (0, _) => (f64::NAN, f64::NAN),
+        (1, Some(n)) => stats::wilson_interval(summary.test_accuracies [0], *n as usize, 0.95),
+        (1, None) => (f64::NAN, f64::NAN),
+        _ => stats::mean_interval(&summary.test_accuracies, 0.95),
+    }
+}
+
+// Builds the Markdown comparison table
+pub fn markdown(summaries: &[Summary]) -> String
+{
+    let mut out = String::new();
+    out.push_str("| Variant | Runs | Test accuracy | 95% CI | N | Train accuracy | Validation accuracy | Cross entropy |\n");
+    out.push_str("|---|---|---|---|---|---|---|---|\n");
+    for summary in summaries
+    {
+        let n = if summary.test_n.is_empty() { "-".to_owned() } else { format!("{:.0}", mean_std(&summary.test_n).0) };
+        out.push_str(&format!("| {} | {} | {} | {} | {} | {} | {} | {} |\n", summary.variant, summary.test_accuracies.len(),
+                              cell(&summary.test_accuracies, true), format_interval(test_interval(summary)), n,
+                              cell(&summary.train_accuracies, true), cell(&summary.validation_accuracies, true),
+                              cell(&summary.cross_entropies, false)));
+    }
+    out
+}
+
+// Builds a Markdown table testing every variant against the baseline on the runs both of them completed
+// Runs are paired by the directory they were read from
+pub fn comparisons(runs: &[Run], summaries: &[Summary], baseline: &str, seed: u64) -> String
+{
+    let mut out = format!("\nCompared with {}:\n\n", baseline);
+    out.push_str("| Variant | Paired runs | Difference | Bootstrap 95% CI | Paired t | Wilcoxon W | McNemar |\n");
+    out.push_str("|---|---|---|---|---|---|---|\n");
+    for summary in summaries.iter().filter(| summary | summary.variant != baseline)
+    {
+        let mut variant_accuracies = Vec::new();
+        let mut baseline_accuracies = Vec::new();
+        let (mut only_baseline, mut only_variant) = (0, 0);
+        let mut listed = false;
+        for run in runs.iter().filter(| run | run.variant == summary.variant)
+        {
+            let base = match runs.iter().find(| other | other.variant == baseline && other.run == run.run) { Some(base) => base, None => continue };
+            if let (Some(a), Some(b)) = (run.test_accuracy, base.test_accuracy)
+            {
+                variant_accuracies.push(a);
+                baseline_accuracies.push(b);
+            }
+
+            // McNemar's test needs both variants tested on the same images, which only holds when the test sets match in size
+            if let (Some(a), Some(b)) = (&run.misclassified, &base.misclassified)
+            {
+                if run.test_n.is_some() && run.test_n == base.test_n
+                {
+                    only_baseline += b.iter().filter(| name | !a.contains(name)).count();
+                    only_variant += a.iter().filter(| name | !b.contains(name)).count();
+                    listed = true;
+                }
+            }
+        }
+
+        let n = variant_accuracies.len();
+        let differences: Vec<f64> = variant_accuracies.iter().zip(&baseline_accuracies).map(| (a, b) | a - b).collect();
+        let difference = if n > 0 { format!("{:+.1}%", differences.iter().sum::<f64>() / n as f64 * 100.0) } else { "-".to_owned() };
+        let interval = if n > 1
+        {
+            stats::bootstrap_interval(n, | indices | indices.iter().map(| i | differences [*i]).sum::<f64>() / n as f64, 1000, 0.95, seed)
+        }
+        else
+        {
+            (f64::NAN, f64::NAN)
+        };
+        // Tests that cannot be run are shown as a dash
+        let untested = stats::Test { statistic: f64::NAN, p: f64::NAN };
+        let wilcoxon = if n > 0 { stats::wilcoxon(&variant_accuracies, &baseline_accuracies) } else { untested };
+        let mcnemar = if listed { stats::mcnemar(only_variant, only_baseline) } else { untested };
+        out.push_str(&format!("| {} | {} | {} | {} | {} | {} | {} |\n", summary.variant, n, difference, format_interval(interval),
+                              format_test(&stats::paired_t_test(&variant_accuracies, &baseline_accuracies)), format_test(&wilcoxon),
+                              format_test(&mcnemar)));
+    }
+    out
+}
+
+// Writes the comparison table as CSV with separate mean and standard deviation columns
+fn write_csv(path: &str, summaries: &[Summary])
+{
+    let mut writer = Writer::from_path(path).expect("Failed to create report CSV");
+    writer.write_record(["variant", "runs", "test_accuracy_mean", "test_accuracy_std", "test_accuracy_ci_low", "test_accuracy_ci_high", "test_n_mean", "train_accuracy_mean", "train_accuracy_std",
+                         "validation_accuracy_mean", "validation_accuracy_std", "cross_entropy_mean", "cross_entropy_std"]).unwrap();
+    for summary in summaries
+    {
+        let mut record = vec![summary.variant.clone(), summary.test_accuracies.len().to_string()];
+        for (index, values) in [&summary.test_accuracies, &summary.test_n, &summary.train_accuracies, &summary.validation_accuracies, &summary.cross_entropies].iter().enumerate()
+        {
+            let (mean, std) = if values.is_empty() { (f64::NAN, f64::NAN) } else { mean_std(values) };
+            let std = if values.len() > 1 { std } else { f64::NAN };
+            record.push(mean.to_string());
+            // The test set size only gets a mean column and the test accuracy is followed by its interval
+            if index != 1
+            {
+                record.push(std.to_string());
+            }
+            if index == 0
+            {
+                let (low, high) = test_interval(summary);
+                record.push(low.to_string());
+                record.push(high.to_string());
+            }
+        }
+        writer.write_record(&record).unwrap();
+    }
+    writer.flush().unwrap();
+}
+
+// Writes every logged step of every run for plotting training curves
+fn write_steps(path: &str, runs: &[Run])
+{
+    let mut writer = Writer::from_path(path).expect("Failed to create steps CSV");
+    writer.write_record(["variant", "run", "step", "train_accuracy", "cross_entropy", "validation_accuracy", "validation_n"]).unwrap();
+    let optional = | value: Option<f64> | value.map_or("".to_owned(), | value | value.to_string());
+    for run in runs
+    {
+        for step in &run.steps
+        {
+            writer.write_record(&[run.variant.clone(), run.run.clone(), step.step.to_string(), optional(step.train_accuracy),
+                                  optional(step.cross_entropy), optional(step.validation_accuracy),
+                                  step.validation_n.map_or("".to_owned(), | n | n.to_string())]).unwrap();
+        }
+    }
+    writer.flush().unwrap();
+}
+
+// Parses TensorFlow retrain logs and writes a comparison table across variants and runs
+pub fn run(args: Vec<String>)
+{
+    let mut dirs: Vec<String> = Vec::new();
+    let mut output = "".to_owned();
+    let mut csv = "".to_owned();
+    let mut steps = "".to_owned();
+    let mut baseline = "".to_owned();
+    let mut seed = 0u64;
+    {
+        let mut ap = ArgumentParser::new();
+        ap.set_description("Summarise TensorFlow retrain logs (<variant>.out) into a comparison table with mean and standard deviation across runs");
+        ap.refer(&mut dirs)
+            .add_argument("runs", List,
+            "Directories holding one run's logs each (tensorflow/ by default)");
+        ap.refer(&mut output)
+            .add_option(&["-o", "--output"], Store,
+            "Set the path of a Markdown file to write the table to (printed by default)");
+        ap.refer(&mut csv)
+            .add_option(&["-c", "--csv"], Store,
+            "Set the path of a CSV file to also write the table to");
+        ap.refer(&mut steps)
+            .add_option(&["-s", "--steps"], Store,
+            "Set the path of a CSV file to write every logged training step to");
+        ap.refer(&mut baseline)
+            .add_option(&["-b", "--baseline"], Store,
+            "Set the variant every other variant is tested against (base when present by default)");
+        ap.refer(&mut seed)
+            .add_option(&["--seed"], Store,
+            "Set the seed used for bootstrap intervals (0 by default)");
+        if let Err(code) = ap.parse(args, &mut io::stdout(), &mut io::stderr())
+        {
+            std::process::exit(code);
+        }
+    }
+    if dirs.is_empty()
+    {
+        dirs.push("tensorflow".to_owned());
+    }
+
+    let runs = read_runs(&dirs);
+    if runs.is_empty()
+    {
+        panic!("No .out logs found in {}", dirs.join(", "));
+    }
+    let summaries = summarize(&runs);
+    let mut table = markdown(&summaries);
+
+    // Significance tests are only run when there is a baseline to compare against
+    if baseline.is_empty() && summaries.iter().any(| summary | summary.variant == "base")
+    {
+        baseline = "base".to_owned();
+    }
+    if !baseline.is_empty()
+    {
+        if !summaries.iter().any(| summary | summary.variant == baseline)
+        {
+            panic!("Baseline variant {} has no logs", baseline);
+        }
+        table.push_str(&comparisons(&runs, &summaries, &baseline, seed));
+    }
+
+    if output.is_empty()
+    {
+        print!("{}", table);
+    }
+    else
+    {
+        fs::write(Path::new(&output), &table).expect("Failed to write report");
+        println!("Wrote report for {} variants to {}", summaries.len(), output);
+    }
+    if !csv.is_empty()
+    {
+        write_csv(&csv, &summaries);
+    }
+    if !steps.is_empty()
+    {
+        write_steps(&steps, &runs);
+    }
+}
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+
+    // The first and last steps of tensorflow/base.out, with absl's repeated lines and the test result
+    const BASE_OUT: &str = "\
+INFO:tensorflow:2020-02-05 00:04:26.968075: Step 0: Train accuracy = 52.0%
+I0205 00:04:26.968166 140682948385664 retrain.py:1082] 2020-02-05 00:04:26.968075: Step 0: Train accuracy = 52.0%
+INFO:tensorflow:2020-02-05 00:04:26.968394: Step 0: Cross entropy = 0.686348
+I0205 00:04:26.968418 140682948385664 retrain.py:1084] 2020-02-05 00:04:26.968394: Step 0: Cross entropy = 0.686348
+INFO:tensorflow:2020-02-05 00:04:28.882778: Step 0: Validation accuracy = 42.0% (N=100)
+I0205 00:04:28.882880 140682948385664 retrain.py:1100] 2020-02-05 00:04:28.882778: Step 0: Validation accuracy = 42.0% (N=100)
+INFO:tensorflow:2020-02-05 00:09:52.843177: Step 3999: Train accuracy = 100.0%
+I0205 00:09:52.843246 140682948385664 retrain.py:1082] 2020-02-05 00:09:52.843177: Step 3999: Train accuracy = 100.0%
+INFO:tensorflow:2020-02-05 00:09:52.843435: Step 3999: Cross entropy = 0.094390
+I0205 00:09:52.843454 140682948385664 retrain.py:1084] 2020-02-05 00:09:52.843435: Step 3999: Cross entropy = 0.094390
+INFO:tensorflow:2020-02-05 00:09:52.920760: Step 3999: Validation accuracy = 36.0% (N=100)
+I0205 00:09:52.920831 140682948385664 retrain.py:1100] 2020-02-05 00:09:52.920760: Step 3999: Validation accuracy = 36.0% (N=100)
+INFO:tensorflow:Final test accuracy = 64.7% (N=34)
+I0205 00:09:54.594749 140682948385664 retrain.py:1126] Final test accuracy = 64.7% (N=34)
+";
+
+    // What --print_misclassified_test_images adds after the test accuracy
+    const MISCLASSIFIED: &str = "\
+INFO:tensorflow:=== MISCLASSIFIED TEST IMAGES ===
+I0205 00:09:54.600000 140682948385664 retrain.py:1129] === MISCLASSIFIED TEST IMAGES ===
+INFO:tensorflow:                                      /data/base/Abnormal/mdb005.bmp  normal
+INFO:tensorflow:                                        /data/base/Normal/mdb012.bmp  abnormal
+INFO:tensorflow:Froze 2 variables.
+I0205 00:09:54.904160 140682948385664 graph_util_impl.py:311] Froze 2 variables.
+";
+
+    fn assert_close(actual: Option<f64>, expected: f64)
+    {
+        assert!(actual.is_some_and(| actual | (actual - expected).abs() < 1e-9), "{:?} is not {}", actual, expected);
+    }
+
+    #[test]
+    fn parses_steps_and_test_accuracy()
+    {
+        let run = parse_log(BASE_OUT, "base", "run1");
+        assert_eq!(run.steps.iter().map(| step | step.step).collect::<Vec<usize>>(), [0, 3999]);
+        let last = &run.steps [1];
+        assert_close(last.train_accuracy, 1.0);
+        assert_close(last.cross_entropy, 0.094390);
+        assert_close(last.validation_accuracy, 0.36);
+        assert_eq!(last.validation_n, Some(100));
+        assert_close(run.test_accuracy, 0.647);
+        assert_eq!(run.test_n, Some(34));
+        assert!(run.misclassified.is_none());
+    }
+
+    #[test]
+    fn lists_misclassified_file_names()
+    {
+        let run = parse_log(&(BASE_OUT.to_owned() + MISCLASSIFIED), "base", "run1");
+        assert_eq!(run.misclassified, Some(vec!["mdb005.bmp".to_owned(), "mdb012.bmp".to_owned()]));
+        assert_eq!(run.test_n, Some(34));
+    }
+
+    #[test]
+    fn summaries_keep_each_runs_last_values()
+    {
+        let other = BASE_OUT.replace("64.7% (N=34)", "50.0% (N=34)").replace("Validation accuracy = 36.0%", "Validation accuracy = 40.0%");
+        let runs = vec![parse_log(BASE_OUT, "base", "run1"), parse_log(&other, "base", "run2"), parse_log(BASE_OUT, "output", "run1")];
+        let summaries = summarize(&runs);
+        assert_eq!(summaries.iter().map(| summary | summary.variant.as_str()).collect::<Vec<&str>>(), ["base", "output"]);
+        assert_eq!(summaries [0].test_accuracies, [0.647, 0.5]);
+        assert_eq!(summaries [0].test_n, [34.0, 34.0]);
+        assert_eq!(summaries [0].validation_accuracies, [0.36, 0.4]);
+        assert_eq!(summaries [0].train_accuracies, [1.0, 1.0]);
+        assert_eq!(summaries [1].test_accuracies, [0.647]);
+    }
+}
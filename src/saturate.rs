@@ -1,5 +1,6 @@
 // Scales every pixel value in the image to fit the scale 0-255
-pub fn saturate(image: &mut image::RgbImage)
+pub fn saturate<P>(image: &mut image::ImageBuffer<P, Vec<u8>>)
+    where P: image::Pixel<Subpixel = u8> + 'static
 {
     // Finds the minimum and maximum pixel values in the image
     let mut min = 255;
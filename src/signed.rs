@@ -0,0 +1,206 @@
+use crate::export::Export;
+use crate::affinity::{get_frequencies, strongest_pair, window};
+use crate::kernels::window_min_max_within;
+use crate::mask::clear;
+use crate::raw::{write_raw, RawFormat};
+use crate::progress::log;
+
+use std::time::Instant;
+use crate::image::GenericImageView;
+
+// Image with signed 16 bit samples, wide enough for any difference of two 8 bit values
+pub type SignedImage = image::ImageBuffer<image::Rgb<i16>, Vec<i16>>;
+
+// Sample types signed outputs can be written with
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum SampleType
+{
+    I16,
+    F32,
+}
+
+impl SampleType
+{
+    pub fn from_name(name: &str) -> SampleType
+    {
+        match name
+        {
+            "i16" => SampleType::I16,
+            "f32" => SampleType::F32,
+            _ => panic!("Unknown signed sample type {} (expected i16 or f32)", name),
+        }
+    }
+}
+
+// Gives a difference the sign of the side of it the center pixel lies on, positive when it is at least as close to the brighter value
+fn oriented(center: u8, high: u8, low: u8) -> i16
+{
+    let difference = high as i16 - low as i16;
+    if 2 * center as i16 >= high as i16 + low as i16 { difference } else { -difference }
+}
+
+// Affinity analysis keeping the sign of the strongest pair relative to the center pixel, counting only pixels inside the mask
+pub fn signed_affinity(img: &image::RgbImage, mask: Option<&image::GrayImage>) -> SignedImage
+{
+    let (width, height) = img.dimensions();
+    let mut subimage = img.view(0, 0, 2, 2);
+    let mut image: SignedImage = image::ImageBuffer::new(width, height);
+    image.enumerate_pixels_mut().for_each(
+        | (x, y, pixel) |
+        {
+            let (xb, yb, wb, hb) = window(x, y, width, height);
+            subimage.change_bounds(xb, yb, wb, hb);
+            let (_, joint) = get_frequencies(&subimage, mask);
+
+            let mut out = [0; 3];
+            for (i, value) in out.iter_mut().enumerate()
+            {
+                if let Some(((l, r), _)) = strongest_pair(&joint [i])
+                {
+                    *value = oriented(img.get_pixel(x, y) [i], l, r);
+                }
+            }
+            *pixel = image::Rgb(out);
+        }
+    );
+    image
+}
+
+// Max diff analysis keeping the sign of the range relative to the center pixel, in the window of the given radius (see window_min_max)
+pub fn signed_max_diff(img: &image::RgbImage, radius: u32, mask: Option<&image::GrayImage>) -> SignedImage
+{
+    // Uses the same window as max diff so magnitudes match its output
+    let (min, max) = window_min_max_within(img, radius, mask);
+    image::ImageBuffer::from_fn(img.width(), img.height(),
+        | x, y |
+        {
+            let (center, min, max) = (img.get_pixel(x, y), min.get_pixel(x, y), max.get_pixel(x, y));
+            // A window with no pixels inside the mask has its minimum above its maximum and no range
+            image::Rgb([0, 1, 2].map(| i | if max [i] < min [i] { 0 } else { oriented(center [i], max [i], min [i]) }))
+        }
+    )
+}
+
+// Center diff analysis keeping the sign, positive when the center pixel is brighter than the neighbour it differs most from
+// Uses the same window as center diff so magnitudes match its output, with the brighter side winning ties
+pub fn signed_center_diff(img: &image::RgbImage, radius: u32, mask: Option<&image::GrayImage>) -> SignedImage
+{
+    // The neighbour differing most from the center is either the smallest or the largest value in the window
+    let (min, max) = window_min_max_within(img, radius, mask);
+    image::ImageBuffer::from_fn(img.width(), img.height(),
+        | x, y |
+        {
+            let (center, min, max) = (img.get_pixel(x, y), min.get_pixel(x, y), max.get_pixel(x, y));
+            image::Rgb([0, 1, 2].map(
+                | i |
+                {
+                    let (above, below) = ((center [i] as i16 - min [i] as i16).max(0), (max [i] as i16 - center [i] as i16).max(0));
+                    if above >= below { above } else { -below }
+                }
+            ))
+        }
+    )
+}
+
+// Gets the absolute value of every sample
+pub fn magnitude(img: &SignedImage) -> image::RgbImage
+{
+    image::ImageBuffer::from_fn(img.width(), img.height(),
+        | x, y | image::Rgb(img.get_pixel(x, y).0.map(| value | value.unsigned_abs().min(255) as u8)))
+}
+
+// Gets the sign of every sample as 0 for negative, 128 for zero and 255 for positive
+pub fn sign(img: &SignedImage) -> image::RgbImage
+{
+    image::ImageBuffer::from_fn(img.width(), img.height(),
+        | x, y | image::Rgb(img.get_pixel(x, y).0.map(| value | match value.signum() { -1 => 0, 0 => 128, _ => 255 })))
+}
+
+// Filters with signed outputs as (name, output directory prefix)
+pub const FILTERS: [(&str, &str); 3] = [("Affinity", "output"), ("Max Diff", "output_max_diff"), ("Center Diff", "output_center_diff")];
+
+// Runs a signed filter by its name in FILTERS, with max diff and center diff looking the given radius from each pixel
+fn run_filter(name: &str, img: &image::RgbImage, radius: u32, mask: Option<&image::GrayImage>) -> SignedImage
+{
+    match name
+    {
+        "Affinity" => signed_affinity(img, mask),
+        "Max Diff" => signed_max_diff(img, radius, mask),
+        "Center Diff" => signed_center_diff(img, radius, mask),
+        _ => panic!("Unknown signed filter {}", name),
+    }
+}
+
+// Runs every signed filter and saves the signed output along with separate magnitude and sign images
+// Samples outside the mask are cleared to 0, which the sign image shows as 128
+// The output directory function maps a directory name from dirs to the directory the image belongs in
+#[allow(clippy::too_many_arguments)]
+pub fn analyze_signed<F>(img: &image::RgbImage, radius: u32, sample_type: SampleType, format: RawFormat, entry: &str, output_dir: F, mask: Option<&image::GrayImage>, export: &mut Export)
+    where F: Fn(&str) -> String
+{
+    for (name, prefix) in FILTERS.iter()
+    {
+        let now = Instant::now();
+        let mut image = run_filter(name, img, radius, mask);
+        clear(&mut image, mask);
+        let elapsed = now.elapsed();
+        let sec = (elapsed.as_secs() as f64) + (elapsed.subsec_nanos() as f64 / 1_000_000_000.0);
+        log(&format!("Signed {} Analysis Completed in: {}", name, sec));
+
+        let (width, height) = image.dimensions();
+        let path = output_dir(&(prefix.to_string() + "_signed")) + entry;
+        match sample_type
+        {
+            SampleType::I16 => write_raw(&path, width, height, 3, &image, format),
+            SampleType::F32 => write_raw(&path, width, height, 3, &image.iter().map(| value | *value as f32).collect::<Vec<f32>>(), format),
+        }
+        export.save(&magnitude(&image), &(output_dir(&(prefix.to_string() + "_magnitude")) + entry));
+        export.save(&sign(&image), &(output_dir(&(prefix.to_string() + "_sign")) + entry));
+    }
+}
+
+// Lists the directories signed outputs are written to
+pub fn dirs() -> Vec<String>
+{
+    FILTERS.iter().flat_map(| (_, prefix) | ["_signed", "_magnitude", "_sign"].iter().map(move | kind | prefix.to_string() + kind)).collect()
+}
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+    use crate::center_diff::center_diff_radius;
+    use crate::max_diff::max_diff_radius;
+
+    fn gradient(width: u32, height: u32) -> image::RgbImage
+    {
+        image::ImageBuffer::from_fn(width, height, | x, y | image::Rgb([(x * 37 + y * 11) as u8, (x * y * 7) as u8, ((x ^ y) * 29) as u8]))
+    }
+
+    #[test]
+    fn magnitudes_match_the_unsigned_filters()
+    {
+        for (width, height) in [(1, 1), (1, 5), (9, 7)]
+        {
+            let img = gradient(width, height);
+            for radius in [0, 1, 3]
+            {
+                assert!(magnitude(&signed_max_diff(&img, radius, None)).into_raw() == max_diff_radius(&img, radius).into_raw(), "max diff differs at radius {}", radius);
+                assert!(magnitude(&signed_center_diff(&img, radius, None)).into_raw() == center_diff_radius(&img, radius).into_raw(), "center diff differs at radius {}", radius);
+            }
+        }
+    }
+
+    #[test]
+    fn signs_follow_the_center()
+    {
+        let img = image::RgbImage::from_raw(3, 1, vec![10, 10, 10, 200, 200, 200, 50, 50, 50]).unwrap();
+        let center = signed_center_diff(&img, 1, None);
+        assert_eq!(center.get_pixel(0, 0) [0], -190);
+        assert_eq!(center.get_pixel(1, 0) [0], 190);
+        assert_eq!(center.get_pixel(2, 0) [0], -150);
+        let range = signed_max_diff(&img, 1, None);
+        assert_eq!(range.get_pixel(1, 0) [0], 190);
+        assert_eq!(range.get_pixel(2, 0) [0], -150);
+    }
+}
@@ -0,0 +1,148 @@
+use crate::export::Export;
+use crate::filters;
+use crate::npy::write_npy;
+use crate::mask::{clear, saturate_within};
+use crate::tiff::{self, write_tiff};
+use crate::progress::log;
+
+use std::time::Instant;
+
+// File formats a channel stack can be written as
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum StackFormat
+{
+    Bmp,
+    Tiff,
+    Npy,
+}
+
+impl StackFormat
+{
+    pub fn from_name(name: &str) -> StackFormat
+    {
+        match name
+        {
+            "bmp" => StackFormat::Bmp,
+            "tiff" => StackFormat::Tiff,
+            "npy" => StackFormat::Npy,
+            _ => panic!("Unknown stack format {} (expected bmp, tiff or npy)", name),
+        }
+    }
+
+    pub fn extension(self) -> &'static str
+    {
+        match self
+        {
+            StackFormat::Bmp => "bmp",
+            StackFormat::Tiff => "tiff",
+            StackFormat::Npy => "npy",
+        }
+    }
+}
+
+// Parses a comma separated list of filter names, checking each one exists and fits the output format
+pub fn parse_filters(list: &str, format: StackFormat) -> Vec<String>
+{
+    let names: Vec<String> = list.split(',').map(| name | name.trim().to_owned()).filter(| name | !name.is_empty()).collect();
+    names.iter().for_each(| name | filters::check(name));
+    if format == StackFormat::Bmp && !names.is_empty() && names.len() != 3
+    {
+        panic!("BMP stacks need exactly 3 filters (one per color channel), use tiff or npy for {}", names.len());
+    }
+    names
+}
+
+// Computes the single channel response of a filter by name, with max diff and center diff using the given radius
+pub fn response(name: &str, img: &image::RgbImage, diff_radius: u32, mask: Option<&image::GrayImage>) -> image::GrayImage
+{
+    filters::luma(&filters::run(name, img, diff_radius, mask))
+}
+
+// Interleaves single channel images into row, column, channel order
+pub fn interleave(channels: &[image::GrayImage]) -> Vec<u8>
+{
+    let raw: Vec<&[u8]> = channels.iter().map(| channel | &**channel).collect();
+    let mut data = Vec::with_capacity(raw.len() * raw [0].len());
+    for i in 0 .. raw [0].len()
+    {
+        for channel in &raw
+        {
+            data.push(channel [i]);
+        }
+    }
+    data
+}
+
+// Writes single channel images as one multi-channel image in the given format
+pub fn write_stack(channels: &[image::GrayImage], format: StackFormat, path: &str, export: &mut Export)
+{
+    let (width, height) = channels [0].dimensions();
+    let data = interleave(channels);
+    match format
+    {
+        StackFormat::Bmp =>
+        {
+            let image: image::RgbImage = image::ImageBuffer::from_raw(width, height, data).unwrap();
+            export.save(&image, path);
+        },
+        StackFormat::Tiff =>
+        {
+            write_tiff(path, width, height, channels.len() as u16, 8, tiff::UNSIGNED, &data).unwrap();
+            export.add(path, width, height, channels.len(), &data);
+        },
+        StackFormat::Npy =>
+        {
+            write_npy(path, &[height as usize, width as usize, channels.len()], &data).unwrap();
+            export.add(path, width, height, channels.len(), &data);
+        },
+    }
+}
+
+// Packs the responses of the named filters into the channels of one image and saves the raw and saturated stacks
+// Channels of the saturated stack are saturated separately so each filter uses the full range
+#[allow(clippy::too_many_arguments)]
+pub fn analyze_stack(img: &image::RgbImage, diff_radius: u32, names: &[String], format: StackFormat, entry: &str, output_dir: &str, mask: Option<&image::GrayImage>, export: &mut Export)
+{
+    let now = Instant::now();
+    let mut channels: Vec<image::GrayImage> = names.iter().map(| name | response(name, img, diff_radius, mask)).collect();
+    let elapsed = now.elapsed();
+    let sec = (elapsed.as_secs() as f64) + (elapsed.subsec_nanos() as f64 / 1_000_000_000.0);
+    log(&format!("Stack Analysis Completed in: {}", sec));
+
+    let name = std::path::Path::new(entry).with_extension(format.extension()).to_str().unwrap().to_owned();
+    channels.iter_mut().for_each(| channel | clear(channel, mask));
+    write_stack(&channels, format, &(output_dir.to_owned() + &name), export);
+    channels.iter_mut().for_each(| channel | saturate_within(channel, mask));
+    write_stack(&channels, format, &("saturated_".to_owned() + output_dir + &name), export);
+}
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+
+    #[test]
+    fn channels_are_interleaved_per_pixel()
+    {
+        let channels: Vec<image::GrayImage> = [0u8, 100].iter().map(| base | image::ImageBuffer::from_fn(3, 2, | x, y | image::Luma([base + (y * 3 + x) as u8]))).collect();
+        assert_eq!(interleave(&channels), vec![0, 100, 1, 101, 2, 102, 3, 103, 4, 104, 5, 105]);
+
+        // The TIFF strip right after the header holds the interleaved samples
+        let path = std::env::temp_dir().join(format!("image_affinity_stack_{}.tiff", std::process::id())).to_string_lossy().into_owned();
+        write_stack(&channels, StackFormat::Tiff, &path, &mut Export::none());
+        let bytes = std::fs::read(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(&bytes [8 .. 20], &interleave(&channels) [..]);
+    }
+
+    #[test]
+    fn stacks_hold_one_channel_per_filter()
+    {
+        let names = parse_filters("original, max_diff,,center_diff", StackFormat::Tiff);
+        assert_eq!(names, ["original", "max_diff", "center_diff"]);
+
+        let img = image::RgbImage::from_raw(2, 1, vec![10, 10, 10, 50, 50, 50]).unwrap();
+        assert_eq!(response("original", &img, 0, None).into_raw(), vec![10, 50]);
+        assert_eq!(response("max_diff", &img, 1, None).into_raw(), vec![40, 40]);
+    }
+}
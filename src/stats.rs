@@ -0,0 +1,393 @@
+use rand::Rng;
+use rand::SeedableRng;
+use rand::rngs::StdRng;
+
+// Result of a two-sided hypothesis test
+#[derive(Clone, Copy)]
+pub struct Test
+{
+    pub statistic: f64,
+    pub p: f64,
+}
+
+// Natural logarithm of the gamma function (Lanczos approximation)
+fn ln_gamma(x: f64) -> f64
+{
+    let coefficients = [76.18009172947146, -86.50532032941677, 24.01409824083091, -1.231739572450155, 0.1208650973866179e-2, -0.5395239384953e-5];
+    let mut y = x;
+    let tmp = x + 5.5;
+    let tmp = tmp - (x + 0.5) * tmp.ln();
+    let mut series = 1.000000000190015;
+    for coefficient in coefficients.iter()
+    {
+        y += 1.0;
+        series += coefficient / y;
+    }
+    -tmp + (2.5066282746310005 * series / x).ln()
+}
+
+// Continued fraction used by the incomplete beta function (modified Lentz's method)
+fn beta_fraction(a: f64, b: f64, x: f64) -> f64
+{
+    let tiny = 1e-300;
+    let clamp = | value: f64 | if value.abs() < tiny { tiny } else { value };
+    let mut c = 1.0;
+    let mut d = 1.0 / clamp(1.0 - (a + b) * x / (a + 1.0));
+    let mut h = d;
+    for m in 1 .. 300
+    {
+        let m = m as f64;
+        let even = m * (b - m) * x / ((a + 2.0 * m - 1.0) * (a + 2.0 * m));
+        d = 1.0 / clamp(1.0 + even * d);
+        c = clamp(1.0 + even / c);
+        h *= d * c;
+        let odd = -(a + m) * (a + b + m) * x / ((a + 2.0 * m) * (a + 2.0 * m + 1.0));
+        d = 1.0 / clamp(1.0 + odd * d);
+        c = clamp(1.0 + odd / c);
+        h *= d * c;
+        if (d * c - 1.0).abs() < 1e-12
+        {
+            break;
+        }
+    }
+    h
+}
+
+// Regularised incomplete beta function I_x(a, b)
+fn incomplete_beta(a: f64, b: f64, x: f64) -> f64
+{
+    if x <= 0.0
+    {
+        return 0.0;
+    }
+    if x >= 1.0
+    {
+        return 1.0;
+    }
+    let front = (ln_gamma(a + b) - ln_gamma(a) - ln_gamma(b) + a * x.ln() + b * (1.0 - x).ln()).exp();
+    // The fraction converges quickly on one side of the mean, so use the symmetry relation on the other
+    if x < (a + 1.0) / (a + b + 2.0)
+    {
+        front * beta_fraction(a, b, x) / a
+    }
+    else
+    {
+        1.0 - front * beta_fraction(b, a, 1.0 - x) / b
+    }
+}
+
+// Cumulative distribution function of Student's t distribution
+pub fn t_cdf(t: f64, df: f64) -> f64
+{
+    let tail = 0.5 * incomplete_beta(df / 2.0, 0.5, df / (df + t * t));
+    if t > 0.0 { 1.0 - tail } else { tail }
+}
+
+// Cumulative distribution function of the standard normal distribution (Abramowitz and Stegun 7.1.26)
+pub fn normal_cdf(z: f64) -> f64
+{
+    let x = z.abs() / 2f64.sqrt();
+    let t = 1.0 / (1.0 + 0.3275911 * x);
+    let erf = 1.0 - t * (0.254829592 + t * (-0.284496736 + t * (1.421413741 + t * (-1.453152027 + t * 1.061405429)))) * (-x * x).exp();
+    if z >= 0.0 { 0.5 * (1.0 + erf) } else { 0.5 * (1.0 - erf) }
+}
+
+// Inverts a monotonically increasing distribution function by bisection
+fn quantile<F>(p: f64, cdf: F) -> f64
+    where F: Fn(f64) -> f64
+{
+    let (mut low, mut high) = (-1e3, 1e3);
+    for _ in 0 .. 200
+    {
+        let middle = (low + high) / 2.0;
+        if cdf(middle) < p { low = middle; } else { high = middle; }
+    }
+    (low + high) / 2.0
+}
+
+// Confidence interval of a mean from Student's t distribution
+pub fn mean_interval(values: &[f64], level: f64) -> (f64, f64)
+{
+    let n = values.len() as f64;
+    if values.len() < 2
+    {
+        return (f64::NAN, f64::NAN);
+    }
+    let mean = values.iter().sum::<f64>() / n;
+    let std = (values.iter().map(| v | (v - mean).powi(2)).sum::<f64>() / (n - 1.0)).sqrt();
+    let t = quantile(0.5 + level / 2.0, | x | t_cdf(x, n - 1.0));
+    let margin = t * std / n.sqrt();
+    (mean - margin, mean + margin)
+}
+
+// Wilson score interval of a proportion measured on n samples
+pub fn wilson_interval(proportion: f64, n: usize, level: f64) -> (f64, f64)
+{
+    if n == 0
+    {
+        return (f64::NAN, f64::NAN);
+    }
+    let n = n as f64;
+    let z = quantile(0.5 + level / 2.0, normal_cdf);
+    let center = (proportion + z * z / (2.0 * n)) / (1.0 + z * z / n);
+    let margin = z / (1.0 + z * z / n) * (proportion * (1.0 - proportion) / n + z * z / (4.0 * n * n)).sqrt();
+    ((center - margin).max(0.0), (center + margin).min(1.0))
+}
+
+// Paired t-test of the mean difference between two matched samples
+pub fn paired_t_test(a: &[f64], b: &[f64]) -> Test
+{
+    let differences: Vec<f64> = a.iter().zip(b).map(| (a, b) | a - b).collect();
+    let n = differences.len() as f64;
+    if differences.len() < 2
+    {
+        return Test { statistic: f64::NAN, p: f64::NAN };
+    }
+    let mean = differences.iter().sum::<f64>() / n;
+    let std = (differences.iter().map(| d | (d - mean).powi(2)).sum::<f64>() / (n - 1.0)).sqrt();
+
+    // Identical differences leave no variance to test against
+    if std == 0.0
+    {
+        return if mean == 0.0 { Test { statistic: 0.0, p: 1.0 } } else { Test { statistic: mean.signum() * f64::INFINITY, p: 0.0 } };
+    }
+    let t = mean / (std / n.sqrt());
+    Test { statistic: t, p: 2.0 * t_cdf(-t.abs(), n - 1.0) }
+}
+
+// Wilcoxon signed-rank test between two matched samples, reporting the smaller rank sum
+// The p-value is exact for up to 20 non-zero differences and uses the tie-corrected normal approximation beyond that
+pub fn wilcoxon(a: &[f64], b: &[f64]) -> Test
+{
+    // Like the paired t-test, fewer than two pairs cannot be tested
+    if a.len().min(b.len()) < 2
+    {
+        return Test { statistic: f64::NAN, p: f64::NAN };
+    }
+
+    // Zero differences are dropped and tied magnitudes share their average rank
+    let mut differences: Vec<f64> = a.iter().zip(b).map(| (a, b) | a - b).filter(| d | *d != 0.0).collect();
+    if differences.is_empty()
+    {
+        return Test { statistic: 0.0, p: 1.0 };
+    }
+    differences.sort_by(| x, y | x.abs().partial_cmp(&y.abs()).unwrap());
+    let n = differences.len();
+    let mut ranks = vec![0.0; n];
+    let mut ties = 0.0;
+    let mut start = 0;
+    while start < n
+    {
+        let mut end = start;
+        while end + 1 < n && differences [end + 1].abs() == differences [start].abs()
+        {
+            end += 1;
+        }
+        let count = (end - start + 1) as f64;
+        ranks [start ..= end].iter_mut().for_each(| rank | *rank = (start + end) as f64 / 2.0 + 1.0);
+        ties += count * count * count - count;
+        start = end + 1;
+    }
+
+    let positive: f64 = differences.iter().zip(&ranks).filter(| (d, _) | **d > 0.0).map(| (_, r) | r).sum();
+    let total = (n * (n + 1)) as f64 / 2.0;
+    let statistic = positive.min(total - positive);
+    let mean = total / 2.0;
+
+    let p = if n <= 20
+    {
+        // Count the sign assignments at least as far from the mean as the observed one
+        let observed = (positive - mean).abs() - 1e-9;
+        let extreme = (0 .. 1u32 << n).filter(
+            | signs |
+            {
+                let sum: f64 = ranks.iter().enumerate().filter(| (i, _) | signs & (1 << i) != 0).map(| (_, r) | r).sum();
+                (sum - mean).abs() >= observed
+            }
+        ).count();
+        extreme as f64 / (1u64 << n) as f64
+    }
+    else
+    {
+        let n = n as f64;
+        let variance = n * (n + 1.0) * (2.0 * n + 1.0) / 24.0 - ties / 48.0;
+        let z = ((positive - mean).abs() - 0.5).max(0.0) / variance.sqrt();
+        2.0 * (1.0 - normal_cdf(z))
+    };
+    Test { statistic, p: p.min(1.0) }
+}
+
+// McNemar's test from the counts of samples only the first (b) or only the second (c) classifier got wrong
+// The exact binomial test is used for fewer than 25 discordant pairs and the continuity corrected chi-squared test otherwise
+pub fn mcnemar(b: usize, c: usize) -> Test
+{
+    let n = b + c;
+    if n == 0
+    {
+        return Test { statistic: 0.0, p: 1.0 };
+    }
+    if n < 25
+    {
+        let tail: f64 = (0 ..= b.min(c)).map(
+            | k | (ln_gamma(n as f64 + 1.0) - ln_gamma(k as f64 + 1.0) - ln_gamma((n - k) as f64 + 1.0) - n as f64 * 2f64.ln()).exp()
+        ).sum();
+        Test { statistic: b.min(c) as f64, p: (2.0 * tail).min(1.0) }
+    }
+    else
+    {
+        let chi = ((b as f64 - c as f64).abs() - 1.0).powi(2) / n as f64;
+        Test { statistic: chi, p: 2.0 * (1.0 - normal_cdf(chi.sqrt())) }
+    }
+}
+
+// Percentile bootstrap interval of a statistic computed from resampled indices of n samples
+pub fn bootstrap_interval<F>(n: usize, statistic: F, resamples: usize, level: f64, seed: u64) -> (f64, f64)
+    where F: Fn(&[usize]) -> f64
+{
+    if n == 0
+    {
+        return (f64::NAN, f64::NAN);
+    }
+    let mut rng = StdRng::seed_from_u64(seed);
+    let mut indices = vec![0; n];
+    let mut values: Vec<f64> = (0 .. resamples).map(
+        | _ |
+        {
+            indices.iter_mut().for_each(| index | *index = rng.gen_range(0, n));
+            statistic(&indices)
+        }
+    ).collect();
+    values.sort_by(| a, b | a.partial_cmp(b).unwrap());
+    let percentile = | q: f64 | values [((q * (resamples - 1) as f64).round() as usize).min(resamples - 1)];
+    (percentile((1.0 - level) / 2.0), percentile((1.0 + level) / 2.0))
+}
+
+// Formats a p-value for a table, or a dash when the test could not be run
+pub fn format_p(p: f64) -> String
+{
+    if p.is_nan() { "-".to_owned() } else if p < 0.001 { "<0.001".to_owned() } else { format!("{:.3}", p) }
+}
+
+// Formats a test statistic with its p-value
+pub fn format_test(test: &Test) -> String
+{
+    if test.p.is_nan() { "-".to_owned() } else { format!("{:.2} (p {})", test.statistic, format_p(test.p)) }
+}
+
+// Formats an interval of proportions as percentages, or a dash when it could not be computed
+pub fn format_interval(interval: (f64, f64)) -> String
+{
+    if interval.0.is_nan() { "-".to_owned() } else { format!("[{:.1}%, {:.1}%]", interval.0 * 100.0, interval.1 * 100.0) }
+}
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+
+    fn close(a: f64, b: f64, tolerance: f64) -> bool
+    {
+        (a - b).abs() < tolerance
+    }
+
+    #[test]
+    fn t_cdf_known_values()
+    {
+        assert!(close(t_cdf(0.0, 7.0), 0.5, 1e-12));
+        // One degree of freedom is the Cauchy distribution
+        assert!(close(t_cdf(1.0, 1.0), 0.75, 1e-9));
+        // Two-sided 5% critical values
+        assert!(close(t_cdf(2.228139, 10.0), 0.975, 1e-6));
+        assert!(close(t_cdf(-2.570582, 5.0), 0.025, 1e-6));
+        assert!(close(normal_cdf(1.959964), 0.975, 1e-6));
+    }
+
+    #[test]
+    fn wilson_known_values()
+    {
+        let (low, high) = wilson_interval(0.5, 10, 0.95);
+        assert!(close(low, 0.2366, 1e-4) && close(high, 0.7634, 1e-4));
+        let (low, high) = wilson_interval(1.0, 10, 0.95);
+        assert!(close(low, 0.7225, 1e-4) && high == 1.0);
+        assert!(wilson_interval(0.5, 0, 0.95).0.is_nan());
+    }
+
+    #[test]
+    fn paired_t_test_known_values()
+    {
+        // Differences 1, 2, 3 have mean 2 and standard error 1/sqrt(3)
+        let test = paired_t_test(&[2.0, 4.0, 6.0], &[1.0, 2.0, 3.0]);
+        assert!(close(test.statistic, 2.0 * 3f64.sqrt(), 1e-12));
+        assert!(close(test.p, 0.074180, 1e-5));
+        assert!(paired_t_test(&[1.0], &[0.0]).p.is_nan());
+    }
+
+    #[test]
+    fn wilcoxon_exact_known_values()
+    {
+        // All five differences positive: only 2 of the 32 sign assignments are as extreme
+        let test = wilcoxon(&[1.0, 2.0, 3.0, 4.0, 5.0], &[0.0; 5]);
+        assert_eq!(test.statistic, 0.0);
+        assert!(close(test.p, 0.0625, 1e-12));
+
+        // Tied magnitudes share ranks 1.5, the zero difference is dropped
+        let test = wilcoxon(&[1.0, -1.0, 2.0, 3.0, 5.0], &[0.0, 0.0, 0.0, 0.0, 5.0]);
+        assert_eq!(test.statistic, 1.5);
+        assert!(close(test.p, 0.375, 1e-12));
+
+        // No differences at all
+        let test = wilcoxon(&[1.0, 2.0], &[1.0, 2.0]);
+        assert_eq!((test.statistic, test.p), (0.0, 1.0));
+    }
+
+    #[test]
+    fn wilcoxon_normal_known_values()
+    {
+        // Ranks 1 to 6 negative and 7 to 22 positive
+        let a: Vec<f64> = (1 ..= 22).map(| i | if i <= 6 { -(i as f64) } else { i as f64 }).collect();
+        let test = wilcoxon(&a, &[0.0; 22]);
+        assert_eq!(test.statistic, 21.0);
+        assert!(close(test.p, 0.000652, 1e-6));
+    }
+
+    #[test]
+    fn wilcoxon_needs_two_pairs()
+    {
+        let test = wilcoxon(&[0.8], &[0.9]);
+        assert!(test.statistic.is_nan() && test.p.is_nan());
+        assert_eq!(format_test(&test), "-");
+    }
+
+    #[test]
+    fn mcnemar_known_values()
+    {
+        // Exact: 2 * P(X <= 1) for X ~ Binomial(6, 0.5) = 2 * 7 / 64
+        let test = mcnemar(1, 5);
+        assert_eq!(test.statistic, 1.0);
+        assert!(close(test.p, 0.21875, 1e-9));
+
+        // Chi-squared: (|10 - 20| - 1)^2 / 30 = 2.7
+        let test = mcnemar(10, 20);
+        assert!(close(test.statistic, 2.7, 1e-12));
+        assert!(close(test.p, 0.100348, 1e-6));
+
+        assert_eq!(mcnemar(0, 0).p, 1.0);
+    }
+
+    #[test]
+    fn bootstrap_known_values()
+    {
+        // A constant statistic has a zero width interval
+        assert_eq!(bootstrap_interval(10, | _ | 3.0, 100, 0.95, 0), (3.0, 3.0));
+
+        // Mean of 100 samples half ones, whose 95% interval is close to 0.5 +- 0.098
+        let values: Vec<f64> = (0 .. 100).map(| i | (i % 2) as f64).collect();
+        let mean = | indices: &[usize] | indices.iter().map(| i | values [*i]).sum::<f64>() / indices.len() as f64;
+        let (low, high) = bootstrap_interval(100, mean, 2000, 0.95, 1);
+        assert!(close(low, 0.402, 0.02) && close(high, 0.598, 0.02));
+
+        // The same seed gives the same interval
+        assert_eq!(bootstrap_interval(100, mean, 2000, 0.95, 1), (low, high));
+        assert!(bootstrap_interval(0, mean, 100, 0.95, 0).0.is_nan());
+    }
+}
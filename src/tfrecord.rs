@@ -0,0 +1,383 @@
+use argparse::{ArgumentParser, Store, List};
+use image::GenericImageView;
+use std::collections::BTreeSet;
+use std::fs;
+use std::fs::File;
+use std::io;
+use std::io::{BufWriter, Write};
+use std::path::{Path, PathBuf};
+
+// Extensions of the image files processed variants are written as
+const EXTENSIONS: [&str; 4] = ["bmp", "png", "jpg", "jpeg"];
+
+// Table of CRC-32C (Castagnoli) remainders for every byte, built at compile time
+const CRC32C_TABLE: [u32; 256] = crc32c_table();
+
+const fn crc32c_table() -> [u32; 256]
+{
+    let mut table = [0u32; 256];
+    let mut i = 0;
+    while i < 256
+    {
+        let mut crc = i as u32;
+        let mut bit = 0;
+        while bit < 8
+        {
+            crc = if crc & 1 != 0 { (crc >> 1) ^ 0x82f63b78 } else { crc >> 1 };
+            bit += 1;
+        }
+        table [i] = crc;
+        i += 1;
+    }
+    table
+}
+
+// CRC-32C (Castagnoli) checksum used by the TFRecord framing
+pub fn crc32c(data: &[u8]) -> u32
+{
+    !data.iter().fold(!0u32, | crc, byte | CRC32C_TABLE [((crc ^ *byte as u32) & 0xff) as usize] ^ (crc >> 8))
+}
+
+// Rotates and offsets a checksum so checksums of data holding checksums stay meaningful
+fn masked_crc(data: &[u8]) -> u32
+{
+    let crc = crc32c(data);
+    crc.rotate_right(15).wrapping_add(0xa282ead8)
+}
+
+// Appends a protocol buffer base 128 varint
+fn varint(out: &mut Vec<u8>, mut value: u64)
+{
+    while value >= 0x80
+    {
+        out.push((value as u8 & 0x7f) | 0x80);
+        value >>= 7;
+    }
+    out.push(value as u8);
+}
+
+// Appends a length delimited protocol buffer field (wire type 2)
+fn field(out: &mut Vec<u8>, number: u64, bytes: &[u8])
+{
+    varint(out, number << 3 | 2);
+    varint(out, bytes.len() as u64);
+    out.extend_from_slice(bytes);
+}
+
+// A tf.train.Feature holding either a list of byte strings or a list of integers
+pub enum Feature
+{
+    Bytes(Vec<Vec<u8>>),
+    Int64(Vec<i64>),
+}
+
+impl Feature
+{
+    fn encode(&self) -> Vec<u8>
+    {
+        // Feature is a oneof of BytesList (1), FloatList (2) and Int64List (3)
+        let mut list = Vec::new();
+        let mut out = Vec::new();
+        match self
+        {
+            Feature::Bytes(values) =>
+            {
+                values.iter().for_each(| value | field(&mut list, 1, value));
+                field(&mut out, 1, &list);
+            }
+            Feature::Int64(values) =>
+            {
+                let mut packed = Vec::new();
+                values.iter().for_each(| value | varint(&mut packed, *value as u64));
+                field(&mut list, 1, &packed);
+                field(&mut out, 3, &list);
+            }
+        }
+        out
+    }
+}
+
+// A tf.train.Example built up one named feature at a time
+#[derive(Default)]
+pub struct Example
+{
+    features: Vec<(String, Feature)>,
+}
+
+impl Example
+{
+    pub fn bytes(&mut self, key: &str, value: &[u8])
+    {
+        self.features.push((key.to_owned(), Feature::Bytes(vec![value.to_vec()])));
+    }
+
+    pub fn int64(&mut self, key: &str, value: i64)
+    {
+        self.features.push((key.to_owned(), Feature::Int64(vec![value])));
+    }
+
+    // Serialises the example, with the features map stored as repeated key/value entries
+    pub fn encode(&self) -> Vec<u8>
+    {
+        let mut features = Vec::new();
+        for (key, feature) in &self.features
+        {
+            let mut entry = Vec::new();
+            field(&mut entry, 1, key.as_bytes());
+            field(&mut entry, 2, &feature.encode());
+            field(&mut features, 1, &entry);
+        }
+        let mut out = Vec::new();
+        field(&mut out, 1, &features);
+        out
+    }
+}
+
+// Writes records framed as TFRecord files expect: length, masked CRC of the length, data and masked CRC of the data
+pub struct RecordWriter
+{
+    file: BufWriter<File>,
+}
+
+impl RecordWriter
+{
+    pub fn create(path: &str) -> RecordWriter
+    {
+        RecordWriter { file: BufWriter::new(File::create(path).unwrap_or_else(| _ | panic!("Failed to create {}", path))) }
+    }
+
+    pub fn write(&mut self, record: &[u8])
+    {
+        let length = (record.len() as u64).to_le_bytes();
+        self.file.write_all(&length).unwrap();
+        self.file.write_all(&masked_crc(&length).to_le_bytes()).unwrap();
+        self.file.write_all(record).unwrap();
+        self.file.write_all(&masked_crc(record).to_le_bytes()).unwrap();
+    }
+
+    pub fn finish(mut self)
+    {
+        self.file.flush().unwrap();
+    }
+}
+
+// An image of a processed variant along with the split and category given by the folders it is in
+pub struct Sample
+{
+    pub path: PathBuf,
+    pub name: String,
+    pub split: String,
+    pub label: String,
+}
+
+// Lists the images of a processed variant directory laid out as [split/][category/]image, sorted by path
+pub fn samples(dir: &str) -> Vec<Sample>
+{
+    let mut samples = Vec::new();
+    let mut pending = vec![(PathBuf::from(dir), Vec::new())];
+    while let Some((path, parents)) = pending.pop()
+    {
+        for entry in fs::read_dir(&path).unwrap_or_else(| _ | panic!("Failed to read directory {}", path.display()))
+        {
+            let entry = entry.unwrap();
+            let name = entry.file_name().to_string_lossy().into_owned();
+            if entry.file_type().unwrap().is_dir()
+            {
+                let mut parents = parents.clone();
+                parents.push(name);
+                pending.push((entry.path(), parents));
+            }
+            else if entry.path().extension().is_some_and(| extension | EXTENSIONS.contains(&extension.to_string_lossy().to_lowercase().as_str()))
+            {
+                let (split, label) = match parents.first().map(| first | first.as_str())
+                {
+                    Some("training") | Some("validation") => (parents [0].clone(), parents [1 ..].join("/")),
+                    _ => ("".to_owned(), parents.join("/")),
+                };
+                samples.push(Sample { path: entry.path(), name, split, label });
+            }
+        }
+    }
+    samples.sort_by(| a, b | a.path.cmp(&b.path));
+    samples
+}
+
+// Lists the processed variant directories in the working directory
+pub fn variants() -> Vec<String>
+{
+    let mut variants: Vec<String> = fs::read_dir(".").unwrap().map(| entry | entry.unwrap()).filter(| entry | entry.file_type().unwrap().is_dir())
+        .map(| entry | entry.file_name().to_string_lossy().into_owned())
+        .filter(| name | name.starts_with("base") || name.starts_with("output") || name.starts_with("saturated_")).collect();
+    variants.sort();
+    variants
+}
+
+// Builds the example stored for one image
+fn example(sample: &Sample, variant: &str, labels: &[String], encoding: &str) -> Example
+{
+    let img = image::open(&sample.path).unwrap_or_else(| _ | panic!("Failed to open {}", sample.path.display()));
+    let (img, channels) = match img
+    {
+        image::DynamicImage::ImageLuma8(_) => (img, 1),
+        _ => (image::DynamicImage::ImageRgb8(img.to_rgb()), 3),
+    };
+    let (width, height) = img.dimensions();
+    let encoded = if encoding == "png"
+    {
+        let mut encoded = Vec::new();
+        img.write_to(&mut encoded, image::ImageOutputFormat::PNG).unwrap();
+        encoded
+    }
+    else
+    {
+        fs::read(&sample.path).unwrap()
+    };
+    let format = if encoding == "png" { "png".to_owned() } else { sample.path.extension().unwrap().to_string_lossy().to_lowercase() };
+
+    let mut example = Example::default();
+    example.bytes("image/encoded", &encoded);
+    example.bytes("image/format", format.as_bytes());
+    example.int64("image/height", height as i64);
+    example.int64("image/width", width as i64);
+    example.int64("image/channels", channels);
+    example.bytes("image/filename", sample.name.as_bytes());
+    example.int64("image/class/label", labels.iter().position(| label | *label == sample.label).map(| index | index as i64).unwrap_or(-1));
+    example.bytes("image/class/text", sample.label.as_bytes());
+    example.bytes("split", sample.split.as_bytes());
+    example.bytes("variant", variant.as_bytes());
+    example
+}
+
+// Packs processed variant directories into sharded TFRecord files of tf.train.Example records
+pub fn run(args: Vec<String>)
+{
+    let mut dirs: Vec<String> = Vec::new();
+    let mut output = "tfrecords/".to_owned();
+    let mut shard_size = 500;
+    let mut encoding = "png".to_owned();
+    {
+        let mut ap = ArgumentParser::new();
+        ap.set_description("Pack processed images into sharded TFRecord files with their labels, split and variant");
+        ap.refer(&mut dirs)
+            .add_argument("variants", List,
+            "Variant directories to pack (every base, output and saturated_ directory in the working directory by default)");
+        ap.refer(&mut output)
+            .add_option(&["-o", "--output"], Store,
+            "Set the directory shards and the label list are written to (tfrecords/ by default)");
+        ap.refer(&mut shard_size)
+            .add_option(&["-s", "--shard-size"], Store,
+            "Set the most images stored in one shard (500 by default)");
+        ap.refer(&mut encoding)
+            .add_option(&["-e", "--encoding"], Store,
+            "Set how images are stored (png to re-encode them losslessly, or raw to keep the file bytes; png by default)");
+        if let Err(code) = ap.parse(args, &mut io::stdout(), &mut io::stderr())
+        {
+            std::process::exit(code);
+        }
+    }
+    if !output.ends_with('/')
+    {
+        output.push('/');
+    }
+    if shard_size == 0
+    {
+        panic!("Shard size must be at least 1");
+    }
+    if encoding != "png" && encoding != "raw"
+    {
+        panic!("Unknown encoding {} (expected png or raw)", encoding);
+    }
+    if dirs.is_empty()
+    {
+        dirs = variants();
+    }
+
+    // Labels are numbered in sorted order across every variant so indices agree between shards
+    let variants: Vec<(String, Vec<Sample>)> = dirs.iter().map(| dir | (dir.trim_end_matches('/').to_owned(), samples(dir))).collect();
+    let labels: Vec<String> = variants.iter().flat_map(| (_, samples) | samples.iter().map(| sample | sample.label.clone())).filter(| label | !label.is_empty())
+        .collect::<BTreeSet<String>>().into_iter().collect();
+    fs::create_dir_all(&output).unwrap();
+    fs::write(output.clone() + "labels.txt", labels.iter().map(| label | label.clone() + "\n").collect::<String>()).unwrap();
+
+    for (variant, samples) in &variants
+    {
+        let name = Path::new(variant).file_name().unwrap().to_string_lossy().into_owned();
+        let splits: BTreeSet<&str> = samples.iter().map(| sample | sample.split.as_str()).collect();
+        for split in splits
+        {
+            let split_samples: Vec<&Sample> = samples.iter().filter(| sample | sample.split == split).collect();
+            let shards = split_samples.len().div_ceil(shard_size);
+            let prefix = if split.is_empty() { name.clone() } else { name.clone() + "-" + split };
+            for (index, chunk) in split_samples.chunks(shard_size).enumerate()
+            {
+                let path = format!("{}{}-{:05}-of-{:05}.tfrecord", output, prefix, index, shards);
+                let mut writer = RecordWriter::create(&path);
+                chunk.iter().for_each(| sample | writer.write(&example(sample, &name, &labels, &encoding).encode()));
+                writer.finish();
+            }
+            println!("Packed {} images of {} into {} shards", split_samples.len(), prefix, shards);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+    use std::io::Read;
+
+    #[test]
+    fn checksums_match_known_values()
+    {
+        assert_eq!(crc32c(b"123456789"), 0xe3069283);
+        assert_eq!(crc32c(b""), 0);
+        // Masking rotates the checksum right by 15 bits and adds 0xa282ead8
+        assert_eq!(masked_crc(b""), 0xa282ead8);
+        assert_eq!(masked_crc(b"123456789"), 0xc78ab0e5);
+    }
+
+    #[test]
+    fn encodes_examples_as_protocol_buffers()
+    {
+        let mut example = Example::default();
+        example.bytes("a", b"xy");
+        example.int64("n", -1);
+        let expected: Vec<u8> = [
+            // Example.features, a Features message of 34 bytes
+            &[0x0a, 0x22][..],
+            // Entry "a" holding Feature.bytes_list with one value
+            &[0x0a, 0x0b, 0x0a, 0x01, b'a', 0x12, 0x06, 0x0a, 0x04, 0x0a, 0x02, b'x', b'y'],
+            // Entry "n" holding Feature.int64_list with -1 packed as a 10 byte varint
+            &[0x0a, 0x13, 0x0a, 0x01, b'n', 0x12, 0x0e, 0x1a, 0x0c, 0x0a, 0x0a],
+            &[0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0x01],
+        ].concat();
+        assert_eq!(example.encode(), expected);
+    }
+
+    #[test]
+    fn frames_records()
+    {
+        let path = std::env::temp_dir().join(format!("image_affinity_tfrecord_{}", std::process::id())).to_string_lossy().into_owned();
+        let mut writer = RecordWriter::create(&path);
+        writer.write(b"first");
+        writer.write(b"");
+        writer.finish();
+        let mut bytes = Vec::new();
+        File::open(&path).unwrap().read_to_end(&mut bytes).unwrap();
+        fs::remove_file(&path).unwrap();
+
+        let mut position = 0;
+        for record in [&b"first"[..], b""]
+        {
+            let length = &bytes [position .. position + 8];
+            assert_eq!(length, (record.len() as u64).to_le_bytes());
+            assert_eq!(bytes [position + 8 .. position + 12], masked_crc(length).to_le_bytes());
+            position += 12;
+            assert_eq!(&bytes [position .. position + record.len()], record);
+            position += record.len();
+            assert_eq!(bytes [position .. position + 4], masked_crc(record).to_le_bytes());
+            position += 4;
+        }
+        assert_eq!(position, bytes.len());
+    }
+}
@@ -0,0 +1,196 @@
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+
+// TIFF sample formats
+pub const UNSIGNED: u16 = 1;
+pub const SIGNED: u16 = 2;
+pub const FLOAT: u16 = 3;
+
+// Field types used in the image file directory
+const SHORT: u16 = 3;
+const LONG: u16 = 4;
+
+// Writes an uncompressed little endian TIFF with any number of interleaved channels
+// Data must already hold the samples as little endian bytes in row, column, channel order
+pub fn write_tiff(path: &str, width: u32, height: u32, channels: u16, bits: u16, sample_format: u16, data: &[u8]) -> io::Result<()>
+{
+    assert_eq!(data.len(), width as usize * height as usize * channels as usize * (bits / 8) as usize);
+
+    // Three channels are tagged as RGB so viewers show them as color, anything else is grayscale with extra samples
+    let photometric = if channels == 3 { 2 } else { 1 };
+    let extra = if channels == 3 { 0 } else { channels - 1 };
+
+    // Image data follows the header and the per-channel arrays follow the image data
+    let data_offset = 8u32;
+    let bits_offset = data_offset + data.len() as u32 + (data.len() % 2) as u32;
+    let format_offset = bits_offset + 2 * channels as u32;
+    let extra_offset = format_offset + 2 * channels as u32;
+    let ifd_offset = extra_offset + 2 * extra as u32;
+
+    // Arrays of up to two shorts fit inside the entry, anything larger is stored at an offset
+    let per_channel = | offset: u32, value: u16 | if channels > 2 { offset } else if channels == 2 { value as u32 | (value as u32) << 16 } else { value as u32 };
+    let mut entries: Vec<(u16, u16, u32, u32)> = vec![
+        (256, LONG, 1, width),
+        (257, LONG, 1, height),
+        (258, SHORT, channels as u32, per_channel(bits_offset, bits)),
+        (259, SHORT, 1, 1),
+        (262, SHORT, 1, photometric),
+        (273, LONG, 1, data_offset),
+        (277, SHORT, 1, channels as u32),
+        (278, LONG, 1, height),
+        (279, LONG, 1, data.len() as u32),
+        (284, SHORT, 1, 1),
+    ];
+    if extra > 0
+    {
+        entries.push((338, SHORT, extra as u32, if extra > 2 { extra_offset } else { 0 }));
+    }
+    entries.push((339, SHORT, channels as u32, per_channel(format_offset, sample_format)));
+
+    let mut out = BufWriter::new(File::create(path)?);
+    out.write_all(b"II")?;
+    out.write_all(&42u16.to_le_bytes())?;
+    out.write_all(&ifd_offset.to_le_bytes())?;
+    out.write_all(data)?;
+    if data.len() % 2 == 1
+    {
+        out.write_all(&[0])?;
+    }
+    for _ in 0 .. channels
+    {
+        out.write_all(&bits.to_le_bytes())?;
+    }
+    for _ in 0 .. channels
+    {
+        out.write_all(&sample_format.to_le_bytes())?;
+    }
+    for _ in 0 .. extra
+    {
+        out.write_all(&0u16.to_le_bytes())?;
+    }
+
+    // Image file directory with entries sorted by tag
+    out.write_all(&(entries.len() as u16).to_le_bytes())?;
+    for (tag, kind, count, value) in entries
+    {
+        out.write_all(&tag.to_le_bytes())?;
+        out.write_all(&kind.to_le_bytes())?;
+        out.write_all(&count.to_le_bytes())?;
+        if kind == SHORT && count == 1
+        {
+            out.write_all(&(value as u16).to_le_bytes())?;
+            out.write_all(&0u16.to_le_bytes())?;
+        }
+        else
+        {
+            out.write_all(&value.to_le_bytes())?;
+        }
+    }
+    out.write_all(&0u32.to_le_bytes())?;
+    out.flush()
+}
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+    use std::collections::BTreeMap;
+
+    fn u16_at(bytes: &[u8], at: usize) -> u16
+    {
+        u16::from_le_bytes([bytes [at], bytes [at + 1]])
+    }
+
+    fn u32_at(bytes: &[u8], at: usize) -> u32
+    {
+        u32::from_le_bytes([bytes [at], bytes [at + 1], bytes [at + 2], bytes [at + 3]])
+    }
+
+    // Directory entries as tag => (type, count, value or offset)
+    type Entries = BTreeMap<u16, (u16, u32, u32)>;
+
+    // Writes a TIFF and reads back its bytes and entries
+    fn read_back(name: &str, width: u32, height: u32, channels: u16, bits: u16, sample_format: u16, data: &[u8]) -> (Vec<u8>, Entries)
+    {
+        let path = std::env::temp_dir().join(format!("image_affinity_tiff_{}_{}.tiff", std::process::id(), name));
+        write_tiff(path.to_str().unwrap(), width, height, channels, bits, sample_format, data).unwrap();
+        let bytes = std::fs::read(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        assert_eq!(&bytes [.. 4], b"II\x2a\x00");
+        let ifd = u32_at(&bytes, 4) as usize;
+        let count = u16_at(&bytes, ifd) as usize;
+        assert_eq!(u32_at(&bytes, ifd + 2 + 12 * count), 0, "the directory is not the last one");
+        assert_eq!(bytes.len(), ifd + 2 + 12 * count + 4);
+        let entries = (0 .. count).map(
+            | i |
+            {
+                let at = ifd + 2 + 12 * i;
+                let kind = u16_at(&bytes, at + 2);
+                let count = u32_at(&bytes, at + 4);
+                let value = if kind == SHORT && count == 1 { u16_at(&bytes, at + 8) as u32 } else { u32_at(&bytes, at + 8) };
+                (u16_at(&bytes, at), (kind, count, value))
+            }
+        ).collect();
+        (bytes, entries)
+    }
+
+    #[test]
+    fn writes_extra_channels_with_offset_arrays()
+    {
+        let data: Vec<u8> = (0 .. 3 * 2 * 4).collect();
+        let (bytes, entries) = read_back("four", 3, 2, 4, 8, UNSIGNED, &data);
+        assert!(entries.keys().zip(entries.keys().skip(1)).all(| (a, b) | a < b), "tags are not sorted");
+        assert_eq!(entries [&256], (LONG, 1, 3));
+        assert_eq!(entries [&257], (LONG, 1, 2));
+        assert_eq!(entries [&259], (SHORT, 1, 1));
+        assert_eq!(entries [&262], (SHORT, 1, 1));
+        assert_eq!(entries [&277], (SHORT, 1, 4));
+        assert_eq!(entries [&278], (LONG, 1, 2));
+        assert_eq!(entries [&284], (SHORT, 1, 1));
+
+        // The strip is the data as given, and its byte count covers every sample
+        let (_, _, offset) = entries [&273];
+        let (_, _, length) = entries [&279];
+        assert_eq!(length, 24);
+        assert_eq!(&bytes [offset as usize .. (offset + length) as usize], &data [..]);
+
+        // Per-channel arrays of more than two shorts are stored at an offset
+        let shorts = | tag: u16 |
+        {
+            let (kind, count, offset) = entries [&tag];
+            assert_eq!(kind, SHORT);
+            (0 .. count as usize).map(| i | u16_at(&bytes, offset as usize + 2 * i)).collect::<Vec<u16>>()
+        };
+        assert_eq!(shorts(258), [8; 4]);
+        assert_eq!(shorts(338), [0; 3]);
+        assert_eq!(shorts(339), [UNSIGNED; 4]);
+    }
+
+    #[test]
+    fn writes_small_arrays_inline()
+    {
+        // Two 16 bit channels of an odd number of pixels, whose arrays fit in the entries
+        let data = vec![7u8; 3 * 4];
+        let (_, entries) = read_back("two", 3, 1, 2, 16, SIGNED, &data);
+        assert_eq!(entries [&258], (SHORT, 2, 16 | 16 << 16));
+        assert_eq!(entries [&338], (SHORT, 1, 0));
+        assert_eq!(entries [&339], (SHORT, 2, SIGNED as u32 | (SIGNED as u32) << 16));
+        assert_eq!(entries [&279], (LONG, 1, 12));
+
+        // Three channels are tagged as RGB and have no extra samples
+        let (_, entries) = read_back("three", 1, 1, 3, 32, FLOAT, &[0; 12]);
+        assert_eq!(entries [&262], (SHORT, 1, 2));
+        assert!(!entries.contains_key(&338));
+        assert_eq!(entries [&279], (LONG, 1, 12));
+    }
+
+    #[test]
+    fn pads_odd_data_to_a_word()
+    {
+        let (bytes, entries) = read_back("odd", 3, 1, 1, 8, UNSIGNED, &[1, 2, 3]);
+        assert_eq!(entries [&279], (LONG, 1, 3));
+        assert_eq!(bytes [11], 0);
+        assert_eq!(u32_at(&bytes, 4) % 2, 0);
+    }
+}
@@ -0,0 +1,544 @@
+use crate::div16::div16;
+use crate::filters;
+use crate::progress::log;
+
+use argparse::{ArgumentParser, Store, StoreTrue};
+use image::GenericImageView;
+use std::fs::{File, OpenOptions};
+use std::io;
+use std::io::{BufRead, BufReader, Read, Seek, SeekFrom, Write};
+use std::path::Path;
+use std::time::Instant;
+
+// How the samples of one pixel are stored in a streamed input
+enum Layout
+{
+    Gray,
+    Rgb,
+    // PNM samples wider than a byte are big endian and scaled from their maximum value to 0-255
+    Gray16(u32),
+    Rgb16(u32),
+    Bgr,
+    Bgrx,
+    Palette(Vec<[u8; 3]>),
+}
+
+impl Layout
+{
+    fn bytes(&self) -> u32
+    {
+        match self
+        {
+            Layout::Gray | Layout::Palette(_) => 1,
+            Layout::Gray16(_) => 2,
+            Layout::Rgb | Layout::Bgr => 3,
+            Layout::Bgrx => 4,
+            Layout::Rgb16(_) => 6,
+        }
+    }
+
+    fn pixel(&self, bytes: &[u8]) -> image::Rgb<u8>
+    {
+        let wide = | i: usize, max: u32 | ((u16::from_be_bytes([bytes [i], bytes [i + 1]]) as u32).min(max) * 255 + max / 2) / max;
+        match self
+        {
+            Layout::Gray => image::Rgb([bytes [0]; 3]),
+            Layout::Rgb => image::Rgb([bytes [0], bytes [1], bytes [2]]),
+            Layout::Gray16(max) => image::Rgb([wide(0, *max) as u8; 3]),
+            Layout::Rgb16(max) => image::Rgb([wide(0, *max) as u8, wide(2, *max) as u8, wide(4, *max) as u8]),
+            Layout::Bgr | Layout::Bgrx => image::Rgb([bytes [2], bytes [1], bytes [0]]),
+            Layout::Palette(palette) => image::Rgb(palette.get(bytes [0] as usize).cloned().unwrap_or([0; 3])),
+        }
+    }
+}
+
+// Reads tiles of the input image one row segment at a time, so only the tile being processed is held in memory
+// Binary PGM and PPM files of up to 16 bits and uncompressed 8, 24 and 32 bit BMP files can be read this way,
+// and other formats are rejected since they would have to be decoded whole
+pub struct TileReader
+{
+    file: File,
+    offset: u64,
+    stride: u64,
+    width: u32,
+    height: u32,
+    bottom_up: bool,
+    layout: Layout,
+}
+
+// Reads the next whitespace separated number of a PNM header, skipping comments
+fn header_number<R: BufRead>(input: &mut R, offset: &mut u64) -> u32
+{
+    let mut digits = String::new();
+    let mut comment = false;
+    loop
+    {
+        let mut byte = [0];
+        input.read_exact(&mut byte).expect("PNM header is truncated");
+        *offset += 1;
+        let c = byte [0] as char;
+        if comment
+        {
+            comment = c != '\n';
+        }
+        else if c == '#'
+        {
+            comment = true;
+        }
+        else if c.is_ascii_digit()
+        {
+            digits.push(c);
+        }
+        else if c.is_ascii_whitespace() && !digits.is_empty()
+        {
+            // The single whitespace after the last number is consumed, which is where the samples start
+            return digits.parse().unwrap();
+        }
+        else if !c.is_ascii_whitespace()
+        {
+            panic!("Unexpected {} in PNM header", c);
+        }
+    }
+}
+
+// Reads little endian values at a position of a BMP header
+fn le_u16(header: &[u8], at: usize) -> u16
+{
+    u16::from_le_bytes([header [at], header [at + 1]])
+}
+
+fn le_u32(header: &[u8], at: usize) -> u32
+{
+    u32::from_le_bytes([header [at], header [at + 1], header [at + 2], header [at + 3]])
+}
+
+impl TileReader
+{
+    pub fn open(path: &str) -> TileReader
+    {
+        let mut input = BufReader::new(File::open(path).unwrap_or_else(| _ | panic!("Failed to open {}", path)));
+        let mut magic = [0; 2];
+        input.read_exact(&mut magic).unwrap_or_else(| _ | panic!("Failed to read {}", path));
+        let (offset, stride, width, height, bottom_up, layout) = match &magic
+        {
+            b"P5" | b"P6" =>
+            {
+                let mut offset = 2;
+                let width = header_number(&mut input, &mut offset);
+                let height = header_number(&mut input, &mut offset);
+                let max = header_number(&mut input, &mut offset);
+                let layout = match (&magic, max)
+                {
+                    (_, 0) | (_, 65536 ..) => panic!("Invalid PNM maximum value {} in {}", max, path),
+                    (b"P5", 1 ..= 255) => Layout::Gray,
+                    (b"P5", _) => Layout::Gray16(max),
+                    (_, 1 ..= 255) => Layout::Rgb,
+                    _ => Layout::Rgb16(max),
+                };
+                (offset, width as u64 * layout.bytes() as u64, width, height, false, layout)
+            },
+            b"BM" =>
+            {
+                let mut header = vec![0; 52];
+                input.read_exact(&mut header).unwrap_or_else(| _ | panic!("BMP header of {} is truncated", path));
+                // Offsets are from the start of the file, which is two bytes before the header read
+                let field = | at: usize | at - 2;
+                let offset = le_u32(&header, field(10)) as u64;
+                let info = le_u32(&header, field(14));
+                let width = le_u32(&header, field(18)) as i32;
+                let height = le_u32(&header, field(22)) as i32;
+                let bits = le_u16(&header, field(28));
+                let compression = le_u32(&header, field(30));
+                if info < 40 || width <= 0 || height == 0 || compression != 0
+                {
+                    panic!("Only uncompressed BMP files with a BITMAPINFOHEADER or later can be tiled, and {} is not one", path);
+                }
+                let layout = match bits
+                {
+                    8 =>
+                    {
+                        // The palette follows the info header as blue, green, red and reserved bytes
+                        let colors = match le_u32(&header, field(46)) { 0 => 256, colors => colors.min(256) };
+                        let mut file = File::open(path).unwrap();
+                        let mut palette = vec![0; colors as usize * 4];
+                        file.seek(SeekFrom::Start(14 + info as u64)).unwrap();
+                        file.read_exact(&mut palette).unwrap_or_else(| _ | panic!("BMP palette of {} is truncated", path));
+                        Layout::Palette(palette.chunks_exact(4).map(| color | [color [2], color [1], color [0]]).collect())
+                    },
+                    24 => Layout::Bgr,
+                    32 => Layout::Bgrx,
+                    _ => panic!("Only 8, 24 and 32 bit BMP files can be tiled, and {} has {} bits per pixel", path, bits),
+                };
+                // Rows are padded to 4 bytes and stored bottom up unless the height is negative
+                let stride = (width as u64 * layout.bytes() as u64).div_ceil(4) * 4;
+                (offset, stride, width as u32, height.unsigned_abs(), height > 0, layout)
+            },
+            _ => panic!("Only binary PGM and PPM files and uncompressed BMP files can be tiled, convert {} to one of them first", path),
+        };
+        TileReader { file: File::open(path).unwrap(), offset, stride, width, height, bottom_up, layout }
+    }
+
+    pub fn dimensions(&self) -> (u32, u32)
+    {
+        (self.width, self.height)
+    }
+
+    // Reads the part of the image with the given bounds (x, y, width, height)
+    pub fn tile(&mut self, bounds: (u32, u32, u32, u32)) -> image::RgbImage
+    {
+        let (x0, y0, w, h) = bounds;
+        let bytes = self.layout.bytes();
+        let mut tile = image::RgbImage::new(w, h);
+        let mut row = vec![0; (w * bytes) as usize];
+        for y in 0 .. h
+        {
+            let stored = if self.bottom_up { self.height - 1 - (y0 + y) } else { y0 + y };
+            let position = self.offset + stored as u64 * self.stride + x0 as u64 * bytes as u64;
+            self.file.seek(SeekFrom::Start(position)).unwrap();
+            self.file.read_exact(&mut row).expect("Image samples are truncated");
+            for (x, pixel) in row.chunks_exact(bytes as usize).enumerate()
+            {
+                tile.put_pixel(x as u32, y, self.layout.pixel(pixel));
+            }
+        }
+        tile
+    }
+}
+
+// Writes tiles of the output straight to their place in a BMP or PPM file, so the whole output is never held in memory
+pub struct TileWriter
+{
+    file: File,
+    offset: u64,
+    stride: u64,
+    width: u32,
+    height: u32,
+    bmp: bool,
+}
+
+impl TileWriter
+{
+    // Creates a 24 bit BMP, or a binary PPM when the path ends in .ppm, of the given size
+    pub fn create(path: &str, width: u32, height: u32) -> TileWriter
+    {
+        let bmp = !path.to_lowercase().ends_with(".ppm");
+        let mut file = OpenOptions::new().read(true).write(true).create(true).truncate(true).open(path).unwrap_or_else(| _ | panic!("Failed to create {}", path));
+        let (offset, stride) = if bmp
+        {
+            // BMP rows are padded to 4 bytes and stored bottom up
+            let stride = (width as u64 * 3).div_ceil(4) * 4;
+            let size = 54 + stride * height as u64;
+            let mut header = Vec::with_capacity(54);
+            header.extend_from_slice(b"BM");
+            header.extend_from_slice(&(size as u32).to_le_bytes());
+            header.extend_from_slice(&[0; 4]);
+            header.extend_from_slice(&54u32.to_le_bytes());
+            header.extend_from_slice(&40u32.to_le_bytes());
+            header.extend_from_slice(&width.to_le_bytes());
+            header.extend_from_slice(&height.to_le_bytes());
+            header.extend_from_slice(&1u16.to_le_bytes());
+            header.extend_from_slice(&24u16.to_le_bytes());
+            header.extend_from_slice(&[0; 4]);
+            header.extend_from_slice(&((stride * height as u64) as u32).to_le_bytes());
+            header.extend_from_slice(&[0; 16]);
+            file.write_all(&header).unwrap();
+            (54, stride)
+        }
+        else
+        {
+            let header = format!("P6\n{} {}\n255\n", width, height);
+            file.write_all(header.as_bytes()).unwrap();
+            (header.len() as u64, width as u64 * 3)
+        };
+        // Sizing the file up front lets rows be written in any order
+        file.set_len(offset + stride * height as u64).unwrap();
+        TileWriter { file, offset, stride, width, height, bmp }
+    }
+
+    fn row_position(&self, x: u32, y: u32) -> u64
+    {
+        let row = if self.bmp { self.height - 1 - y } else { y };
+        self.offset + row as u64 * self.stride + x as u64 * 3
+    }
+
+    fn encode(&self, pixel: &image::Rgb<u8>) -> [u8; 3]
+    {
+        if self.bmp { [pixel [2], pixel [1], pixel [0]] } else { pixel.0 }
+    }
+
+    // Writes the part of a tile with the given bounds (x, y, width, height) within it to where the tile starts in the output
+    pub fn write(&mut self, tile: &image::RgbImage, inner: (u32, u32, u32, u32), at: (u32, u32))
+    {
+        let (ix, iy, w, h) = inner;
+        let mut row = Vec::with_capacity(w as usize * 3);
+        for y in 0 .. h
+        {
+            row.clear();
+            for x in 0 .. w
+            {
+                row.extend_from_slice(&self.encode(tile.get_pixel(ix + x, iy + y)));
+            }
+            let position = self.row_position(at.0, at.1 + y);
+            self.file.seek(SeekFrom::Start(position)).unwrap();
+            self.file.write_all(&row).unwrap();
+        }
+    }
+
+    // Stretches the written samples from the given range to 0-255 a row at a time, the same way saturate does
+    pub fn saturate(&mut self, min: u8, max: u8)
+    {
+        let mut scale: f64 = 255.0;
+        if max != min
+        {
+            scale /= (max - min) as f64;
+        }
+        let mut row = vec![0; self.width as usize * 3];
+        for y in 0 .. self.height
+        {
+            let position = self.row_position(0, y);
+            self.file.seek(SeekFrom::Start(position)).unwrap();
+            self.file.read_exact(&mut row).unwrap();
+            row.iter_mut().for_each(| sample | *sample = ((*sample - min) as f64 * scale) as u8);
+            self.file.seek(SeekFrom::Start(position)).unwrap();
+            self.file.write_all(&row).unwrap();
+        }
+    }
+
+    pub fn finish(mut self)
+    {
+        self.file.flush().unwrap();
+    }
+}
+
+// Runs a filter over the input one overlapping tile at a time and stitches the results together in the output
+// Tiles are read with radius extra pixels on every side not at the image edge, and only their inner part is kept,
+// so every kept pixel sees the same neighbours it would in the whole image
+// Returns the number of tiles and the smallest and largest output samples
+pub fn process<F>(reader: &mut TileReader, writer: &mut TileWriter, tile: u32, radius: u32, filter: F) -> (usize, u8, u8)
+    where F: Fn(&image::RgbImage) -> image::RgbImage
+{
+    let (width, height) = reader.dimensions();
+    let (mut count, mut min, mut max) = (0, 255, 0);
+    for y in (0 .. height).step_by(tile as usize)
+    {
+        for x in (0 .. width).step_by(tile as usize)
+        {
+            let (w, h) = (tile.min(width - x), tile.min(height - y));
+            let (x0, y0) = (x.saturating_sub(radius), y.saturating_sub(radius));
+            let (x1, y1) = ((x + w + radius).min(width), (y + h + radius).min(height));
+            let output = filter(&reader.tile((x0, y0, x1 - x0, y1 - y0)));
+            let inner = (x - x0, y - y0, w, h);
+            for (_, _, pixel) in output.view(inner.0, inner.1, w, h).pixels()
+            {
+                pixel.0.iter().for_each(| sample | { min = min.min(*sample); max = max.max(*sample); });
+            }
+            writer.write(&output, inner, (x, y));
+            count += 1;
+        }
+    }
+    (count, min, max)
+}
+
+// Filters an image too large to hold in memory by processing it in tiles
+pub fn run(args: Vec<String>)
+{
+    let mut input = "".to_owned();
+    let mut output = "".to_owned();
+    let mut filter = "affinity".to_owned();
+    let mut tile = 1024u32;
+    let mut diff_radius = 0;
+    let mut divide = false;
+    let mut saturate = false;
+    {
+        let mut ap = ArgumentParser::new();
+        ap.set_description("Run a filter over a large image in overlapping tiles, keeping memory use bounded by the tile size");
+        ap.refer(&mut input)
+            .add_argument("input", Store,
+            "Path of the input image, which is streamed and so has to be a binary PGM or PPM file or an uncompressed 8, 24 or 32 bit BMP file")
+            .required();
+        ap.refer(&mut output)
+            .add_argument("output", Store,
+            "Path of the output image, written as a BMP or, for paths ending in .ppm, a binary PPM")
+            .required();
+        ap.refer(&mut filter)
+            .add_option(&["-f", "--filter"], Store,
+            "Set the filter to run (original, affinity, max_diff or center_diff; affinity by default)");
+        ap.refer(&mut tile)
+            .add_option(&["-t", "--tile"], Store,
+            "Set the width and height of the tiles written at a time, which are read with an overlap of the filter's radius (1024 by default)");
+        ap.refer(&mut diff_radius)
+            .add_option(&["--diff-radius"], Store,
+            "Set how far max diff and center diff look from each pixel, as the main --diff-radius option does (0 by default)");
+        ap.refer(&mut divide)
+            .add_option(&["--div16"], StoreTrue,
+            "Divide the input by 16 before filtering");
+        ap.refer(&mut saturate)
+            .add_option(&["--saturate"], StoreTrue,
+            "Stretch the output to 0-255 once every tile is written");
+        if let Err(code) = ap.parse(args, &mut io::stdout(), &mut io::stderr())
+        {
+            std::process::exit(code);
+        }
+    }
+    filters::check(&filter);
+    let radius = filters::radius(&filter, diff_radius).unwrap_or_else(|| panic!("Filter {} depends on the whole image and cannot be tiled", filter));
+    if tile == 0
+    {
+        panic!("Tile size must be at least 1");
+    }
+    if Path::new(&input) == Path::new(&output)
+    {
+        panic!("Output must not overwrite the input");
+    }
+
+    let now = Instant::now();
+    let mut reader = TileReader::open(&input);
+    let (width, height) = reader.dimensions();
+    let mut writer = TileWriter::create(&output, width, height);
+    let (count, min, max) = process(&mut reader, &mut writer, tile, radius,
+        | img |
+        {
+            if divide
+            {
+                let mut img = img.clone();
+                div16(&mut img);
+                filters::run(&filter, &img, diff_radius, None)
+            }
+            else
+            {
+                filters::run(&filter, img, diff_radius, None)
+            }
+        }
+    );
+    let elapsed = now.elapsed();
+    let sec = (elapsed.as_secs() as f64) + (elapsed.subsec_nanos() as f64 / 1_000_000_000.0);
+    log(&format!("Filtered {}x{} image in {} tiles in: {}", width, height, count, sec));
+    if saturate
+    {
+        let now = Instant::now();
+        writer.saturate(min, max);
+        let elapsed = now.elapsed();
+        let sec = (elapsed.as_secs() as f64) + (elapsed.subsec_nanos() as f64 / 1_000_000_000.0);
+        log(&format!("Output Saturated in: {}", sec));
+    }
+    writer.finish();
+}
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+
+    // Gets a scratch path that no other test writes to
+    fn scratch(name: &str) -> String
+    {
+        std::env::temp_dir().join(format!("image_affinity_tiled_{}_{}", std::process::id(), name)).to_string_lossy().into_owned()
+    }
+
+    fn noise(width: u32, height: u32) -> image::RgbImage
+    {
+        let mut state = 0x2545f491u32;
+        image::ImageBuffer::from_fn(width, height,
+            | _, _ |
+            {
+                let mut sample = ||
+                {
+                    state ^= state << 13;
+                    state ^= state >> 17;
+                    state ^= state << 5;
+                    (state >> 28) as u8 * 17
+                };
+                image::Rgb([sample(), sample(), sample()])
+            }
+        )
+    }
+
+    // Writes an image in one piece through the tile writer
+    fn write(path: &str, img: &image::RgbImage)
+    {
+        let mut writer = TileWriter::create(path, img.width(), img.height());
+        writer.write(img, (0, 0, img.width(), img.height()), (0, 0));
+        writer.finish();
+    }
+
+    fn read(path: &str) -> image::RgbImage
+    {
+        let mut reader = TileReader::open(path);
+        let (width, height) = reader.dimensions();
+        reader.tile((0, 0, width, height))
+    }
+
+    #[test]
+    fn tiles_match_filtering_the_whole_image()
+    {
+        let img = noise(23, 17);
+        for extension in ["ppm", "bmp"]
+        {
+            let input = scratch(&format!("input.{}", extension));
+            write(&input, &img);
+            assert_eq!(read(&input).into_raw(), img.clone().into_raw());
+            for filter in ["original", "affinity", "max_diff", "center_diff"]
+            {
+                for (tile, diff_radius) in [(1, 0), (4, 0), (7, 0), (10, 0), (16, 0), (30, 0), (4, 3), (7, 5)]
+                {
+                    let output = scratch(&format!("output_{}_{}_{}.{}", filter, tile, diff_radius, extension));
+                    let mut reader = TileReader::open(&input);
+                    let mut writer = TileWriter::create(&output, 23, 17);
+                    process(&mut reader, &mut writer, tile, filters::radius(filter, diff_radius).unwrap(), | img | filters::run(filter, img, diff_radius, None));
+                    writer.finish();
+                    assert!(read(&output).into_raw() == filters::run(filter, &img, diff_radius, None).into_raw(),
+                            "{} differs with {} pixel tiles of a {} input at radius {}", filter, tile, extension, diff_radius);
+                    std::fs::remove_file(output).unwrap();
+                }
+            }
+            std::fs::remove_file(input).unwrap();
+        }
+    }
+
+    #[test]
+    fn reads_sixteen_bit_pnm()
+    {
+        let path = scratch("wide.pgm");
+        let mut bytes = b"P5\n# comment\n3 1\n1000\n".to_vec();
+        for value in [0u16, 500, 1000]
+        {
+            bytes.extend_from_slice(&value.to_be_bytes());
+        }
+        std::fs::write(&path, bytes).unwrap();
+        assert_eq!(read(&path).into_raw(), [0, 0, 0, 128, 128, 128, 255, 255, 255]);
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn reads_top_down_palette_bmp()
+    {
+        // A 2x2 top down BMP with two palette colors and rows padded to 4 bytes
+        let path = scratch("palette.bmp");
+        let mut bytes = b"BM".to_vec();
+        bytes.extend_from_slice(&(14 + 40 + 8 + 8u32).to_le_bytes());
+        bytes.extend_from_slice(&[0; 4]);
+        bytes.extend_from_slice(&(14 + 40 + 8u32).to_le_bytes());
+        bytes.extend_from_slice(&40u32.to_le_bytes());
+        bytes.extend_from_slice(&2i32.to_le_bytes());
+        bytes.extend_from_slice(&(-2i32).to_le_bytes());
+        bytes.extend_from_slice(&1u16.to_le_bytes());
+        bytes.extend_from_slice(&8u16.to_le_bytes());
+        bytes.extend_from_slice(&[0; 16]);
+        bytes.extend_from_slice(&2u32.to_le_bytes());
+        bytes.extend_from_slice(&[0; 4]);
+        bytes.extend_from_slice(&[30, 20, 10, 0, 3, 2, 1, 0]);
+        bytes.extend_from_slice(&[0, 1, 0, 0, 1, 1, 0, 0]);
+        std::fs::write(&path, bytes).unwrap();
+        assert_eq!(read(&path).into_raw(), [10, 20, 30, 1, 2, 3, 1, 2, 3, 1, 2, 3]);
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    #[should_panic(expected = "Only binary PGM and PPM files and uncompressed BMP files can be tiled")]
+    fn rejects_formats_that_cannot_be_streamed()
+    {
+        let path = scratch("image.png");
+        std::fs::write(&path, b"\x89PNG\r\n\x1a\n").unwrap();
+        TileReader::open(&path);
+    }
+}
@@ -12,5 +12,6 @@ do
             --output_graph=tf_files/"$i"_graph.pb \
             --output_labels=tf_files/"$i"_labels.txt \
             --image_dir=../"$i" \
+            --print_misclassified_test_images \
             2>&1 | tee "$i".out
 done